@@ -0,0 +1,108 @@
+//! Times a few scripts on the tree-walking interpreter, to compare builds
+//! with and without the `nan-boxing` feature:
+//!
+//!     cargo bench
+//!     cargo bench --features nan-boxing
+
+use std::time::{Duration, Instant};
+
+use rlox::{ErrorReporter, RLox};
+
+const RUNS: usize = 5;
+
+const SCRIPTS: &[(&str, &str)] = &[
+    (
+        "arithmetic on locals",
+        r#"
+        {
+            var total = 0;
+            for (var i = 0; i < 500000; i = i + 1) {
+                var half = i / 2;
+                if (half > 10) total = total + half - i * 0.5 + 1;
+            }
+            result = total;
+        }
+        "#,
+    ),
+    (
+        "recursive calls",
+        r#"
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 1) + fib(n - 2);
+        }
+        result = fib(24);
+        "#,
+    ),
+    (
+        "closures",
+        r#"
+        {
+            var total = 0;
+            for (var i = 0; i < 100000; i = i + 1) {
+                fun add(x) { return x + i; }
+                total = total + add(1);
+            }
+            result = total;
+        }
+        "#,
+    ),
+    (
+        "methods and strings",
+        r#"
+        class Counter {
+            init() { this.n = 0; this.name = "counter"; }
+            bump() { this.n = this.n + 1; return this.name; }
+        }
+        {
+            var c = Counter();
+            var last = "";
+            for (var i = 0; i < 100000; i = i + 1) {
+                var name = c.bump();
+                if (name == "counter") last = name;
+            }
+            result = c.n + len(last);
+        }
+        "#,
+    ),
+    (
+        "lists",
+        r#"
+        {
+            var l = [];
+            for (var i = 0; i < 100000; i = i + 1) push(l, i);
+            var total = 0;
+            for (var i = 0; i < len(l); i = i + 1) total = total + l[i];
+            result = total;
+        }
+        "#,
+    ),
+];
+
+fn main() {
+    let representation = match cfg!(feature = "nan-boxing") {
+        true => "nan-boxed",
+        false => "enum",
+    };
+    println!(
+        "tree-walking interpreter, {} values, best of {}",
+        representation, RUNS
+    );
+
+    for (name, src) in SCRIPTS {
+        let best = (0..RUNS).map(|_| time(src)).min().unwrap_or_default();
+        println!("{:>24}: {:>8.2?}", name, best);
+    }
+}
+
+fn time(src: &str) -> Duration {
+    let mut rlox = RLox::new(ErrorReporter::default());
+    rlox.run("var result;").expect("should declare the result");
+
+    let start = Instant::now();
+    rlox.run(src).expect("benchmark script should run");
+    let elapsed = start.elapsed();
+
+    assert!(matches!(rlox.get_global("result"), Ok(Some(v)) if !v.is_nil()));
+    elapsed
+}
@@ -0,0 +1,289 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Result, RloxError};
+use crate::map::MapKey;
+use crate::token::Span;
+
+/// The functions every script can call without defining them. Both
+/// backends have them, so they're written once here, over `ScriptValue`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BuiltInFunction {
+    Clock,
+
+    // list functions
+    Len,
+    Push,
+    Pop,
+    Insert,
+    Remove,
+    Slice,
+
+    // map functions, `len` and `remove` work on maps too
+    Has,
+    Keys,
+}
+
+/// What the builtins, and the maps `catch` sees for runtime errors, need
+/// from a backend's values. The tree-walker's `RlValue` and the `Vm`'s
+/// `Value` each have their own functions and classes, but share the rest.
+pub(crate) trait ScriptValue: Clone + Default + for<'a> From<&'a MapKey> {
+    fn type_name(&self) -> &'static str;
+    fn number(n: f64) -> Self;
+    fn boolean(b: bool) -> Self;
+    fn string(s: &str) -> Self;
+    fn as_numeric(&self) -> Option<f64>;
+    fn as_string(&self) -> Option<Rc<str>>;
+    fn as_list(&self) -> Option<Rc<RefCell<Vec<Self>>>>;
+    fn as_map(&self) -> Option<Rc<RefCell<HashMap<MapKey, Self>>>>;
+    /// The value as a map key, or `None` if it's of an unhashable type.
+    fn to_key(&self) -> Option<MapKey>;
+    fn new_list(values: Vec<Self>) -> Self;
+    fn new_map(entries: HashMap<MapKey, Self>) -> Self;
+}
+
+impl BuiltInFunction {
+    pub(crate) const ALL: [BuiltInFunction; 9] = [
+        BuiltInFunction::Clock,
+        BuiltInFunction::Len,
+        BuiltInFunction::Push,
+        BuiltInFunction::Pop,
+        BuiltInFunction::Insert,
+        BuiltInFunction::Remove,
+        BuiltInFunction::Slice,
+        BuiltInFunction::Has,
+        BuiltInFunction::Keys,
+    ];
+
+    pub(crate) fn find(name: &str) -> Option<BuiltInFunction> {
+        Self::ALL.into_iter().find(|f| f.name() == name)
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        use BuiltInFunction::*;
+
+        match self {
+            Clock => "clock",
+            Len => "len",
+            Push => "push",
+            Pop => "pop",
+            Insert => "insert",
+            Remove => "remove",
+            Slice => "slice",
+            Has => "has",
+            Keys => "keys",
+        }
+    }
+
+    pub(crate) fn arity(self) -> usize {
+        use BuiltInFunction::*;
+
+        match self {
+            Clock => 0,
+            Len | Pop | Keys => 1,
+            Push | Remove | Has => 2,
+            Insert | Slice => 3,
+        }
+    }
+
+    /// Call the builtin with `args`, which the caller has already checked
+    /// there are the right number of. `allocate` is told how many list
+    /// elements it's about to make room for, so the tree-walker can keep
+    /// them within it's `Limits`.
+    ///
+    /// The errors don't know where the call was, see `RloxError::or_at`.
+    pub(crate) fn call<V: ScriptValue>(
+        self,
+        args: &[V],
+        allocate: &mut dyn FnMut(usize) -> Result<()>,
+    ) -> Result<V> {
+        use BuiltInFunction::*;
+
+        match self {
+            Clock => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis();
+                Ok(V::number(now as f64))
+            }
+            Len => {
+                let len = match (args[0].as_list(), args[0].as_map(), args[0].as_string()) {
+                    (Some(l), _, _) => l.borrow().len(),
+                    (_, Some(m), _) => m.borrow().len(),
+                    (_, _, Some(s)) => s.chars().count(),
+                    _ => {
+                        return Err(RloxError::IncorrectType {
+                            message: format!(
+                                "len() expects a list, map or string, got {}",
+                                args[0].type_name()
+                            ),
+                            span: None,
+                        })
+                    }
+                };
+                Ok(V::number(len as f64))
+            }
+            Push => {
+                let list = list_arg("push", &args[0])?;
+                allocate(1)?;
+                list.borrow_mut().push(args[1].clone());
+                Ok(V::default())
+            }
+            Pop => match list_arg("pop", &args[0])?.borrow_mut().pop() {
+                Some(v) => Ok(v),
+                None => Err(RloxError::RuntimeError {
+                    message: String::from("pop() from an empty list"),
+                    span: None,
+                }),
+            },
+            Insert => {
+                let list = list_arg("insert", &args[0])?;
+                let len = list.borrow().len();
+                // inserting at the very end is fine, it's a push
+                let index = index_arg("insert", &args[1], len, true)?;
+                allocate(1)?;
+                list.borrow_mut().insert(index, args[2].clone());
+                Ok(V::default())
+            }
+            Remove => {
+                if let Some(map) = args[0].as_map() {
+                    let key = map_key(&args[1], None)?;
+                    let removed = map.borrow_mut().remove(&key);
+                    return removed.ok_or_else(|| RloxError::KeyError {
+                        key: key.to_string(),
+                        span: None,
+                    });
+                }
+
+                let list = list_arg("remove", &args[0])?;
+                let len = list.borrow().len();
+                let index = index_arg("remove", &args[1], len, false)?;
+                let removed = list.borrow_mut().remove(index);
+                Ok(removed)
+            }
+            Slice => {
+                let list = list_arg("slice", &args[0])?;
+                let len = list.borrow().len();
+                let start = index_arg("slice", &args[1], len, true)?;
+                let end = index_arg("slice", &args[2], len, true)?;
+                if start > end {
+                    return Err(RloxError::RuntimeError {
+                        message: format!("slice() start {} is after end {}", start, end),
+                        span: None,
+                    });
+                }
+                allocate(end - start)?;
+                let values = list.borrow()[start..end].to_vec();
+                Ok(V::new_list(values))
+            }
+            Has => {
+                let map = map_arg("has", &args[0])?;
+                let key = map_key(&args[1], None)?;
+                let found = map.borrow().contains_key(&key);
+                Ok(V::boolean(found))
+            }
+            Keys => {
+                let map = map_arg("keys", &args[0])?;
+                let mut keys: Vec<MapKey> = map.borrow().keys().cloned().collect();
+                keys.sort();
+                allocate(keys.len())?;
+                Ok(V::new_list(keys.iter().map(V::from).collect()))
+            }
+        }
+    }
+}
+
+/// `object[index]` of a list or map, for a subscript at `span`.
+pub(crate) fn get_index<V: ScriptValue>(object: &V, index: &V, span: Span) -> Result<V> {
+    if let Some(map) = object.as_map() {
+        let key = map_key(index, Some(span))?;
+        let value = map.borrow().get(&key).cloned();
+        return value.ok_or_else(|| RloxError::KeyError {
+            key: key.to_string(),
+            span: Some(span),
+        });
+    }
+
+    let (list, i) = list_position(object, index, span)?;
+    let value = list.borrow()[i].clone();
+    Ok(value)
+}
+
+/// Check `object[index]` refers to an element of a list, handing
+/// back the list and the element's position.
+pub(crate) fn list_position<V: ScriptValue>(
+    object: &V,
+    index: &V,
+    span: Span,
+) -> Result<(Rc<RefCell<Vec<V>>>, usize)> {
+    let (list, index) = match (object.as_list(), index.as_numeric()) {
+        (Some(list), Some(index)) => (list, index),
+        _ => {
+            return Err(RloxError::TypeError {
+                operator: String::from("[]"),
+                operands: format!("{} and {}", object.type_name(), index.type_name()),
+                span,
+            })
+        }
+    };
+
+    let len = list.borrow().len();
+    let i = list_index(index, len, false, Some(span))?;
+    Ok((list, i))
+}
+
+/// `value` as a map key, failing for the types which can't be one.
+pub(crate) fn map_key<V: ScriptValue>(value: &V, span: Option<Span>) -> Result<MapKey> {
+    value.to_key().ok_or_else(|| RloxError::UnhashableKey {
+        type_name: value.type_name().to_string(),
+        span,
+    })
+}
+
+/// Turn a script's `index` into a position in a list of `len` values.
+/// It has to be a whole number in `0..len`, or `0..=len` if `past_end`
+/// is ok, as for an insert.
+fn list_index(index: f64, len: usize, past_end: bool, span: Option<Span>) -> Result<usize> {
+    if index.fract() != 0.0 {
+        return Err(RloxError::IncorrectType {
+            message: format!("list index must be a whole number, got {}", index),
+            span,
+        });
+    }
+    let end = if past_end { len + 1 } else { len };
+    match index >= 0.0 && index < end as f64 {
+        true => Ok(index as usize),
+        false => Err(RloxError::IndexError { index, len, span }),
+    }
+}
+
+fn list_arg<V: ScriptValue>(function: &str, arg: &V) -> Result<Rc<RefCell<Vec<V>>>> {
+    arg.as_list().ok_or_else(|| RloxError::IncorrectType {
+        message: format!("{}() expects a list, got {}", function, arg.type_name()),
+        span: None,
+    })
+}
+
+fn map_arg<V: ScriptValue>(function: &str, arg: &V) -> Result<Rc<RefCell<HashMap<MapKey, V>>>> {
+    arg.as_map().ok_or_else(|| RloxError::IncorrectType {
+        message: format!("{}() expects a map, got {}", function, arg.type_name()),
+        span: None,
+    })
+}
+
+fn index_arg<V: ScriptValue>(function: &str, arg: &V, len: usize, past_end: bool) -> Result<usize> {
+    match arg.as_numeric() {
+        Some(index) => list_index(index, len, past_end, None),
+        None => Err(RloxError::IncorrectType {
+            message: format!(
+                "{}() expects a number index, got {}",
+                function,
+                arg.type_name()
+            ),
+            span: None,
+        }),
+    }
+}
@@ -1,88 +1,166 @@
-use crate::environment::Environment;
-use crate::error::Result;
+use crate::builtins::BuiltInFunction;
+use crate::class::Instance;
+use crate::environment::{Environment, Slot};
+use crate::error::{Result, RloxError};
+use crate::gc::{self, Trace};
 use crate::stmt::Stmt;
+use crate::symbol::{self, Symbol};
 use crate::token::Token;
 use crate::{interpreter::Interpreter, rlvalue::RlValue};
 
 use std::cell::RefCell;
 use std::fmt;
 use std::rc::Rc;
-use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The signature of a host function registered by an embedding application.
+pub type NativeFn = dyn Fn(&mut Interpreter, &[RlValue]) -> Result<RlValue>;
+
+/// A function provided by the host (a Rust application embedding rlox),
+/// rather than by the script or the `BuiltInFunction`s.
+pub struct NativeFunction {
+    pub(crate) name: String,
+    pub(crate) arity: usize,
+    function: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new<F>(name: &str, arity: usize, function: F) -> Self
+    where
+        F: Fn(&mut Interpreter, &[RlValue]) -> Result<RlValue> + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        }
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum Callable {
     BuiltIn(BuiltInFunction),
+    Native(Rc<NativeFunction>),
     Dynamic {
+        // for tracebacks, "lambda" for an anonymous function
+        name: Symbol,
         params: Vec<Token>,
-        body: Vec<Stmt>,
+        // shared, so copying a function value is cheap, and two values
+        // are the same function if they share a body and closure.
+        body: Rc<Vec<Stmt>>,
         closure: Rc<RefCell<Environment>>,
+        // class initializers (`init()`) always return `this`
+        is_initializer: bool,
     },
 }
 
+/// Callables compare by identity, rather than by their definition.
+impl PartialEq for Callable {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Callable::BuiltIn(a), Callable::BuiltIn(b)) => a == b,
+            (Callable::Native(a), Callable::Native(b)) => Rc::ptr_eq(a, b),
+            (
+                Callable::Dynamic {
+                    body: b1,
+                    closure: c1,
+                    ..
+                },
+                Callable::Dynamic {
+                    body: b2,
+                    closure: c2,
+                    ..
+                },
+            ) => Rc::ptr_eq(b1, b2) && Rc::ptr_eq(c1, c2),
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for Callable {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Callable::BuiltIn(func) => write!(f, "{:?}", func),
+            Callable::Native(func) => write!(f, "<native fn {}>", func.name),
             Callable::Dynamic { params, .. } => write!(f, "function with arity {}", params.len()),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum BuiltInFunction {
-    Clock,
-}
-
 impl Callable {
     // TODO: not sure if this is better as a From which returns Option<Callable>.
     // tryFrom returns a Result<>, but an Err isn't quite right for not finding a builtin.
     pub fn find_builtin(name: &str) -> Option<Callable> {
-        match name {
-            "clock" => Some(Callable::BuiltIn(BuiltInFunction::Clock)),
-            _ => None,
-        }
+        BuiltInFunction::find(name).map(Callable::BuiltIn)
     }
 
-    fn builtin_arity(f: BuiltInFunction) -> usize {
-        use BuiltInFunction::*;
-
-        match f {
-            Clock => 0,
+    pub fn arity(&self) -> usize {
+        match self {
+            Callable::BuiltIn(f) => f.arity(),
+            Callable::Native(f) => f.arity,
+            Callable::Dynamic { params, .. } => params.len(),
         }
     }
 
-    fn builtin_call(
-        f: BuiltInFunction,
-        _interpreter: &Interpreter,
-        _args: &[RlValue],
-    ) -> Result<RlValue> {
-        use BuiltInFunction::*;
-
-        match f {
-            Clock => {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                Ok(RlValue::Double(now as f64))
+    /// Create a copy of this method whose closure has `this` bound
+    /// to the given instance.
+    ///
+    /// The garbage collector isn't told about the new closure, as most
+    /// bound methods are called right away and dropped. If it might be
+    /// kept around, like in a field of the instance, use `bind_value`.
+    pub fn bind(&self, instance: Rc<RefCell<Instance>>) -> Callable {
+        match self {
+            Callable::BuiltIn(_) | Callable::Native(_) => self.clone(),
+            Callable::Dynamic {
+                name,
+                params,
+                body,
+                closure,
+                is_initializer,
+            } => {
+                let env = Environment::new(Some(Rc::clone(closure)), 43);
+                env.define_name(*symbol::THIS, Some(RlValue::Instance(instance)));
+                Callable::Dynamic {
+                    name: *name,
+                    params: params.clone(),
+                    body: Rc::clone(body),
+                    closure: Rc::new(RefCell::new(env)),
+                    is_initializer: *is_initializer,
+                }
             }
         }
     }
 
-    pub fn arity(&self) -> usize {
-        match self {
-            Callable::BuiltIn(f) => Self::builtin_arity(*f),
-            Callable::Dynamic { params, .. } => params.len(),
+    /// Bind the method to the instance, as a value a script can keep.
+    pub fn bind_value(&self, instance: Rc<RefCell<Instance>>) -> RlValue {
+        let method = self.bind(instance);
+        if let Callable::Dynamic { closure, .. } = &method {
+            gc::track_closure(closure);
         }
+        RlValue::Callable(method)
     }
 
     pub fn call(&mut self, interpreter: &mut Interpreter, args: &[RlValue]) -> Result<RlValue> {
         match self {
-            Callable::BuiltIn(f) => Self::builtin_call(*f, interpreter, args),
+            Callable::BuiltIn(f) => {
+                let budget = &mut interpreter.budget;
+                f.call(args, &mut |n| budget.allocate_list(n))
+            }
+            Callable::Native(f) => (f.function)(interpreter, args),
             Callable::Dynamic {
                 params,
                 body,
                 closure,
+                is_initializer,
+                ..
             } => {
                 let c = Rc::clone(closure);
                 let env = Environment::new(Some(c), 42);
@@ -91,8 +169,26 @@ impl Callable {
                     env.define(param.clone(), args.get(i).cloned());
                 }
 
-                Ok(interpreter.execute_block(body, env)?)
+                // a `return` unwinds all the way out of the function body
+                let ret = match interpreter.execute_block(body, env) {
+                    Ok(()) => RlValue::Nil,
+                    Err(RloxError::Return(val)) => val.unwrap_or_default(),
+                    Err(e) => return Err(e),
+                };
+                if *is_initializer {
+                    // `this` is the only thing bound in the closure
+                    return closure.borrow().get_at(Slot { depth: 0, index: 0 });
+                }
+                Ok(ret)
             }
         }
     }
 }
+
+impl Trace for Callable {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Callable::Dynamic { closure, .. } = self {
+            visit(Rc::as_ptr(closure) as *const ());
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use crate::callable::Callable;
+use crate::error::{Result, RloxError};
+use crate::gc::{Clear, Collect, Trace};
+use crate::rlvalue::RlValue;
+use crate::symbol::{self, Symbol};
+use crate::token::Token;
+
+/// The runtime representation of a class declaration. Calling a class
+/// creates a new `Instance`.
+#[derive(Debug)]
+pub struct Class {
+    pub(crate) name: Symbol,
+    superclass: Option<Rc<Class>>,
+    methods: HashMap<Symbol, Callable>,
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Class {
+    pub fn new(
+        name: Symbol,
+        superclass: Option<Rc<Class>>,
+        methods: HashMap<Symbol, Callable>,
+    ) -> Self {
+        Self {
+            name,
+            superclass,
+            methods,
+        }
+    }
+
+    /// Find a method on this class, walking up the superclass chain
+    /// if this class doesn't define it.
+    pub fn find_method(&self, name: Symbol) -> Option<&Callable> {
+        match self.methods.get(&name) {
+            Some(method) => Some(method),
+            None => self.superclass.as_ref().and_then(|s| s.find_method(name)),
+        }
+    }
+
+    /// A class's arity is that of it's initializer, if it has one.
+    pub fn arity(&self) -> usize {
+        match self.find_method(*symbol::INIT) {
+            Some(init) => init.arity(),
+            None => 0,
+        }
+    }
+}
+
+/// An instance of a `Class`, with it's own bag of fields.
+#[derive(Debug)]
+pub struct Instance {
+    class: Rc<Class>,
+    fields: HashMap<Symbol, RlValue>,
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} instance", self.class.name)
+    }
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    /// Look up a property on the instance. Fields shadow methods, and
+    /// methods are bound to the instance so that `this` works as expected.
+    ///
+    /// This takes the `Rc` rather than `&self` as binding a method needs
+    /// a handle to the instance itself.
+    pub fn get(instance: &Rc<RefCell<Instance>>, name: &Token) -> Result<RlValue> {
+        if let Some(v) = instance.borrow().fields.get(&name.name()) {
+            return Ok(v.clone());
+        }
+
+        let class = Rc::clone(&instance.borrow().class);
+        match class.find_method(name.name()) {
+            Some(method) => Ok(method.bind_value(Rc::clone(instance))),
+            None => Err(RloxError::UndefinedSymbol {
+                message: format!("undefined property '{}'", name.lexeme),
+                span: Some(name.span),
+            }),
+        }
+    }
+
+    pub fn set(&mut self, name: &Token, value: RlValue) {
+        self.fields.insert(name.name(), value);
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Some(superclass) = &self.superclass {
+            visit(Rc::as_ptr(superclass) as *const ());
+        }
+        self.methods.values().for_each(|m| m.trace(visit));
+    }
+
+    fn heap_size(&self) -> usize {
+        let method = mem::size_of::<Symbol>() + mem::size_of::<Callable>();
+        mem::size_of::<Self>() + self.methods.capacity() * method
+    }
+}
+
+// classes can't be changed once they're made
+impl Collect for Class {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        Trace::trace(self, visit);
+        true
+    }
+
+    fn heap_size(&self) -> usize {
+        Trace::heap_size(self)
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        visit(Rc::as_ptr(&self.class) as *const ());
+        self.fields.values().for_each(|v| v.trace(visit));
+    }
+
+    fn heap_size(&self) -> usize {
+        let field = mem::size_of::<Symbol>() + mem::size_of::<RlValue>();
+        mem::size_of::<Self>() + self.fields.capacity() * field
+    }
+}
+
+impl Clear for Instance {
+    type Contents = HashMap<Symbol, RlValue>;
+
+    fn take_all(&mut self) -> Self::Contents {
+        std::mem::take(&mut self.fields)
+    }
+}
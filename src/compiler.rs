@@ -0,0 +1,977 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::error::{Result, RloxError};
+use crate::expr::{Expr, ExprKind, LiteralValue};
+use crate::stmt::Stmt;
+use crate::token::{Span, Token, TokenType};
+use crate::vm::Value;
+
+/// The instruction set for the bytecode `Vm`.
+///
+/// Unlike clox, which packs operands into the byte stream after the
+/// opcode, operands here ride along inside the enum. Indices point
+/// into the chunk's constant pool, the current frame's stack slots or
+/// the closure's upvalues; jump targets are absolute instruction offsets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpCode {
+    Constant(usize),
+    Nil,
+    True,
+    False,
+    Pop,
+    GetLocal(usize),
+    SetLocal(usize),
+    GetGlobal(usize),
+    DefineGlobal(usize),
+    SetGlobal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    GetSuper(usize),
+    GetIndex,
+    SetIndex,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Negate,
+    Print,
+    Jump(usize),
+    JumpIfFalse(usize),
+    Loop(usize),
+    Call(usize),
+    Closure(usize),
+    CloseUpvalue,
+    Return,
+    Class(usize),
+    Inherit,
+    Method(usize),
+    // build a list from the top n values on the stack
+    BuildList(usize),
+    // build a map from the top n key/value pairs on the stack
+    BuildMap(usize),
+    // push the module at the path in the constant
+    Import(usize),
+    Throw,
+    // errors unwind to the innermost handler, which carries on from it's
+    // target with what was caught pushed on to the stack. a catch handler
+    // only takes the errors scripts can catch, a finally handler takes
+    // anything, to rethrow once the finally block has run.
+    PushCatch(usize),
+    PushFinally(usize),
+    PopHandler,
+    Rethrow,
+}
+
+/// A sequence of instructions, with the source span of each one
+/// and the constants they refer to.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub(crate) code: Vec<OpCode>,
+    pub(crate) spans: Vec<Span>,
+    pub(crate) constants: Vec<Value>,
+}
+
+impl Chunk {
+    fn write(&mut self, op: OpCode, span: Span) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+}
+
+/// Where a closure finds one of it's captured variables: either a local
+/// slot in the directly enclosing function, or one of that function's
+/// own upvalues.
+#[derive(Clone, Copy, Debug)]
+pub struct UpvalueDesc {
+    pub(crate) is_local: bool,
+    pub(crate) index: usize,
+}
+
+/// A compiled function. The top-level script is compiled into a
+/// function with an empty name.
+#[derive(Debug, Default)]
+pub struct Function {
+    pub(crate) name: String,
+    pub(crate) arity: usize,
+    pub(crate) chunk: Chunk,
+    pub(crate) upvalues: Vec<UpvalueDesc>,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}>", self.name)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+    Initializer,
+    Method,
+}
+
+struct Local {
+    name: String,
+    // `None` while the variable is declared, but it's initializer
+    // hasn't finished yet.
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+/// Book-keeping for the function currently being compiled. These are
+/// stacked up, one per level of function nesting.
+struct FunctionState {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    scope_depth: usize,
+    loops: Vec<LoopState>,
+    tries: Vec<TryState>,
+}
+
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Jumps out of the innermost loop, waiting to be patched once the
+/// loop's layout is known.
+struct LoopState {
+    // scope depth just outside the loop body, locals deeper than
+    // this must be discarded when jumping out of the body.
+    scope_depth: usize,
+    break_jumps: Vec<usize>,
+    continue_jumps: Vec<usize>,
+}
+
+/// A `try` being compiled, so a `return`, `break` or `continue` which
+/// jumps out of it knows to drop it's handlers and run it's `finally`
+/// block on the way.
+struct TryState {
+    // scope depth just outside the try
+    scope_depth: usize,
+    // how many handlers the vm has for it right now, one for the catch
+    // while in the body, and one for the finally until it runs.
+    handlers: usize,
+    finally: Option<Vec<Stmt>>,
+}
+
+/// Lowers the already-parsed (and resolved) statements into bytecode
+/// for the `Vm`. As the input is an AST rather than a token stream,
+/// this is a tree walk, but it does it's own local variable and upvalue
+/// resolution as the stack layout is only known here.
+pub struct Compiler {
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+
+    // span of the most recently seen token, used for the span table.
+    span: Span,
+}
+
+impl Compiler {
+    pub fn compile(stmts: &[Stmt]) -> Result<Rc<Function>> {
+        let mut compiler = Compiler {
+            states: Vec::new(),
+            classes: Vec::new(),
+            span: Span::from_line(1),
+        };
+
+        compiler.begin_function(String::new(), FunctionType::Script);
+        for stmt in stmts {
+            compiler.statement(stmt)?;
+        }
+        Ok(Rc::new(compiler.end_function()))
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<()> {
+        match stmt {
+            Stmt::Block(stmts) => self.block(stmts)?,
+            Stmt::Break(keyword) => {
+                self.span = keyword.span;
+                let jump = self.loop_exit_jump(keyword)?;
+                if let Some(l) = self.current().loops.last_mut() {
+                    l.break_jumps.push(jump);
+                }
+            }
+            Stmt::Continue(keyword) => {
+                self.span = keyword.span;
+                let jump = self.loop_exit_jump(keyword)?;
+                if let Some(l) = self.current().loops.last_mut() {
+                    l.continue_jumps.push(jump);
+                }
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.class_declaration(name, superclass.as_ref(), methods)?,
+            Stmt::Expression(e) => {
+                self.expression(e)?;
+                self.emit(OpCode::Pop);
+            }
+            Stmt::Function { name, params, body } => {
+                self.span = name.span;
+                let global = self.identifier_constant(name.lexeme.as_str());
+                self.declare_variable(name);
+                // mark it initialized right away so the function may recurse
+                self.mark_initialized();
+                self.function(name.lexeme.as_str(), params, body, FunctionType::Function)?;
+                self.define_variable(global);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition)?;
+                let then_jump = self.emit(OpCode::JumpIfFalse(0));
+                self.emit(OpCode::Pop);
+                self.statement(then_branch)?;
+
+                let else_jump = self.emit(OpCode::Jump(0));
+                self.patch_jump(then_jump);
+                self.emit(OpCode::Pop);
+                if let Some(el) = else_branch {
+                    self.statement(el)?;
+                }
+                self.patch_jump(else_jump);
+            }
+            Stmt::Import {
+                keyword,
+                path,
+                name,
+            } => {
+                self.span = keyword.span;
+                let global = self.identifier_constant(name.lexeme.as_str());
+                self.declare_variable(name);
+                let path = self.make_constant(Value::String(Rc::from(path.as_str())));
+                self.emit(OpCode::Import(path));
+                self.define_variable(global);
+            }
+            Stmt::Print(e) => {
+                self.expression(e)?;
+                self.emit(OpCode::Print);
+            }
+            Stmt::Return { keyword, expr } => {
+                self.span = keyword.span;
+                // a `return` outside of any function just ends the script,
+                // same as the tree-walker, so it compiles like any other
+                if self.current().function_type == FunctionType::Initializer && expr.is_some() {
+                    return Err(self.error("Can't return a value from an initializer"));
+                }
+
+                match expr {
+                    Some(e) => self.expression(e)?,
+                    None => self.emit_return_value(),
+                }
+
+                // keep the value as a nameless local while any finally
+                // blocks the return leaves run, the frame goes with it.
+                self.add_local("");
+                self.mark_initialized();
+                self.leave_tries(0)?;
+                self.current().locals.pop();
+
+                self.span = keyword.span;
+                self.emit(OpCode::Return);
+            }
+            Stmt::Throw { keyword, value } => {
+                self.expression(value)?;
+                self.span = keyword.span;
+                self.emit(OpCode::Throw);
+            }
+            Stmt::Try {
+                keyword,
+                body,
+                catch,
+                finally,
+            } => {
+                self.span = keyword.span;
+                self.try_statement(keyword, body, catch.as_ref(), finally.as_deref())?;
+            }
+            Stmt::Var { name, initializer } => {
+                self.span = name.span;
+                let global = self.identifier_constant(name.lexeme.as_str());
+                self.declare_variable(name);
+                match initializer {
+                    Some(e) => self.expression(e)?,
+                    None => {
+                        self.emit(OpCode::Nil);
+                    }
+                }
+                self.define_variable(global);
+            }
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
+                let loop_start = self.current_chunk().code.len();
+                self.expression(condition)?;
+
+                let exit_jump = self.emit(OpCode::JumpIfFalse(0));
+                self.emit(OpCode::Pop);
+
+                let scope_depth = self.current().scope_depth;
+                self.current().loops.push(LoopState {
+                    scope_depth,
+                    break_jumps: Vec::new(),
+                    continue_jumps: Vec::new(),
+                });
+                self.statement(body)?;
+                let state = self.current().loops.pop().expect("should be in a loop");
+
+                for jump in state.continue_jumps {
+                    self.patch_jump(jump);
+                }
+                if let Some(incr) = increment {
+                    self.expression(incr)?;
+                    self.emit(OpCode::Pop);
+                }
+                self.emit(OpCode::Loop(loop_start));
+
+                self.patch_jump(exit_jump);
+                self.emit(OpCode::Pop);
+                for jump in state.break_jumps {
+                    self.patch_jump(jump);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        self.begin_scope();
+        for stmt in stmts {
+            self.statement(stmt)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    /// A finally block is compiled in to every way out of the try: after
+    /// the body or catch, as the finally handler which runs it before an
+    /// error carries on unwinding, and before any jump out of it, see
+    /// `leave_tries`.
+    fn try_statement(
+        &mut self,
+        keyword: &Token,
+        body: &[Stmt],
+        catch: Option<&(Token, Vec<Stmt>)>,
+        finally: Option<&[Stmt]>,
+    ) -> Result<()> {
+        let finally_handler = finally.map(|_| self.emit(OpCode::PushFinally(0)));
+        let catch_handler = catch.map(|_| self.emit(OpCode::PushCatch(0)));
+        let scope_depth = self.current().scope_depth;
+        self.current().tries.push(TryState {
+            scope_depth,
+            handlers: usize::from(catch.is_some()) + usize::from(finally.is_some()),
+            finally: finally.map(|f| f.to_vec()),
+        });
+        self.block(body)?;
+
+        if let (Some((name, handler)), Some(catch_handler)) = (catch, catch_handler) {
+            self.emit(OpCode::PopHandler);
+            let skip_jump = self.emit(OpCode::Jump(0));
+
+            // the caught value is pushed where the catch's variable goes
+            self.patch_jump(catch_handler);
+            if let Some(t) = self.current().tries.last_mut() {
+                t.handlers -= 1;
+            }
+            self.begin_scope();
+            self.declare_variable(name);
+            self.mark_initialized();
+            for stmt in handler {
+                self.statement(stmt)?;
+            }
+            self.end_scope();
+            self.patch_jump(skip_jump);
+        }
+        self.current().tries.pop();
+
+        if let (Some(finally), Some(finally_handler)) = (finally, finally_handler) {
+            self.span = keyword.span;
+            self.emit(OpCode::PopHandler);
+            self.block(finally)?;
+            let end_jump = self.emit(OpCode::Jump(0));
+
+            // the handler pushes a placeholder where the caught value
+            // would go, the vm keeps the error itself for the rethrow.
+            self.patch_jump(finally_handler);
+            self.begin_scope();
+            self.add_local("");
+            self.mark_initialized();
+            self.block(finally)?;
+            self.end_scope();
+            self.span = keyword.span;
+            self.emit(OpCode::Rethrow);
+            self.patch_jump(end_jump);
+        }
+        Ok(())
+    }
+
+    /// Before a jump out to scope `depth`, drop the handlers of the tries
+    /// it leaves and run their finally blocks, innermost first.
+    fn leave_tries(&mut self, depth: usize) -> Result<()> {
+        let span = self.span;
+        let mut index = self.current().tries.len();
+        while index > 0 && self.current().tries[index - 1].scope_depth >= depth {
+            index -= 1;
+            for _ in 0..self.current().tries[index].handlers {
+                self.emit(OpCode::PopHandler);
+            }
+            if let Some(finally) = self.current().tries[index].finally.clone() {
+                self.inline_finally(index, &finally)?;
+            }
+        }
+        self.span = span;
+        Ok(())
+    }
+
+    /// Compile a copy of the finally block of the try at `index`, right
+    /// where the jump out of it is. It's compiled as if it were just after
+    /// the try, so it can't see the try's locals (which are still on the
+    /// stack), or jump out of any loop or try inside it.
+    fn inline_finally(&mut self, index: usize, finally: &[Stmt]) -> Result<()> {
+        let scope_depth = self.current().tries[index].scope_depth;
+        let inner_tries = self.current().tries.split_off(index);
+        let inner_loop = self
+            .current()
+            .loops
+            .iter()
+            .position(|l| l.scope_depth > scope_depth)
+            .unwrap_or(self.current().loops.len());
+        let inner_loops = self.current().loops.split_off(inner_loop);
+        let hidden: Vec<(usize, String)> = self
+            .current()
+            .locals
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, l)| l.depth.is_none_or(|d| d > scope_depth))
+            .map(|(slot, l)| (slot, std::mem::take(&mut l.name)))
+            .collect();
+
+        self.block(finally)?;
+
+        for (slot, name) in hidden {
+            self.current().locals[slot].name = name;
+        }
+        self.current().loops.extend(inner_loops);
+        self.current().tries.extend(inner_tries);
+        Ok(())
+    }
+
+    fn class_declaration(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &[Stmt],
+    ) -> Result<()> {
+        self.span = name.span;
+        let name_constant = self.identifier_constant(name.lexeme.as_str());
+        self.declare_variable(name);
+        self.emit(OpCode::Class(name_constant));
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        if let Some(superclass) = superclass {
+            if let ExprKind::Variable(superclass_name) = &superclass.kind {
+                if superclass_name.lexeme == name.lexeme {
+                    return Err(self.error("A class can't inherit from itself"));
+                }
+            }
+            self.expression(superclass)?;
+
+            // `super` lives in a scope wrapping the methods, so each
+            // method closure captures it as an upvalue.
+            self.begin_scope();
+            self.add_local("super");
+            self.mark_initialized();
+
+            self.named_variable(name.lexeme.as_str(), None)?;
+            self.emit(OpCode::Inherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // load the class back on to the stack so the methods can be bound to it
+        self.named_variable(name.lexeme.as_str(), None)?;
+        for method in methods {
+            if let Stmt::Function { name, params, body } = method {
+                self.span = name.span;
+                let method_constant = self.identifier_constant(name.lexeme.as_str());
+                let function_type = match name.lexeme.as_str() {
+                    "init" => FunctionType::Initializer,
+                    _ => FunctionType::Method,
+                };
+                self.function(name.lexeme.as_str(), params, body, function_type)?;
+                self.emit(OpCode::Method(method_constant));
+            }
+        }
+        self.emit(OpCode::Pop);
+
+        if superclass.is_some() {
+            self.end_scope();
+        }
+        self.classes.pop();
+        Ok(())
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        params: &[Token],
+        body: &[Stmt],
+        function_type: FunctionType,
+    ) -> Result<()> {
+        self.begin_function(name.to_string(), function_type);
+        self.begin_scope();
+
+        for param in params {
+            self.current().function.arity += 1;
+            self.declare_variable(param);
+            self.mark_initialized();
+        }
+
+        for stmt in body {
+            self.statement(stmt)?;
+        }
+
+        // no need to end the scope, the whole frame goes away on return
+        let function = self.end_function();
+        let constant = self.make_constant(Value::Function(Rc::new(function)));
+        self.emit(OpCode::Closure(constant));
+        Ok(())
+    }
+
+    fn expression(&mut self, expr: &Expr) -> Result<()> {
+        match &expr.kind {
+            ExprKind::Assign(t, e) => {
+                self.span = t.span;
+                self.named_variable(t.lexeme.as_str(), Some(e))?;
+            }
+            ExprKind::Binary(l, t, r) => {
+                self.expression(l)?;
+                self.expression(r)?;
+                self.span = t.span;
+                let op = match t.token_type {
+                    TokenType::Plus => OpCode::Add,
+                    TokenType::Minus => OpCode::Subtract,
+                    TokenType::Star => OpCode::Multiply,
+                    TokenType::Slash => OpCode::Divide,
+                    TokenType::Greater => OpCode::Greater,
+                    TokenType::GreaterEqual => OpCode::GreaterEqual,
+                    TokenType::Less => OpCode::Less,
+                    TokenType::LessEqual => OpCode::LessEqual,
+                    TokenType::EqualEqual => OpCode::Equal,
+                    TokenType::BangEqual => OpCode::NotEqual,
+                    _ => {
+                        return Err(RloxError::Unreachable(format!(
+                            "unsupported Binary type: {:?}",
+                            t,
+                        )))
+                    }
+                };
+                self.emit(op);
+            }
+            ExprKind::Call(callee, paren, arguments) => {
+                self.expression(callee)?;
+                for arg in arguments {
+                    self.expression(arg)?;
+                }
+                self.span = paren.span;
+                self.emit(OpCode::Call(arguments.len()));
+            }
+            ExprKind::Get(object, name) => {
+                self.expression(object)?;
+                self.span = name.span;
+                let constant = self.identifier_constant(name.lexeme.as_str());
+                self.emit(OpCode::GetProperty(constant));
+            }
+            ExprKind::Grouping(e) => self.expression(e)?,
+            ExprKind::Index(object, bracket, index) => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.span = bracket.span;
+                self.emit(OpCode::GetIndex);
+            }
+            ExprKind::List(bracket, elements) => {
+                for e in elements {
+                    self.expression(e)?;
+                }
+                self.span = bracket.span;
+                self.emit(OpCode::BuildList(elements.len()));
+            }
+            ExprKind::Lambda(keyword, params, body) => {
+                self.span = keyword.span;
+                self.function("lambda", params, body, FunctionType::Function)?;
+            }
+            ExprKind::Literal(l, _span) => match l {
+                LiteralValue::Nil() => {
+                    self.emit(OpCode::Nil);
+                }
+                LiteralValue::Boolean(true) => {
+                    self.emit(OpCode::True);
+                }
+                LiteralValue::Boolean(false) => {
+                    self.emit(OpCode::False);
+                }
+                LiteralValue::Number(n) => {
+                    let constant = self.make_constant(Value::Double(*n));
+                    self.emit(OpCode::Constant(constant));
+                }
+                LiteralValue::String(s) => {
+                    let constant = self.make_constant(Value::String(Rc::clone(s)));
+                    self.emit(OpCode::Constant(constant));
+                }
+            },
+            ExprKind::Map(brace, entries) => {
+                for (key, value) in entries {
+                    self.expression(key)?;
+                    self.expression(value)?;
+                }
+                self.span = brace.span;
+                self.emit(OpCode::BuildMap(entries.len()));
+            }
+            ExprKind::Logical(left, operator, right) => {
+                self.expression(left)?;
+                if operator.token_type == TokenType::Or {
+                    let else_jump = self.emit(OpCode::JumpIfFalse(0));
+                    let end_jump = self.emit(OpCode::Jump(0));
+                    self.patch_jump(else_jump);
+                    self.emit(OpCode::Pop);
+                    self.expression(right)?;
+                    self.patch_jump(end_jump);
+                } else {
+                    let end_jump = self.emit(OpCode::JumpIfFalse(0));
+                    self.emit(OpCode::Pop);
+                    self.expression(right)?;
+                    self.patch_jump(end_jump);
+                }
+            }
+            ExprKind::Set(object, name, value) => {
+                self.expression(object)?;
+                self.expression(value)?;
+                self.span = name.span;
+                let constant = self.identifier_constant(name.lexeme.as_str());
+                self.emit(OpCode::SetProperty(constant));
+            }
+            ExprKind::SetIndex(object, bracket, index, value) => {
+                self.expression(object)?;
+                self.expression(index)?;
+                self.expression(value)?;
+                self.span = bracket.span;
+                self.emit(OpCode::SetIndex);
+            }
+            ExprKind::Super(keyword, method) => {
+                self.span = keyword.span;
+                match self.classes.last() {
+                    None => return Err(self.error("Can't use 'super' outside of a class")),
+                    Some(class) if !class.has_superclass => {
+                        return Err(self.error("Can't use 'super' in a class with no superclass"))
+                    }
+                    _ => (),
+                }
+
+                let constant = self.identifier_constant(method.lexeme.as_str());
+                self.named_variable("this", None)?;
+                self.named_variable("super", None)?;
+                self.emit(OpCode::GetSuper(constant));
+            }
+            ExprKind::This(t) => {
+                self.span = t.span;
+                if self.classes.is_empty() {
+                    return Err(self.error("Can't use 'this' outside of a class"));
+                }
+                self.named_variable("this", None)?;
+            }
+            ExprKind::Unary(t, e) => {
+                self.expression(e)?;
+                self.span = t.span;
+                match t.token_type {
+                    TokenType::Minus => self.emit(OpCode::Negate),
+                    TokenType::Bang => self.emit(OpCode::Not),
+                    _ => {
+                        return Err(RloxError::Unreachable(format!(
+                            "TokenType not accepted: {:?}",
+                            t.token_type,
+                        )))
+                    }
+                };
+            }
+            ExprKind::Variable(t) => {
+                self.span = t.span;
+                self.named_variable(t.lexeme.as_str(), None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit a read of the variable `name` or, if `value` is provided,
+    /// an assignment to it.
+    fn named_variable(&mut self, name: &str, value: Option<&Expr>) -> Result<()> {
+        let top = self.states.len() - 1;
+        let (get_op, set_op) = if let Some(slot) = self.resolve_local(top, name)? {
+            (OpCode::GetLocal(slot), OpCode::SetLocal(slot))
+        } else if let Some(index) = self.resolve_upvalue(top, name)? {
+            (OpCode::GetUpvalue(index), OpCode::SetUpvalue(index))
+        } else {
+            let constant = self.identifier_constant(name);
+            (OpCode::GetGlobal(constant), OpCode::SetGlobal(constant))
+        };
+
+        match value {
+            Some(e) => {
+                self.expression(e)?;
+                self.emit(set_op);
+            }
+            None => {
+                self.emit(get_op);
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_local(&self, state: usize, name: &str) -> Result<Option<usize>> {
+        for (slot, local) in self.states[state].locals.iter().enumerate().rev() {
+            if local.name == name {
+                if local.depth.is_none() {
+                    return Err(self.error("Can't read local variable in its own initializer"));
+                }
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Result<Option<usize>> {
+        if state == 0 {
+            return Ok(None);
+        }
+
+        if let Some(slot) = self.resolve_local(state - 1, name)? {
+            self.states[state - 1].locals[slot].is_captured = true;
+            return Ok(Some(self.add_upvalue(state, slot, true)));
+        }
+
+        if let Some(index) = self.resolve_upvalue(state - 1, name)? {
+            return Ok(Some(self.add_upvalue(state, index, false)));
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: usize, is_local: bool) -> usize {
+        let upvalues = &mut self.states[state].function.upvalues;
+        for (i, upvalue) in upvalues.iter().enumerate() {
+            if upvalue.index == index && upvalue.is_local == is_local {
+                return i;
+            }
+        }
+
+        upvalues.push(UpvalueDesc { is_local, index });
+        upvalues.len() - 1
+    }
+
+    fn declare_variable(&mut self, name: &Token) {
+        if self.current().scope_depth == 0 {
+            return;
+        }
+        // redeclaring a name in the same scope simply shadows the earlier
+        // one, matching the tree-walking interpreter.
+        self.add_local(name.lexeme.as_str());
+    }
+
+    fn add_local(&mut self, name: &str) {
+        self.current().locals.push(Local {
+            name: name.to_string(),
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.current().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit(OpCode::DefineGlobal(global));
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.current();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    /// Discard the locals of the loop body (without forgetting about them,
+    /// as compilation of the body carries on), then emit a jump to be patched
+    /// by the enclosing loop.
+    fn loop_exit_jump(&mut self, keyword: &Token) -> Result<usize> {
+        let loop_depth = match self.current().loops.last() {
+            Some(l) => l.scope_depth,
+            None => {
+                return Err(self.error(&format!("Can't use '{}' outside of a loop", keyword.lexeme)))
+            }
+        };
+        self.leave_tries(loop_depth)?;
+
+        let ops: Vec<OpCode> = self
+            .current()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth.is_none_or(|d| d > loop_depth))
+            .map(|l| match l.is_captured {
+                true => OpCode::CloseUpvalue,
+                false => OpCode::Pop,
+            })
+            .collect();
+        for op in ops {
+            self.emit(op);
+        }
+
+        Ok(self.emit(OpCode::Jump(0)))
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current().scope_depth -= 1;
+        let depth = self.current().scope_depth;
+
+        while let Some(local) = self.current().locals.last() {
+            if local.depth.is_none_or(|d| d <= depth) {
+                break;
+            }
+            let op = match local.is_captured {
+                true => OpCode::CloseUpvalue,
+                false => OpCode::Pop,
+            };
+            self.emit(op);
+            self.current().locals.pop();
+        }
+    }
+
+    fn begin_function(&mut self, name: String, function_type: FunctionType) {
+        // slot zero holds the function itself, or the receiver for methods.
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+
+        self.states.push(FunctionState {
+            function: Function {
+                name,
+                ..Default::default()
+            },
+            function_type,
+            locals: vec![Local {
+                name: slot_zero.to_string(),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            scope_depth: 0,
+            loops: Vec::new(),
+            tries: Vec::new(),
+        });
+    }
+
+    fn end_function(&mut self) -> Function {
+        self.emit_return();
+        self.states
+            .pop()
+            .expect("should always have a function state")
+            .function
+    }
+
+    fn emit_return(&mut self) {
+        self.emit_return_value();
+        self.emit(OpCode::Return);
+    }
+
+    /// What a function returns when it doesn't say.
+    fn emit_return_value(&mut self) {
+        if self.current().function_type == FunctionType::Initializer {
+            self.emit(OpCode::GetLocal(0));
+        } else {
+            self.emit(OpCode::Nil);
+        }
+    }
+
+    ///////////////////
+    // helper functions
+    ///////////////////
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.states
+            .last_mut()
+            .expect("should always have a function state")
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current().function.chunk
+    }
+
+    fn emit(&mut self, op: OpCode) -> usize {
+        let span = self.span;
+        self.current_chunk().write(op, span)
+    }
+
+    /// Point a previously emitted jump at the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) {
+        let target = self.current_chunk().code.len();
+        let code = &mut self.current_chunk().code;
+        code[offset] = match code[offset] {
+            OpCode::Jump(_) => OpCode::Jump(target),
+            OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(target),
+            OpCode::PushCatch(_) => OpCode::PushCatch(target),
+            OpCode::PushFinally(_) => OpCode::PushFinally(target),
+            op => unreachable!("tried to patch a non-jump instruction: {:?}", op),
+        };
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.current_chunk().add_constant(value)
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        self.make_constant(Value::String(Rc::from(name)))
+    }
+
+    fn error(&self, message: &str) -> RloxError {
+        RloxError::CompileError(format!("[{}] {}", self.span, message))
+    }
+}
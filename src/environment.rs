@@ -1,19 +1,41 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::fmt;
+use std::mem;
 use std::rc::Rc;
 
 use crate::error::{Result, RloxError};
+use crate::gc::{Clear, Trace};
+use crate::symbol::Symbol;
 use crate::{callable::Callable, rlvalue::RlValue, token::Token};
 
-/// A place to store level-scoped variables
+/// Where the `Resolver` found a local variable: how many environments out
+/// from the current one it lives, and it's index in that one's slots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Slot {
+    pub(crate) depth: u32,
+    pub(crate) index: usize,
+}
+
+/// A place to store level-scoped variables.
+///
+/// The outer-most environment (the globals of a script or module) keeps
+/// them by name, as they can be defined after the code using them is
+/// resolved. Every other one keeps it's locals in slots, in the order
+/// they're declared, which is the order the `Resolver` numbered them in.
 pub struct Environment {
     // at least some form of interior mutability (yay!)
-    values: RefCell<HashMap<String, Option<RlValue>>>,
+    values: RefCell<HashMap<Symbol, Option<RlValue>>>,
+
+    // the locals, for any environment but the outer-most one.
+    slots: RefCell<Vec<RlValue>>,
 
     // a parent Environment. If it's None, it's the outer-most environment.
     enclosing: Option<Rc<RefCell<Environment>>>,
 
+    // whether the garbage collector knows about it yet
+    tracked: Cell<bool>,
+
     // Temp identifier for debugging
     id: i32,
 }
@@ -51,79 +73,180 @@ impl Environment {
     pub fn new(enclosing: Option<Rc<RefCell<Environment>>>, id: i32) -> Self {
         Self {
             values: Default::default(),
+            slots: Default::default(),
             enclosing,
+            tracked: Cell::new(false),
             id,
         }
     }
 
     pub fn define(&self, key: Token, value: Option<RlValue>) {
-        self.values.borrow_mut().insert(key.lexeme, value);
+        self.define_name(key.name(), value);
+    }
+
+    /// Define a value for a name that doesn't come from a source token,
+    /// like `this` or `super`.
+    pub fn define_name(&self, name: Symbol, value: Option<RlValue>) {
+        match self.enclosing {
+            None => {
+                self.values.borrow_mut().insert(name, value);
+            }
+            // an uninitialized local is just nil
+            Some(_) => self.slots.borrow_mut().push(value.unwrap_or_default()),
+        }
+    }
+
+    /// Everything defined directly in this environment, sorted by name.
+    pub fn values(&self) -> Vec<(String, Option<RlValue>)> {
+        let mut values: Vec<(String, Option<RlValue>)> = self
+            .values
+            .borrow()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values
     }
 
     pub fn get(&self, key: &Token) -> Result<Option<RlValue>> {
-        if let Some(v) = self.values.borrow().get(&key.lexeme) {
+        self.get_name(key.name()).map_err(|e| e.or_at(key.span))
+    }
+
+    pub fn get_name(&self, name: Symbol) -> Result<Option<RlValue>> {
+        if let Some(v) = self.values.borrow().get(&name) {
             return Ok(v.clone());
         }
 
         if let Some(e) = &self.enclosing {
-            return e.borrow().get(key);
+            return e.borrow().get_name(name);
         }
 
         // if we're at the outer-most Env and we still haven't found the symbol,
         // try looking into the "built-in functions" as defined in
         // Callable::BuiltInFunction. This is a bit of a hack, but works
         // for the current state (as of chapter 10 ...)
-        match Callable::find_builtin(&key.lexeme) {
+        match Callable::find_builtin(name.as_str()) {
             Some(builtin) => Ok(Some(RlValue::Callable(builtin))),
-            None => Err(RloxError::UndefinedSymbol(key.lexeme.clone())),
+            None => Err(RloxError::UndefinedSymbol {
+                message: format!("undefined variable '{}'", name),
+                span: None,
+            }),
+        }
+    }
+
+    /// Look up a name in the outer-most environment, skipping over
+    /// anything shadowing it on the way.
+    pub fn get_global(&self, key: &Token) -> Result<Option<RlValue>> {
+        match &self.enclosing {
+            Some(e) => e.borrow().get_global(key),
+            None => self.get(key),
         }
     }
 
-    pub fn get_at(&self, distance: u32, token: &Token) -> Result<RlValue> {
-        if 0 == distance {
-            return match self.values.borrow().get(&token.lexeme) {
-                Some(v) => Ok(v.clone().expect("should have a defined rlvalue")),
-                None => Err(RloxError::ResolutionError(format!(
-                    "should have a defined rlvalue for token {:?}",
-                    token,
+    pub fn get_at(&self, slot: Slot) -> Result<RlValue> {
+        self.at(slot, |value| value.clone())
+    }
+
+    pub fn assign_at(&self, slot: Slot, value: RlValue) -> Result<()> {
+        self.at(slot, |v| *v = value)
+    }
+
+    // run `f` on the local at `slot`, walking out `slot.depth` environments.
+    fn at<T>(&self, slot: Slot, f: impl FnOnce(&mut RlValue) -> T) -> Result<T> {
+        if slot.depth > 0 {
+            return match self.enclosing {
+                Some(ref e) => e.borrow().at(
+                    Slot {
+                        depth: slot.depth - 1,
+                        index: slot.index,
+                    },
+                    f,
+                ),
+                None => Err(RloxError::ResolutionError(String::from(
+                    "No more envs left to upwardly traverse?!?",
                 ))),
             };
         }
 
-        match self.enclosing {
-            Some(ref e) => Rc::clone(e).borrow().get_at(distance - 1, token),
-            None => Err(RloxError::ResolutionError(String::from(
-                "No more envs left to upwardly traverse?!?",
+        match self.slots.borrow_mut().get_mut(slot.index) {
+            Some(value) => Ok(f(value)),
+            None => Err(RloxError::ResolutionError(format!(
+                "should have a local in slot {}",
+                slot.index
             ))),
         }
     }
 
     /// Looks up the key in the values map, but will not recurse up
-    /// to the enclosing. Mainly for testing.
-    #[allow(dead_code)]
-    fn get_local(&self, key: &Token) -> Result<Option<RlValue>> {
-        match self.values.borrow().get(&key.lexeme) {
+    /// to the enclosing.
+    pub fn get_local(&self, key: &Token) -> Result<Option<RlValue>> {
+        match self.values.borrow().get(&key.name()) {
             Some(v) => Ok(v.clone()),
-            None => Err(RloxError::UndefinedVariable(key.lexeme.clone())),
+            None => Err(RloxError::UndefinedVariable {
+                message: format!("undefined variable '{}'", key.lexeme),
+                span: Some(key.span),
+            }),
         }
     }
 
-    pub fn assign(&self, key: &Token, value: RlValue) -> Result<()> {
-        if self.values.borrow().contains_key(&key.lexeme) {
-            self.values
-                .borrow_mut()
-                .entry(key.lexeme.clone())
-                .and_modify(|e| *e = Some(value));
-            return Ok(());
+    pub(crate) fn enclosing(&self) -> Option<Rc<RefCell<Environment>>> {
+        self.enclosing.clone()
+    }
+
+    /// Mark the environment as known to the garbage collector, returning
+    /// false if it already was.
+    pub(crate) fn start_tracking(&self) -> bool {
+        !self.tracked.replace(true)
+    }
+
+    /// Assign to a variable in the outer-most environment.
+    pub fn assign_global(&self, key: &Token, value: RlValue) -> Result<()> {
+        if let Some(enclosing) = &self.enclosing {
+            return enclosing.borrow().assign_global(key, value);
         }
 
-        match &self.enclosing {
-            Some(enclosing) => enclosing.borrow().assign(key, value),
-            None => Err(RloxError::UndefinedVariable(key.lexeme.clone())),
+        match self.values.borrow_mut().get_mut(&key.name()) {
+            Some(v) => {
+                *v = Some(value);
+                Ok(())
+            }
+            None => Err(RloxError::UndefinedVariable {
+                message: format!("undefined variable '{}'", key.lexeme),
+                span: Some(key.span),
+            }),
         }
     }
 }
 
+impl Trace for Environment {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let Some(enclosing) = &self.enclosing {
+            visit(Rc::as_ptr(enclosing) as *const ());
+        }
+        self.values
+            .borrow()
+            .values()
+            .flatten()
+            .for_each(|v| v.trace(visit));
+        self.slots.borrow().iter().for_each(|v| v.trace(visit));
+    }
+
+    fn heap_size(&self) -> usize {
+        let global = mem::size_of::<Symbol>() + mem::size_of::<Option<RlValue>>();
+        mem::size_of::<Self>()
+            + self.values.borrow().capacity() * global
+            + self.slots.borrow().capacity() * mem::size_of::<RlValue>()
+    }
+}
+
+impl Clear for Environment {
+    type Contents = (HashMap<Symbol, Option<RlValue>>, Vec<RlValue>);
+
+    fn take_all(&mut self) -> Self::Contents {
+        (self.values.take(), self.slots.take())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -136,12 +259,12 @@ mod test {
     fn simple_read() -> Result<()> {
         let env = Rc::new(RefCell::new(Environment::new(None, 0)));
 
-        let key = Token::simple_token(TokenType::Var, String::from("k1"), 1);
+        let key = Token::simple_token(TokenType::Var, "k1", 1);
         let value = RlValue::Boolean(true);
 
         env.borrow().define(key.clone(), Some(value));
         let res = env.borrow().get(&key)?;
-        assert!(matches!(res, Some(RlValue::Boolean(true))));
+        assert_eq!(res, Some(RlValue::Boolean(true)));
 
         Ok(())
     }
@@ -152,19 +275,49 @@ mod test {
         let outer_cpy = Rc::clone(&outer);
         let inner = Rc::new(RefCell::new(Environment::new(Some(outer_cpy), 1)));
 
-        let key = Token::simple_token(TokenType::Var, String::from("k1"), 1);
+        let key = Token::simple_token(TokenType::Var, "k1", 1);
         let value = RlValue::Boolean(true);
 
         outer.borrow().define(key.clone(), Some(value));
         let res = outer.borrow().get(&key)?;
-        assert!(matches!(res, Some(RlValue::Boolean(true))));
+        assert_eq!(res, Some(RlValue::Boolean(true)));
 
         let res = inner.borrow().get(&key)?;
-        assert!(matches!(res, Some(RlValue::Boolean(true))));
+        assert_eq!(res, Some(RlValue::Boolean(true)));
 
         let res = inner.borrow().get_local(&key);
         assert!(res.is_err());
 
         Ok(())
     }
+
+    #[test]
+    fn slot_read_write() -> Result<()> {
+        let globals = Rc::new(RefCell::new(Environment::new(None, 0)));
+        let outer = Rc::new(RefCell::new(Environment::new(Some(globals), 1)));
+        let inner = Environment::new(Some(Rc::clone(&outer)), 2);
+
+        outer
+            .borrow()
+            .define_name("a".into(), Some(RlValue::Double(1.0)));
+        outer.borrow().define_name("b".into(), None);
+        inner.define_name("c".into(), Some(RlValue::Boolean(true)));
+
+        let b = Slot { depth: 1, index: 1 };
+        assert!(inner.get_at(b)?.is_nil());
+        inner.assign_at(b, RlValue::Double(2.0))?;
+        let a = outer.borrow().get_at(Slot { depth: 0, index: 1 })?;
+        assert_eq!(a.as_numeric(), Some(2.0));
+        assert_eq!(
+            inner.get_at(Slot { depth: 0, index: 0 })?,
+            RlValue::Boolean(true)
+        );
+
+        // locals aren't kept by name
+        let key = Token::simple_token(TokenType::Identifier, "a", 1);
+        assert!(outer.borrow().get_local(&key).is_err());
+        assert!(inner.get_at(Slot { depth: 0, index: 1 }).is_err());
+
+        Ok(())
+    }
 }
@@ -1,8 +1,16 @@
 #![allow(dead_code)]
 
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
 use thiserror::Error;
 
+use crate::builtins::ScriptValue;
+use crate::limits::Limit;
+use crate::map::MapKey;
 use crate::rlvalue::RlValue;
+use crate::token::Span;
 
 /// An `RloxError` wrapper for both garden variety errors
 /// as well as early-return (and just any return) from a
@@ -15,8 +23,26 @@ pub enum RloxError {
     #[error("not-an-error, just return this value ...")]
     Return(Option<RlValue>),
 
-    #[error("expected {0} args, but got {1}")]
-    ArityError(usize, usize),
+    #[error("not-an-error, just break out of the loop ...")]
+    Break,
+
+    #[error("not-an-error, just continue to the next loop iteration ...")]
+    Continue,
+
+    /// A value thrown by a script's `throw`, unwinding to the nearest
+    /// `catch`. It's only an error if nothing catches it.
+    #[error("[{span}] uncaught exception: {value}")]
+    Throw { value: RlValue, span: Span },
+
+    // these, and the other errors with an optional span, are also made by
+    // builtins and host functions, which don't know where they were called
+    // from. the call fills it in.
+    #[error("{}expected {expected} args, but got {got}", at(.span))]
+    ArityError {
+        expected: usize,
+        got: usize,
+        span: Option<Span>,
+    },
 
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
@@ -24,20 +50,78 @@ pub enum RloxError {
     #[error("{0}")]
     ParseError(String),
 
-    #[error("{0}")]
-    UndefinedSymbol(String),
+    #[error("{}{message}", at(.span))]
+    UndefinedSymbol { message: String, span: Option<Span> },
+
+    #[error("{}{message}", at(.span))]
+    UndefinedVariable { message: String, span: Option<Span> },
+
+    #[error("{}{message}", at(.span))]
+    IncorrectType { message: String, span: Option<Span> },
+
+    /// An operator was applied to values of the wrong type,
+    /// like `"a" - 1`.
+    #[error("[{span}] invalid operand type for '{operator}': {operands}")]
+    TypeError {
+        operator: String,
+        // the offending operand type(s), like "string and number"
+        operands: String,
+        span: Span,
+    },
 
+    /// A list was indexed outside of it's bounds.
+    #[error("{}index {index} out of bounds for list of length {len}", at(.span))]
+    IndexError {
+        index: f64,
+        len: usize,
+        span: Option<Span>,
+    },
+
+    #[error("{}key {key} not found in map", at(.span))]
+    KeyError { key: String, span: Option<Span> },
+
+    /// Only nil, booleans, numbers (other than NaN) and strings
+    /// can be used as map keys.
+    #[error("{}{type_name} can't be used as a map key", at(.span))]
+    UnhashableKey {
+        type_name: String,
+        span: Option<Span>,
+    },
+
+    /// A module couldn't be loaded: it's missing, it's part of an import
+    /// cycle, or it failed to run.
+    #[error("[{span}] can't import \"{path}\": {message}")]
+    ImportError {
+        path: String,
+        message: String,
+        span: Span,
+    },
+
+    /// The host asked the script to stop, through it's `Interrupt`.
+    /// Scripts can't catch this either.
+    #[error("interrupted")]
+    Interrupted,
+
+    /// The script went over one of the `Limits` the host set for it.
+    /// Scripts can't catch these.
     #[error("{0}")]
-    UndefinedVariable(String),
+    LimitExceeded(Limit),
+
+    /// Calls nested deeper than the maximum call depth allows.
+    #[error("[{span}] stack overflow")]
+    StackOverflow { span: Span },
 
     #[error("{0}")]
-    IncorrectType(String),
+    CompileError(String),
+
+    #[error("{}{message}", at(.span))]
+    RuntimeError { message: String, span: Option<Span> },
 
     #[error("{0}")]
     ResolutionError(String),
 
-    #[error("{0}")]
-    ResolveError(String),
+    #[error("[{span}] {message}")]
+    ResolveError { message: String, span: Span },
 
     #[error("how did you get here?!?!: {0}")]
     Unreachable(String),
@@ -46,4 +130,118 @@ pub enum RloxError {
     Unsupported(String),
 }
 
+impl RloxError {
+    /// Where in the source the error happened, if we know.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            RloxError::Throw { span, .. }
+            | RloxError::TypeError { span, .. }
+            | RloxError::ResolveError { span, .. }
+            | RloxError::ImportError { span, .. }
+            | RloxError::StackOverflow { span } => Some(*span),
+            RloxError::ArityError { span, .. }
+            | RloxError::UndefinedSymbol { span, .. }
+            | RloxError::UndefinedVariable { span, .. }
+            | RloxError::IncorrectType { span, .. }
+            | RloxError::IndexError { span, .. }
+            | RloxError::KeyError { span, .. }
+            | RloxError::UnhashableKey { span, .. }
+            | RloxError::RuntimeError { span, .. } => *span,
+            _ => None,
+        }
+    }
+
+    /// Place the error at `call` if it doesn't know where it happened,
+    /// as for those from builtin and host functions.
+    pub(crate) fn or_at(mut self, call: Span) -> Self {
+        if let RloxError::ArityError { span, .. }
+        | RloxError::UndefinedSymbol { span, .. }
+        | RloxError::UndefinedVariable { span, .. }
+        | RloxError::IncorrectType { span, .. }
+        | RloxError::IndexError { span, .. }
+        | RloxError::KeyError { span, .. }
+        | RloxError::UnhashableKey { span, .. }
+        | RloxError::RuntimeError { span, .. } = &mut self
+        {
+            span.get_or_insert(call);
+        }
+        self
+    }
+
+    /// Whether this is a `return`, `break` or `continue` unwinding to
+    /// where it's handled, rather than an actual error.
+    pub(crate) fn is_control_flow(&self) -> bool {
+        matches!(
+            self,
+            RloxError::Return(_) | RloxError::Break | RloxError::Continue
+        )
+    }
+
+    /// What a script's `catch` calls the error, or `None` for the errors
+    /// scripts can't catch: the control flow ones, and those which mean
+    /// the program itself is broken.
+    pub fn exception_type(&self) -> Option<&'static str> {
+        match self {
+            RloxError::ArityError { .. } => Some("arity error"),
+            RloxError::IncorrectType { .. } | RloxError::TypeError { .. } => Some("type error"),
+            RloxError::UndefinedSymbol { .. } | RloxError::UndefinedVariable { .. } => {
+                Some("undefined variable")
+            }
+            RloxError::IndexError { .. } => Some("index error"),
+            RloxError::KeyError { .. } | RloxError::UnhashableKey { .. } => Some("key error"),
+            RloxError::ImportError { .. } => Some("import error"),
+            RloxError::IoError(_) => Some("io error"),
+            RloxError::StackOverflow { .. } => Some("stack overflow"),
+            RloxError::RuntimeError { .. } => Some("runtime error"),
+            _ => None,
+        }
+    }
+
+    /// The value a `catch` sees for a runtime error, a map describing it
+    /// like `{"line": 2, "message": "...", "type": "type error"}`. `None`
+    /// if it can't be caught, or if it's a `throw`, as the backend which
+    /// threw it has the actual value.
+    pub(crate) fn exception<V: ScriptValue>(&self) -> Option<V> {
+        let kind = self.exception_type()?;
+
+        // the span is it's own entry, so drop it from the message
+        let mut message = self.to_string();
+        if let Some(span) = self.span() {
+            let prefix = format!("[{}] ", span);
+            if let Some(rest) = message.strip_prefix(&prefix) {
+                message = rest.to_string();
+            }
+        }
+
+        let mut map = HashMap::new();
+        let mut insert = |key: &str, value: V| map.insert(MapKey::String(Rc::from(key)), value);
+        insert("type", V::string(kind));
+        insert("message", V::string(&message));
+        if let Some(span) = self.span() {
+            insert("line", V::number(span.line as f64));
+        }
+        Some(V::new_map(map))
+    }
+}
+
+/// One line of a traceback: a function an error unwound out of, and the
+/// line it had got to in it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceFrame {
+    pub(crate) function: String,
+    pub(crate) line: u32,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at {} (line {})", self.function, self.line)
+    }
+}
+
+/// The `[line 1:7] ` an error's message starts with, if it knows where
+/// it happened.
+fn at(span: &Option<Span>) -> String {
+    span.map(|span| format!("[{}] ", span)).unwrap_or_default()
+}
+
 pub type Result<T, E = RloxError> = core::result::Result<T, E>;
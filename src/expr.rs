@@ -1,21 +1,75 @@
 #![allow(dead_code)]
 
-use crate::token::Token;
+use crate::stmt::Stmt;
+use crate::token::{Span, Token};
 
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Identifies a single expression in the syntax tree, for the resolver's
+/// side tables. Every parse draws from the same counter, so expressions
+/// from different files or repl inputs never share one, even when they
+/// look the same.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ExprId(u64);
+
+static NEXT_EXPR_ID: AtomicU64 = AtomicU64::new(0);
+
+impl ExprId {
+    fn next() -> Self {
+        Self(NEXT_EXPR_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// An expression, and the id it was given when it was parsed. Clones
+/// keep the id, as they're the same bit of source.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Expr {
+    pub(crate) id: ExprId,
+    pub(crate) kind: ExprKind,
+}
+
+impl Expr {
+    pub(crate) fn new(kind: ExprKind) -> Self {
+        Self {
+            id: ExprId::next(),
+            kind,
+        }
+    }
+}
+
+/// Wraps the kind with a fresh id, which is how the parser builds
+/// expressions.
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Self::new(kind)
+    }
+}
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub enum Expr {
+pub enum ExprKind {
     Assign(Token, Box<Expr>),
     Binary(Box<Expr>, Token, Box<Expr>),
     Call(Box<Expr>, Token, Vec<Expr>),
     Get(Box<Expr>, Token),
     Grouping(Box<Expr>),
-    Literal(LiteralValue),
+    // `object[index]`, the token is the closing ']'
+    Index(Box<Expr>, Token, Box<Expr>),
+    // `fun (params) { body }`, the token is the `fun` keyword. the body is
+    // shared with every function value made from it.
+    Lambda(Token, Vec<Token>, Rc<Vec<Stmt>>),
+    // `[a, b, c]`, the token is the opening '[', spanning the whole literal
+    List(Token, Vec<Expr>),
+    Literal(LiteralValue, Span),
     Logical(Box<Expr>, Token, Box<Expr>),
+    // `{key: value, ...}`, the token is the opening '{', spanning the whole literal
+    Map(Token, Vec<(Expr, Expr)>),
     Set(Box<Expr>, Token, Box<Expr>),
+    // `object[index] = value`, the token is the closing ']'
+    SetIndex(Box<Expr>, Token, Box<Expr>, Box<Expr>),
     Super(Token, Token),
     This(Token),
     Unary(Token, Box<Expr>),
@@ -24,7 +78,7 @@ pub enum Expr {
 
 #[derive(Clone, Debug)]
 pub enum LiteralValue {
-    String(String),
+    String(Rc<str>),
     Number(f64),
     Boolean(bool),
     Nil(),
@@ -73,21 +127,69 @@ impl Display for LiteralValue {
     }
 }
 impl Expr {
-    fn sorta_pretty_print(expr: &Expr) -> String {
-        use Expr::*;
-        match expr {
-            Assign(_t, _e) => String::new(),
-            Binary(l, t, r) => Self::parenthesize(Some(&t.lexeme), vec![l, r]),
-            Call(_e, _t, _v) => String::new(),
-            Get(_e, _t) => String::new(),
-            Grouping(e) => Self::parenthesize(None, vec![e]),
-            Literal(l) => format!("{}", l),
-            Logical(_l, _t, _r) => String::new(),
-            Set(_l, _t, _r) => String::new(),
-            Super(_t1, _t2) => String::new(),
-            This(_t) => String::new(),
-            Unary(t, e) => Self::parenthesize(Some(&t.lexeme), vec![e]),
-            Variable(_t) => String::new(),
+    pub fn kind(&self) -> &ExprKind {
+        &self.kind
+    }
+
+    /// The source the whole expression covers.
+    pub fn span(&self) -> Span {
+        use ExprKind::*;
+        match &self.kind {
+            Assign(t, e) => t.span.to(e.span()),
+            Binary(l, _t, r) | Logical(l, _t, r) => l.span().to(r.span()),
+            Call(callee, paren, _args) => callee.span().to(paren.span),
+            Get(object, name) => object.span().to(name.span),
+            Grouping(e) => e.span(),
+            Index(object, bracket, _index) => object.span().to(bracket.span),
+            List(bracket, _elements) => bracket.span,
+            Lambda(keyword, _params, body) => body
+                .iter()
+                .filter_map(|s| s.span())
+                .fold(keyword.span, Span::to),
+            Literal(_l, span) => *span,
+            Map(brace, _entries) => brace.span,
+            Set(object, _, value) | SetIndex(object, _, _, value) => object.span().to(value.span()),
+            Super(keyword, method) => keyword.span.to(method.span),
+            This(t) | Variable(t) => t.span,
+            Unary(t, e) => t.span.to(e.span()),
+        }
+    }
+
+    /// Print the expression lisp style, like CI's `AstPrinter`,
+    /// e.g. `(* (- 123) (group 45.67))`.
+    pub(crate) fn sorta_pretty_print(expr: &Expr) -> String {
+        use ExprKind::*;
+        match &expr.kind {
+            Assign(t, e) => Self::parenthesize(Some(&format!("= {}", t.lexeme)), vec![e]),
+            Binary(l, t, r) => Self::parenthesize(Some(t.lexeme.as_str()), vec![l, r]),
+            Call(callee, _t, args) => {
+                let mut exprs = vec![callee.as_ref()];
+                exprs.extend(args);
+                Self::parenthesize(Some("call"), exprs)
+            }
+            Get(e, t) => Self::parenthesize(Some(&format!(". {}", t.lexeme)), vec![e]),
+            Grouping(e) => Self::parenthesize(Some("group"), vec![e]),
+            Index(object, _t, index) => Self::parenthesize(Some("index"), vec![object, index]),
+            List(_t, elements) => Self::parenthesize(Some("list"), elements.iter().collect()),
+            Lambda(_t, params, _body) => {
+                let params: Vec<&str> = params.iter().map(|p| p.lexeme.as_str()).collect();
+                format!("(fun ({}) ...)", params.join(" "))
+            }
+            Literal(LiteralValue::String(s), _span) => format!("{:?}", s),
+            Literal(l, _span) => format!("{}", l),
+            Logical(l, t, r) => Self::parenthesize(Some(t.lexeme.as_str()), vec![l, r]),
+            Map(_t, entries) => Self::parenthesize(
+                Some("map"),
+                entries.iter().flat_map(|(k, v)| [k, v]).collect(),
+            ),
+            Set(l, t, r) => Self::parenthesize(Some(&format!("= .{}", t.lexeme)), vec![l, r]),
+            SetIndex(object, _t, index, value) => {
+                Self::parenthesize(Some("= index"), vec![object, index, value])
+            }
+            Super(_t, method) => format!("super.{}", method.lexeme),
+            This(_t) => String::from("this"),
+            Unary(t, e) => Self::parenthesize(Some(t.lexeme.as_str()), vec![e]),
+            Variable(t) => t.lexeme.to_string(),
         }
     }
 
@@ -112,29 +214,57 @@ impl Expr {
 
 #[cfg(test)]
 mod test {
-    use crate::expr::{Expr, LiteralValue};
-    use crate::token::{Token, TokenType};
+    use crate::expr::{Expr, ExprKind, LiteralValue};
+    use crate::token::{Span, Token, TokenType};
+    use std::rc::Rc;
 
     #[test]
     fn simple_literal() {
-        let s = Expr::Literal(LiteralValue::String("asdf".to_string()));
+        let s = Expr::new(ExprKind::Literal(
+            LiteralValue::String(Rc::from("asdf")),
+            Span::default(),
+        ));
         println!("{:?}", Expr::sorta_pretty_print(&s));
     }
 
     #[test]
     fn simple_negative() {
-        let n = Box::new(Expr::Literal(LiteralValue::Number(42.0)));
-        let neg = Token::simple_token(TokenType::Bang, "!".to_string(), 0);
-        let unary = Expr::Unary(neg, n);
+        let n = Box::new(Expr::new(ExprKind::Literal(
+            LiteralValue::Number(42.0),
+            Span::default(),
+        )));
+        let neg = Token::simple_token(TokenType::Bang, "!", 0);
+        let unary = Expr::new(ExprKind::Unary(neg, n));
         println!("{:?}", Expr::sorta_pretty_print(&unary));
     }
 
     #[test]
     fn simple_add() {
-        let left = Box::new(Expr::Literal(LiteralValue::Number(42.0)));
-        let right = Box::new(Expr::Literal(LiteralValue::Number(3.0)));
-        let plus = Token::simple_token(TokenType::Plus, "+".to_string(), 0);
-        let binary = Expr::Binary(left, plus, right);
-        println!("{:?}", Expr::sorta_pretty_print(&binary));
+        let left = Box::new(Expr::new(ExprKind::Literal(
+            LiteralValue::Number(42.0),
+            Span::default(),
+        )));
+        let right = Box::new(Expr::new(ExprKind::Literal(
+            LiteralValue::Number(3.0),
+            Span::default(),
+        )));
+        let plus = Token::simple_token(TokenType::Plus, "+", 0);
+        let binary = Expr::new(ExprKind::Binary(left, plus, right));
+        assert_eq!(Expr::sorta_pretty_print(&binary), "(+ 42 3)");
+    }
+
+    #[test]
+    fn span_covers_whole_expression() {
+        let left = Box::new(Expr::new(ExprKind::Literal(
+            LiteralValue::Number(42.0),
+            Span::new(7, 2, 1, 8),
+        )));
+        let right = Box::new(Expr::new(ExprKind::Literal(
+            LiteralValue::Number(3.0),
+            Span::new(12, 1, 1, 13),
+        )));
+        let plus = Token::simple_token(TokenType::Plus, "+", 1).with_span(Span::new(10, 1, 1, 11));
+        let binary = Expr::new(ExprKind::Binary(left, plus, right));
+        assert_eq!(binary.span(), Span::new(7, 6, 1, 8));
     }
 }
@@ -0,0 +1,377 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::environment::Environment;
+use crate::map::MapKey;
+
+/// Values are reference counted, which frees almost everything as soon as
+/// it's unused, but not cycles. The most common one is a function stored
+/// in the environment it closes over, which every named function declared
+/// in a block is. This finds and frees those.
+///
+/// Every instance, list, map and class the tree-walking interpreter
+/// makes is registered here (weakly), along with the environments which
+/// functions close over. So are the bytecode `Vm`'s, along with it's
+/// bound methods, closed upvalues and the closures holding them. A
+/// collection works out, for each of them, how many of it's strong
+/// references come from other registered objects. Any with references
+/// left over are held from somewhere else, like the interpreter, the
+/// `Vm`'s stack or a native stack frame, so they and everything they
+/// reach are live. What's left is only referenced by other garbage, and
+/// is freed by clearing it out, breaking the cycles.
+///
+/// As nothing needs to know the roots, it's safe to collect at any
+/// allocation, even in the middle of evaluating something.
+///
+/// The collector is per thread, shared by every interpreter on it.
+struct Heap {
+    objects: Vec<Object>,
+    // registered since the last collection
+    allocations: usize,
+    // how many allocations to allow before collecting again
+    threshold: usize,
+    // collect on every allocation, to shake out bugs
+    stress: bool,
+    collections: usize,
+    freed: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            allocations: 0,
+            threshold: MIN_THRESHOLD,
+            stress: false,
+            collections: 0,
+            freed: 0,
+        }
+    }
+}
+
+// collecting more often than this would spend more time looking for
+// garbage than it's worth.
+const MIN_THRESHOLD: usize = 1_000;
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap::default());
+}
+
+/// What the garbage collector has been up to, on this thread.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    /// Roughly how many bytes the live objects which could be part of a
+    /// cycle take up. Strings and other values they hold aren't counted.
+    pub heap_bytes: usize,
+    /// How many of those objects are alive.
+    pub objects: usize,
+    /// How many collections have run.
+    pub collections: usize,
+    /// How many objects collections have freed, in total.
+    pub freed: usize,
+}
+
+/// Something the collector can look inside of.
+pub(crate) trait Trace {
+    /// Call `visit` with every registered object this holds a strong
+    /// reference to, once for each reference.
+    fn trace(&self, visit: &mut dyn FnMut(*const ()));
+
+    /// Roughly how many bytes this takes up.
+    fn heap_size(&self) -> usize {
+        mem::size_of_val(self)
+    }
+}
+
+/// Something the collector can empty out, to break the cycles it's in.
+pub(crate) trait Clear {
+    type Contents;
+
+    /// Take out everything this references. It's handed back to be
+    /// dropped once this is no longer borrowed, as it may include other
+    /// garbage.
+    fn take_all(&mut self) -> Self::Contents;
+}
+
+/// An object the collector keeps track of. Those which can be changed
+/// are a `RefCell` around something it can trace and clear, the rest
+/// are never changed, and only trace.
+pub(crate) trait Collect {
+    /// Visit everything this references, or return false if it can't be
+    /// looked at right now.
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool;
+
+    fn heap_size(&self) -> usize;
+
+    /// Drop everything this references. Those which can't be changed can
+    /// only be part of a cycle through something which can, so they don't.
+    fn clear(&self) {}
+}
+
+impl<T: Trace + Clear> Collect for RefCell<T> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        match self.try_borrow() {
+            Ok(o) => {
+                o.trace(visit);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.try_borrow().map(|o| o.heap_size()).unwrap_or_default()
+    }
+
+    fn clear(&self) {
+        let values = self.try_borrow_mut().map(|mut o| o.take_all());
+        drop(values);
+    }
+}
+
+impl<V: Trace> Trace for Vec<V> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        self.iter().for_each(|v| v.trace(visit));
+    }
+
+    fn heap_size(&self) -> usize {
+        mem::size_of::<Self>() + self.capacity() * mem::size_of::<V>()
+    }
+}
+
+impl<V> Clear for Vec<V> {
+    type Contents = Self;
+
+    fn take_all(&mut self) -> Self {
+        mem::take(self)
+    }
+}
+
+impl<V: Trace> Trace for HashMap<MapKey, V> {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        self.values().for_each(|v| v.trace(visit));
+    }
+
+    fn heap_size(&self) -> usize {
+        let entry = mem::size_of::<MapKey>() + mem::size_of::<V>();
+        mem::size_of::<Self>() + self.capacity() * entry
+    }
+}
+
+impl<V> Clear for HashMap<MapKey, V> {
+    type Contents = Self;
+
+    fn take_all(&mut self) -> Self {
+        mem::take(self)
+    }
+}
+
+/// Start keeping track of a newly made object, and maybe collect.
+pub(crate) fn track<T: Collect + 'static>(object: &Rc<T>) {
+    let object = Object(Rc::downgrade(object) as Weak<dyn Collect>);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.push(object);
+        heap.allocations += 1;
+        if heap.stress || heap.allocations >= heap.threshold {
+            heap.collect();
+        }
+    });
+}
+
+/// Start keeping track of an environment a function is closing over, and
+/// those enclosing it, if that hasn't happened already.
+///
+/// Environments can only end up in a cycle once something closes over
+/// them (or one nested inside them), so they aren't tracked before then.
+/// Most blocks and calls never are, and tracking them would be slow.
+pub(crate) fn track_closure(env: &Rc<RefCell<Environment>>) {
+    let mut env = Rc::clone(env);
+    // if one is tracked, so are all those enclosing it
+    while env.borrow().start_tracking() {
+        track(&env);
+        let enclosing = env.borrow().enclosing();
+        match enclosing {
+            Some(enclosing) => env = enclosing,
+            None => break,
+        }
+    }
+}
+
+/// Run a collection now, returning how many objects it freed.
+pub fn collect() -> usize {
+    HEAP.with(|heap| heap.borrow_mut().collect())
+}
+
+pub fn stats() -> GcStats {
+    HEAP.with(|heap| heap.borrow().stats())
+}
+
+/// Collect on every allocation, or stop doing so.
+pub fn set_stress(on: bool) {
+    HEAP.with(|heap| heap.borrow_mut().stress = on);
+}
+
+impl Heap {
+    fn collect(&mut self) -> usize {
+        self.collections += 1;
+        self.allocations = 0;
+
+        // everything still alive, once each. this drops the dead ones
+        // from the registry too.
+        let mut index: HashMap<*const (), usize> = HashMap::new();
+        let mut nodes: Vec<Node> = Vec::new();
+        self.objects.retain(|object| match object.upgrade() {
+            Some(node) if !index.contains_key(&node.ptr()) => {
+                index.insert(node.ptr(), nodes.len());
+                nodes.push(node);
+                true
+            }
+            _ => false,
+        });
+
+        // take away every reference which comes from another registered
+        // object, leaving those from outside. `nodes` holds one itself.
+        let mut outside: Vec<usize> = nodes.iter().map(|n| n.strong_count() - 1).collect();
+        let mut hidden = vec![false; nodes.len()];
+        for (i, node) in nodes.iter().enumerate() {
+            // something's in the middle of changing it, so it's in use
+            hidden[i] = !node.trace(&mut |child| {
+                if let Some(&j) = index.get(&child) {
+                    outside[j] = outside[j].saturating_sub(1);
+                }
+            });
+        }
+
+        // whatever is held from outside is live, and so is everything
+        // reachable from it.
+        let mut live = vec![false; nodes.len()];
+        let mut stack: Vec<usize> = (0..nodes.len())
+            .filter(|&i| outside[i] > 0 || hidden[i])
+            .collect();
+        while let Some(i) = stack.pop() {
+            if live[i] {
+                continue;
+            }
+            live[i] = true;
+            nodes[i].trace(&mut |child| {
+                if let Some(&j) = index.get(&child) {
+                    stack.push(j);
+                }
+            });
+        }
+
+        // the rest only keep each other alive
+        let garbage: Vec<Node> = nodes
+            .into_iter()
+            .zip(live)
+            .filter_map(|(node, live)| (!live).then_some(node))
+            .collect();
+        for node in &garbage {
+            node.clear();
+        }
+
+        let freed = garbage.len();
+        self.freed += freed;
+        self.threshold = MIN_THRESHOLD.max(2 * (self.objects.len() - freed));
+        freed
+    }
+
+    fn stats(&self) -> GcStats {
+        let mut stats = GcStats {
+            collections: self.collections,
+            freed: self.freed,
+            ..GcStats::default()
+        };
+        for node in self.objects.iter().filter_map(Object::upgrade) {
+            stats.objects += 1;
+            stats.heap_bytes += node.heap_size();
+        }
+        stats
+    }
+}
+
+/// A registered object. Weak, so the registry doesn't keep anything alive.
+struct Object(Weak<dyn Collect>);
+
+impl Object {
+    fn upgrade(&self) -> Option<Node> {
+        self.0.upgrade().map(Node)
+    }
+}
+
+/// A registered object which is still alive, during a collection.
+struct Node(Rc<dyn Collect>);
+
+impl Node {
+    /// The same pointer `Trace::trace` visits references to it by.
+    fn ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+
+    fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        self.0.trace(visit)
+    }
+
+    fn heap_size(&self) -> usize {
+        self.0.heap_size()
+    }
+
+    fn clear(&self) {
+        self.0.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::callable::Callable;
+    use crate::rlvalue::RlValue;
+
+    #[test]
+    fn frees_a_function_stored_in_it_s_own_closure() {
+        let env = Rc::new(RefCell::new(Environment::new(None, 0)));
+        track_closure(&env);
+        let function = Callable::Dynamic {
+            name: "f".into(),
+            params: Vec::new(),
+            body: Rc::new(Vec::new()),
+            closure: Rc::clone(&env),
+            is_initializer: false,
+        };
+        env.borrow()
+            .define_name("f".into(), Some(RlValue::Callable(function)));
+
+        // still in use here, so it isn't touched
+        collect();
+        assert!(env.borrow().get_name("f".into()).is_ok());
+
+        let weak = Rc::downgrade(&env);
+        drop(env);
+        assert!(weak.upgrade().is_some(), "the cycle keeps it alive");
+        assert_eq!(collect(), 1);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn frees_a_list_containing_itself() {
+        let list = Rc::new(RefCell::new(Vec::new()));
+        track(&list);
+        list.borrow_mut().push(RlValue::List(Rc::clone(&list)));
+
+        // a list holding the list, from outside the heap, keeps both alive
+        let outer = vec![RlValue::List(Rc::clone(&list))];
+        let weak = Rc::downgrade(&list);
+        drop(list);
+        assert_eq!(collect(), 0);
+        drop(outer);
+        assert_eq!(collect(), 1);
+        assert!(weak.upgrade().is_none());
+    }
+}
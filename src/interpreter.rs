@@ -2,16 +2,35 @@
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-use crate::callable::Callable;
-use crate::environment::{self, Environment};
-use crate::error::{Result, RloxError};
-use crate::expr::{Expr, LiteralValue};
-use crate::rlvalue::RlValue;
+use crate::builtins;
+use crate::callable::{Callable, NativeFunction};
+use crate::class::{Class, Instance};
+use crate::environment::{self, Environment, Slot};
+use crate::error::{Result, RloxError, TraceFrame};
+use crate::expr::{Expr, ExprId, ExprKind, LiteralValue};
+use crate::gc;
+use crate::interrupt::Interrupt;
+use crate::limits::{Budget, Limits};
+use crate::map::MapKey;
+use crate::module::{self, Import, Module, Modules};
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::rlvalue::{RlValue, Unpacked};
+use crate::scanner::Scanner;
 use crate::stmt::Stmt;
-use crate::token::{Token, TokenType};
-use crate::ErrorReporter;
+use crate::symbol::{self, Symbol};
+use crate::token::{Span, Token, TokenType};
+use crate::{ErrorReporter, DEFAULT_MAX_CALL_DEPTH, DEFAULT_NATIVE_STACK_LIMIT};
+
+/// Once the stack a call is on has less than this left, it carries on on
+/// a new `STACK_SEGMENT` of stack. It's enough for the rest of the call,
+/// up to the next one, in a debug build.
+const STACK_RED_ZONE: usize = 128 * 1024;
+const STACK_SEGMENT: usize = 1024 * 1024;
 
 pub struct Interpreter {
     /// Top-most environment for holding, appropriately enough,
@@ -26,24 +45,68 @@ pub struct Interpreter {
     /// given `Environment`.
     env_id: RefCell<i32>,
 
-    /// For resolved variables, the distance from local context
-    /// to where it's defined.
-    locals: RefCell<HashMap<Expr, u32>>,
+    /// For resolved variables, by the id of the expression using them,
+    /// the distance from local context to where it's defined, and it's
+    /// slot there.
+    locals: RefCell<HashMap<ExprId, Slot>>,
+
+    /// The files being run and the modules they've imported.
+    modules: Modules<Module>,
+
+    /// What the host allows scripts to do, and how much of it the
+    /// current run has used.
+    pub(crate) budget: Budget,
+
+    /// Set from outside to stop the running script.
+    interrupt: Interrupt,
+
+    /// How many calls deep the script is right now, and how deep it
+    /// may go before a "stack overflow" error.
+    call_depth: usize,
+    max_call_depth: usize,
+
+    /// Every call recurses on the native stack too, so calls also fail
+    /// once more than `native_stack_limit` bytes of it are in use. That's
+    /// counted from `stack_base`, where the outermost run started or the
+    /// current stack segment did, plus `stack_below`, the bytes used on
+    /// the segments before it.
+    stack_base: usize,
+    stack_below: usize,
+    native_stack_limit: usize,
 
-    _error_reporter: ErrorReporter,
+    /// While an error is unwinding, the functions it's come out of so
+    /// far, innermost first.
+    trace: Vec<TraceFrame>,
+
+    /// The line an unwinding error was at, in the function it's
+    /// currently unwinding out of.
+    error_line: Option<u32>,
+
+    pub(crate) error_reporter: ErrorReporter,
 }
 
 impl Interpreter {
     pub fn new(error_reporter: ErrorReporter) -> Self {
         let env_id: i32 = 0;
         let globals = Rc::new(RefCell::new(Environment::new(None, env_id)));
+        gc::track_closure(&globals);
         let environment = globals.clone();
         Self {
             globals,
             environment,
             env_id: RefCell::new(env_id),
             locals: RefCell::new(HashMap::new()),
-            _error_reporter: error_reporter,
+            modules: Modules::default(),
+            budget: Budget::default(),
+            interrupt: Interrupt::default(),
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            stack_base: stack_position(),
+            stack_below: 0,
+            native_stack_limit: DEFAULT_NATIVE_STACK_LIMIT,
+            trace: Vec::new(),
+            error_line: None,
+            error_reporter,
         }
     }
 
@@ -60,263 +123,1430 @@ impl Interpreter {
     }
 
     pub fn interpret(&mut self, stmts: Vec<Stmt>) -> Result<()> {
-        for stmt in stmts.iter() {
-            self.execute(stmt)?;
+        self.start_run();
+        match stmts.iter().try_for_each(|stmt| self.execute(stmt)) {
+            // a `return` outside of any function just ends the script
+            Err(RloxError::Return(_)) => Ok(()),
+            res => res,
         }
-        Ok(())
+    }
+
+    fn start_run(&mut self) {
+        // a host function may run more script from inside a call, which
+        // still counts from where the outermost run started, against the
+        // same budget.
+        if self.call_depth == 0 {
+            self.clear_trace();
+            self.budget.start();
+            self.stack_base = stack_position();
+            self.stack_below = 0;
+        }
+    }
+
+    fn execute(&mut self, stmt: &Stmt) -> Result<()> {
+        let res = self.budget.step().and_then(|_| self.execute_stmt(stmt));
+        if let Err(e) = &res {
+            // the first statement an error comes out of is where it happened
+            if self.error_line.is_none() && !e.is_control_flow() {
+                let span = e.span().or_else(|| stmt.span());
+                self.error_line = span.map(|s| s.line);
+            }
+        }
+        res
     }
 
     // TODO: see if the 'mut' can be eliminated here - it's only used for changing the
     // environment
-    fn execute(&mut self, stmt: &Stmt) -> Result<()> {
+    //
+    // every call a script makes recurses through here and `evaluate_expr`,
+    // so anything more than a line or two goes in a method of it's own,
+    // which isn't inlined back in. that keeps the frames on the recursive
+    // path small, debug builds especially, which give every temporary in
+    // a function it's own slot on the stack.
+    fn execute_stmt(&mut self, stmt: &Stmt) -> Result<()> {
         match stmt {
             Stmt::Block(stmts) => {
                 let env = self.new_env();
-                self.execute_block(stmts, env)?;
-                Ok(())
+                self.execute_block(stmts, env)
             }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => self.execute_class(name, superclass.as_ref(), methods),
+            // like `Return`, unwind via the error path to the enclosing loop
+            Stmt::Break(_) => Err(RloxError::Break),
+            Stmt::Continue(_) => Err(RloxError::Continue),
             Stmt::Expression(e) => {
                 self.evaluate_expr(e)?;
                 Ok(())
             }
-            Stmt::Function { name, params, body } => {
-                // TODO: not sure if i really need to clone() all the things ...
-                let callable = Callable::Dynamic {
-                    params: params.clone(),
-                    body: body.clone(),
-                    closure: Rc::clone(&self.environment),
-                };
-                let rlcallable = Some(RlValue::Callable(callable));
-                self.environment.borrow().define(name.clone(), rlcallable);
-                Ok(())
-            }
+            Stmt::Function { name, params, body } => self.execute_function(name, params, body),
             Stmt::If {
                 condition,
                 then_branch,
                 else_branch,
-            } => {
-                if self.evaluate_expr(condition)?.is_truthy() {
-                    self.execute(then_branch)?;
-                } else if let Some(el) = else_branch {
-                    self.execute(el)?;
+            } => self.execute_if(condition, then_branch, else_branch.as_deref()),
+            Stmt::Import {
+                keyword,
+                path,
+                name,
+            } => self.execute_import(keyword, path, name),
+            Stmt::Print(e) => self.execute_print(e),
+            Stmt::Return { expr, .. } => self.execute_return(expr.as_ref()),
+            Stmt::Throw { keyword, value } => self.execute_throw(keyword, value),
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+                ..
+            } => self.execute_try(body, catch.as_ref(), finally.as_deref()),
+            Stmt::Var { name, initializer } => self.execute_var(name, initializer.as_ref()),
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => self.execute_while(condition, body, increment.as_ref()),
+        }
+    }
+
+    #[inline(never)]
+    fn execute_class(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        methods: &[Stmt],
+    ) -> Result<()> {
+        let superclass = match superclass {
+            Some(expr) => match self.evaluate_expr(expr)?.unpack() {
+                Unpacked::Class(class) => Some(class),
+                v => {
+                    return Err(RloxError::IncorrectType {
+                        message: format!("superclass must be a class, got {}", v.type_name()),
+                        span: Some(expr.span()),
+                    })
                 }
-                Ok(())
+            },
+            None => None,
+        };
+
+        // methods of a subclass close over an extra environment
+        // which holds `super`.
+        let restore_env = Rc::clone(&self.environment);
+        if let Some(ref superclass) = superclass {
+            let env = self.new_env();
+            env.define_name(*symbol::SUPER, Some(RlValue::Class(Rc::clone(superclass))));
+            self.environment = Rc::new(RefCell::new(env));
+        }
+
+        gc::track_closure(&self.environment);
+        let mut class_methods = HashMap::new();
+        for method in methods {
+            if let Stmt::Function { name, params, body } = method {
+                let callable = Callable::Dynamic {
+                    name: name.name(),
+                    params: params.clone(),
+                    body: Rc::new(body.clone()),
+                    closure: Rc::clone(&self.environment),
+                    is_initializer: name.name() == *symbol::INIT,
+                };
+                class_methods.insert(name.name(), callable);
             }
-            Stmt::Print(e) => {
-                let val = self.evaluate_expr(e)?;
-                println!("{}", val);
-                Ok(())
+        }
+
+        self.environment = restore_env;
+
+        // the methods only look the class up once they're called, so
+        // it's fine for it to only be defined now.
+        let class = Rc::new(Class::new(name.name(), superclass, class_methods));
+        gc::track(&class);
+        self.environment
+            .borrow()
+            .define(name.clone(), Some(RlValue::Class(class)));
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn execute_function(&mut self, name: &Token, params: &[Token], body: &[Stmt]) -> Result<()> {
+        // TODO: not sure if i really need to clone() all the things ...
+        gc::track_closure(&self.environment);
+        let callable = Callable::Dynamic {
+            name: name.name(),
+            params: params.to_vec(),
+            body: Rc::new(body.to_vec()),
+            closure: Rc::clone(&self.environment),
+            is_initializer: false,
+        };
+        let rlcallable = Some(RlValue::Callable(callable));
+        self.environment.borrow().define(name.clone(), rlcallable);
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn execute_if(
+        &mut self,
+        condition: &Expr,
+        then_branch: &Stmt,
+        else_branch: Option<&Stmt>,
+    ) -> Result<()> {
+        if self.evaluate_expr(condition)?.is_truthy() {
+            self.execute(then_branch)?;
+        } else if let Some(el) = else_branch {
+            self.execute(el)?;
+        }
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn execute_import(&mut self, keyword: &Token, path: &str, name: &Token) -> Result<()> {
+        let module = self.import(keyword, path)?;
+        self.environment
+            .borrow()
+            .define(name.clone(), Some(RlValue::Module(module)));
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn execute_print(&mut self, e: &Expr) -> Result<()> {
+        self.budget.print()?;
+        let val = self.evaluate_expr(e)?;
+        println!("{}", val);
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn execute_return(&mut self, expr: Option<&Expr>) -> Result<()> {
+        let ret = match expr {
+            Some(expr) => Some(self.evaluate_expr(expr)?),
+            None => None,
+        };
+
+        // holy shit, the CI book will throw an exception (in Java)
+        // in order to (early) return from a function.
+        Err(RloxError::Return(ret))
+    }
+
+    #[inline(never)]
+    fn execute_throw(&mut self, keyword: &Token, value: &Expr) -> Result<()> {
+        let value = self.evaluate_expr(value)?;
+        Err(RloxError::Throw {
+            value,
+            span: keyword.span,
+        })
+    }
+
+    #[inline(never)]
+    fn execute_try(
+        &mut self,
+        body: &[Stmt],
+        catch: Option<&(Token, Vec<Stmt>)>,
+        finally: Option<&[Stmt]>,
+    ) -> Result<()> {
+        let env = self.new_env();
+        let mut res = self.execute_block(body, env);
+
+        if let (Err(e), Some((name, handler))) = (&res, catch) {
+            if let Some(exception) = Self::exception(e) {
+                self.clear_trace();
+                let env = self.new_env();
+                env.define(name.clone(), Some(exception));
+                res = self.execute_block(handler, env);
             }
-            Stmt::Return { expr, .. } => {
-                let ret = match expr {
-                    Some(expr) => Some(self.evaluate_expr(expr)?),
-                    None => None,
-                };
+        }
+
+        // the finally block always runs, and if it unwinds itself
+        // (an error, or a return or break) that wins out.
+        if let Some(finally) = finally {
+            let env = self.new_env();
+            self.execute_block(finally, env)?;
+        }
+        res
+    }
+
+    #[inline(never)]
+    fn execute_var(&mut self, name: &Token, initializer: Option<&Expr>) -> Result<()> {
+        let val = match initializer {
+            Some(e) => Some(self.evaluate_expr(e)?),
+            None => None,
+        };
+        self.environment.borrow().define(name.clone(), val);
+        Ok(())
+    }
 
-                // holy shit, the CI book will throw an exception (in Java)
-                // in order to (early) return from a function.
-                Err(RloxError::Return(ret))
+    #[inline(never)]
+    fn execute_while(
+        &mut self,
+        condition: &Expr,
+        body: &Stmt,
+        increment: Option<&Expr>,
+    ) -> Result<()> {
+        while self.evaluate_expr(condition)?.is_truthy() {
+            match self.execute(body) {
+                Ok(()) | Err(RloxError::Continue) => (),
+                Err(RloxError::Break) => break,
+                Err(e) => return Err(e),
             }
-            Stmt::Var { name, initializer } => {
-                let val = match initializer {
-                    Some(e) => Some(self.evaluate_expr(e)?),
-                    None => None,
-                };
-                self.environment.borrow().define(name.clone(), val);
-                Ok(())
+
+            if let Some(incr) = increment {
+                self.evaluate_expr(incr)?;
             }
-            Stmt::While { condition, body } => {
-                while self.evaluate_expr(condition)?.is_truthy() {
-                    self.execute(body)?;
+            self.interrupt.check()?;
+        }
+        Ok(())
+    }
+
+    fn evaluate_expr(&mut self, expr: &Expr) -> Result<RlValue> {
+        use ExprKind::*;
+        match &expr.kind {
+            Assign(t, e) => self.evaluate_assign(expr.id, t, e),
+            Binary(l, t, r) => self.evaluate_binary(l, t, r),
+            Call(callee, paren, arguments) => self.evaluate_call(callee, paren, arguments),
+            Get(object, name) => self.evaluate_get(object, name),
+            Grouping(e) => self.evaluate_expr(e.as_ref()),
+            Index(object, bracket, index) => self.evaluate_index(object, bracket, index),
+            List(_bracket, elements) => self.evaluate_list(elements),
+            Lambda(_keyword, params, body) => {
+                gc::track_closure(&self.environment);
+                Ok(RlValue::Callable(Callable::Dynamic {
+                    name: *symbol::LAMBDA,
+                    params: params.clone(),
+                    body: Rc::clone(body),
+                    closure: Rc::clone(&self.environment),
+                    is_initializer: false,
+                }))
+            }
+            Literal(l, _span) => Ok(RlValue::from(l)),
+            Map(_brace, entries) => self.evaluate_map(entries),
+            Logical(left, operator, right) => self.evaluate_logical(left, operator, right),
+            Set(object, name, value) => self.evaluate_set(object, name, value),
+            SetIndex(object, bracket, index, value) => {
+                self.evaluate_set_index(object, bracket, index, value)
+            }
+            Super(_keyword, method) => self.evaluate_super(expr.id, method),
+            This(t) => self.look_up_variable(expr.id, t),
+            Unary(t, e) => self.evaluate_unary(t, e),
+            Variable(t) => self.look_up_variable(expr.id, t),
+        }
+    }
+
+    #[inline(never)]
+    fn evaluate_assign(&mut self, id: ExprId, t: &Token, e: &Expr) -> Result<RlValue> {
+        let value = self.evaluate_expr(e)?;
+        match self.locals.borrow().get(&id) {
+            Some(slot) => self.environment.borrow().assign_at(*slot, value.clone())?,
+            None => self.environment.borrow().assign_global(t, value.clone())?,
+        }
+        Ok(value)
+    }
+
+    #[inline(never)]
+    fn evaluate_binary(&mut self, l: &Expr, t: &Token, r: &Expr) -> Result<RlValue> {
+        let left = self.evaluate_expr(l)?;
+        let right = self.evaluate_expr(r)?;
+        self.binary_op(t, left, right)
+    }
+
+    #[inline(never)]
+    fn binary_op(&mut self, t: &Token, left: RlValue, right: RlValue) -> Result<RlValue> {
+        match t.token_type {
+            TokenType::Minus => {
+                let (left_d, right_d) = Self::numeric_operands(t, &left, &right)?;
+                Ok(RlValue::Double(left_d - right_d))
+            }
+            TokenType::Slash => {
+                let (left_d, right_d) = Self::numeric_operands(t, &left, &right)?;
+                Ok(RlValue::Double(left_d / right_d))
+            }
+            TokenType::Star => {
+                let (left_d, right_d) = Self::numeric_operands(t, &left, &right)?;
+                Ok(RlValue::Double(left_d * right_d))
+            }
+            TokenType::Plus => {
+                if let (Some(l), Some(r)) = (left.as_string(), right.as_string()) {
+                    self.budget.allocate(l.len() + r.len())?;
+                    let mut ss = String::with_capacity(l.len() + r.len());
+                    ss.push_str(&l);
+                    ss.push_str(&r);
+                    Ok(RlValue::String(Rc::from(ss)))
+                } else if left.is_numeric() && right.is_numeric() {
+                    let d = left.as_numeric().expect("nust be numeric")
+                        + right.as_numeric().expect("Must be numeric");
+                    Ok(RlValue::Double(d))
+                } else if let (Some(l), Some(r)) = (left.as_list(), right.as_list()) {
+                    // concatenating makes a new list, leaving both sides alone
+                    self.budget
+                        .allocate_list(l.borrow().len() + r.borrow().len())?;
+                    let mut values = l.borrow().clone();
+                    values.extend(r.borrow().iter().cloned());
+                    Ok(RlValue::new_list(values))
+                } else {
+                    Err(Self::type_error(t, &[&left, &right]))
                 }
-                Ok(())
             }
-            _ => Err(RloxError::Unsupported(format!(
-                "unsupported stmt type: {:?}",
-                stmt
+            TokenType::Greater => {
+                let (left_d, right_d) = Self::numeric_operands(t, &left, &right)?;
+                Ok(RlValue::Boolean(left_d > right_d))
+            }
+            TokenType::GreaterEqual => {
+                let (left_d, right_d) = Self::numeric_operands(t, &left, &right)?;
+                Ok(RlValue::Boolean(left_d >= right_d))
+            }
+            TokenType::Less => {
+                let (left_d, right_d) = Self::numeric_operands(t, &left, &right)?;
+                Ok(RlValue::Boolean(left_d < right_d))
+            }
+            TokenType::LessEqual => {
+                let (left_d, right_d) = Self::numeric_operands(t, &left, &right)?;
+                Ok(RlValue::Boolean(left_d <= right_d))
+            }
+            TokenType::BangEqual => Ok(RlValue::Boolean(left != right)),
+            TokenType::EqualEqual => Ok(RlValue::Boolean(left == right)),
+            _ => Err(RloxError::Unreachable(format!(
+                "unsupported Binary type: {:?}",
+                t,
             ))),
         }
     }
 
-    fn evaluate_expr(&mut self, expr: &Expr) -> Result<RlValue> {
-        use Expr::*;
-        match expr {
-            Assign(t, e) => {
-                let value = self.evaluate_expr(e)?;
-                self.environment.borrow().assign(t, value.clone())?;
-                Ok(value)
-            }
-            Binary(l, t, r) => {
-                let left = self.evaluate_expr(l)?;
-                let right = self.evaluate_expr(r)?;
-
-                match t.token_type {
-                    TokenType::Minus => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        let ret_val: f64 = left_d - right_d;
-                        Ok(RlValue::Double(ret_val))
-                    }
-                    TokenType::Slash => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        let ret_val: f64 = left_d / right_d;
-                        Ok(RlValue::Double(ret_val))
-                    }
-                    TokenType::Star => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        let ret_val: f64 = left_d * right_d;
-                        Ok(RlValue::Double(ret_val))
-                    }
-                    TokenType::Plus => {
-                        // TODO: there's a way to do this with match ...
-                        if left.is_string() && right.is_string() {
-                            let mut ss = left.as_string().expect("must be string").clone();
-                            ss.push_str(right.as_string().expect("must be string").as_str());
-                            Ok(RlValue::String(ss))
-                        } else if left.is_numeric() && right.is_numeric() {
-                            let d = left.as_numeric().expect("nust be numeric")
-                                + right.as_numeric().expect("Must be numeric");
-                            Ok(RlValue::Double(d))
-                        } else {
-                            Err(RloxError::IncorrectType(format!(
-                                "mismatched types for '+' operator, l: {:?}, , r: {:?}",
-                                l, r
-                            )))
-                        }
-                    }
-                    TokenType::Greater => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        Ok(RlValue::Boolean(left_d > right_d))
-                    }
-                    TokenType::GreaterEqual => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        Ok(RlValue::Boolean(left_d >= right_d))
-                    }
-                    TokenType::Less => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        Ok(RlValue::Boolean(left_d < right_d))
-                    }
-                    TokenType::LessEqual => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        Ok(RlValue::Boolean(left_d <= right_d))
-                    }
-                    TokenType::BangEqual => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        Ok(RlValue::Boolean(left_d != right_d))
-                    }
-                    TokenType::EqualEqual => {
-                        let left_d = left.as_numeric().unwrap();
-                        let right_d = right.as_numeric().unwrap();
-                        Ok(RlValue::Boolean(left_d == right_d))
-                    }
-                    _ => Err(RloxError::Unreachable(format!(
-                        "unsupported Binary type: {:?}",
-                        t,
-                    ))),
-                }
+    #[inline(never)]
+    fn evaluate_call(
+        &mut self,
+        callee: &Expr,
+        paren: &Token,
+        arguments: &[Expr],
+    ) -> Result<RlValue> {
+        // the thread's own stack may be small, so don't overflow it
+        if stacker::remaining_stack().is_some_and(|left| left < STACK_RED_ZONE) {
+            return self.on_new_stack(|this| this.evaluate_call(callee, paren, arguments));
+        }
+
+        let callee = self.evaluate_expr(callee)?.unpack();
+
+        let mut args = Vec::new();
+        for arg in arguments {
+            args.push(self.evaluate_expr(arg)?);
+        }
+
+        self.interrupt.check()?;
+
+        // catch runaway recursion before it overflows the real stack
+        let stack_used = self.stack_below + stack_position().abs_diff(self.stack_base);
+        if self.call_depth >= self.max_call_depth || stack_used > self.native_stack_limit {
+            return Err(RloxError::StackOverflow { span: paren.span });
+        }
+
+        let function = Self::frame_name(&callee);
+        self.call_depth += 1;
+        // errors from builtin and host functions, or from calling something
+        // which isn't a function, happened at the call
+        let res = self
+            .call_value(callee, &args)
+            .map_err(|e| e.or_at(paren.span));
+        self.call_depth -= 1;
+        if let Err(e) = &res {
+            self.unwind_call(e, function, paren);
+        }
+        res
+    }
+
+    /// Carry on on a new segment of stack, until `f` returns.
+    #[cold]
+    fn on_new_stack<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let (base, below) = (self.stack_base, self.stack_below);
+        self.stack_below += stack_position().abs_diff(base);
+        let res = stacker::grow(STACK_SEGMENT, || {
+            self.stack_base = stack_position();
+            f(self)
+        });
+        (self.stack_base, self.stack_below) = (base, below);
+        res
+    }
+
+    #[inline(never)]
+    fn evaluate_get(&mut self, object: &Expr, name: &Token) -> Result<RlValue> {
+        match self.evaluate_expr(object)?.unpack() {
+            Unpacked::Instance(instance) => Instance::get(&instance, name),
+            Unpacked::Module(module) => module.get(name),
+            v => Err(RloxError::IncorrectType {
+                message: format!("only instances have properties, got a {}", v.type_name()),
+                span: Some(name.span),
+            }),
+        }
+    }
+
+    #[inline(never)]
+    fn evaluate_index(&mut self, object: &Expr, bracket: &Token, index: &Expr) -> Result<RlValue> {
+        let object = self.evaluate_expr(object)?;
+        let index = self.evaluate_expr(index)?;
+        builtins::get_index(&object, &index, bracket.span)
+    }
+
+    #[inline(never)]
+    fn evaluate_list(&mut self, elements: &[Expr]) -> Result<RlValue> {
+        self.budget.allocate_list(elements.len())?;
+        let mut values = Vec::with_capacity(elements.len());
+        for e in elements {
+            values.push(self.evaluate_expr(e)?);
+        }
+        Ok(RlValue::new_list(values))
+    }
+
+    #[inline(never)]
+    fn evaluate_map(&mut self, entries: &[(Expr, Expr)]) -> Result<RlValue> {
+        self.budget.allocate_map(entries.len())?;
+        let mut map = HashMap::with_capacity(entries.len());
+        for (key, value) in entries {
+            let k = self.evaluate_expr(key)?;
+            let k = builtins::map_key(&k, Some(key.span()))?;
+            map.insert(k, self.evaluate_expr(value)?);
+        }
+        Ok(RlValue::new_map(map))
+    }
+
+    #[inline(never)]
+    fn evaluate_logical(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<RlValue> {
+        let l = self.evaluate_expr(left)?;
+        if operator.token_type == TokenType::Or {
+            if l.is_truthy() {
+                return Ok(l);
             }
-            Call(expr, _token, arguments) => {
-                let callee = self.evaluate_expr(expr)?;
-                let mut function = match callee {
-                    RlValue::Callable(c) => c,
-                    _ => {
-                        return Err(RloxError::IncorrectType(format!(
-                            "tried to call a function, but {:?} is not a function: {:?}",
-                            expr, callee
-                        )))
-                    }
-                };
+        } else if !l.is_truthy() {
+            return Ok(l);
+        }
 
-                let mut args = Vec::new();
-                for arg in arguments {
-                    args.push(self.evaluate_expr(arg)?);
-                }
+        self.evaluate_expr(right)
+    }
 
-                // check for the correct number of arguments
-                if args.len() != function.arity() {
-                    return Err(RloxError::ArityError(function.arity(), args.len()));
-                }
+    #[inline(never)]
+    fn evaluate_set(&mut self, object: &Expr, name: &Token, value: &Expr) -> Result<RlValue> {
+        let instance = match self.evaluate_expr(object)?.unpack() {
+            Unpacked::Instance(instance) => instance,
+            v => {
+                return Err(RloxError::IncorrectType {
+                    message: format!("only instances have fields, got a {}", v.type_name()),
+                    span: Some(name.span),
+                })
+            }
+        };
+
+        let value = self.evaluate_expr(value)?;
+        instance.borrow_mut().set(name, value.clone());
+        Ok(value)
+    }
 
-                Ok(function.call(self, &args)?)
+    #[inline(never)]
+    fn evaluate_set_index(
+        &mut self,
+        object: &Expr,
+        bracket: &Token,
+        index: &Expr,
+        value: &Expr,
+    ) -> Result<RlValue> {
+        let object = self.evaluate_expr(object)?;
+        let index = self.evaluate_expr(index)?;
+        if let Some(map) = object.as_map() {
+            let key = builtins::map_key(&index, Some(bracket.span))?;
+            let value = self.evaluate_expr(value)?;
+            if !map.borrow().contains_key(&key) {
+                self.budget.allocate_map(1)?;
             }
-            Get(_e, _t) => Ok(RlValue::Nil),
-            Grouping(e) => self.evaluate_expr(e.as_ref()),
-            Literal(l) => Ok(RlValue::from(l)),
-            Logical(left, operator, right) => {
-                let l = self.evaluate_expr(left)?;
-                if operator.token_type == TokenType::Or {
-                    if l.is_truthy() {
-                        return Ok(l);
-                    }
-                } else if !l.is_truthy() {
-                    return Ok(l);
-                }
+            map.borrow_mut().insert(key, value.clone());
+            return Ok(value);
+        }
 
-                self.evaluate_expr(right)
-            }
-            Set(_l, _t, _r) => Ok(RlValue::Nil),
-            Super(_t1, _t2) => Ok(RlValue::Nil),
-            This(_t) => Ok(RlValue::Nil),
-            Unary(t, e) => {
-                let right = self.evaluate_expr(e)?;
-                match t.token_type {
-                    TokenType::Minus => match right.as_numeric() {
-                        Some(d) => Ok(RlValue::Double(-d)),
-                        None => Err(RloxError::IncorrectType(String::from(
-                            "rlvalue not a numeric value",
-                        ))),
-                    },
-                    TokenType::Bang => {
-                        let b = !right.is_truthy();
-                        Ok(RlValue::Boolean(b))
-                    }
-                    _ => Err(RloxError::Unreachable(format!(
-                        "TokenType not accepted: {:?}",
-                        t.token_type,
-                    ))),
-                }
+        let (list, i) = builtins::list_position(&object, &index, bracket.span)?;
+        let value = self.evaluate_expr(value)?;
+        list.borrow_mut()[i] = value.clone();
+        Ok(value)
+    }
+
+    #[inline(never)]
+    fn evaluate_super(&mut self, id: ExprId, method: &Token) -> Result<RlValue> {
+        let slot = match self.locals.borrow().get(&id) {
+            Some(slot) => *slot,
+            None => {
+                return Err(RloxError::ResolutionError(String::from(
+                    "'super' was not resolved",
+                )))
+            }
+        };
+
+        let superclass = match self.environment.borrow().get_at(slot)?.unpack() {
+            Unpacked::Class(class) => class,
+            _ => {
+                return Err(RloxError::Unreachable(String::from(
+                    "'super' must be a class",
+                )))
+            }
+        };
+
+        // `this` is always bound in the environment just inside
+        // the one holding `super`, and both are alone in theirs.
+        let this = Slot {
+            depth: slot.depth - 1,
+            index: 0,
+        };
+        let object = match self.environment.borrow().get_at(this)?.unpack() {
+            Unpacked::Instance(instance) => instance,
+            _ => {
+                return Err(RloxError::Unreachable(String::from(
+                    "'this' must be an instance",
+                )))
             }
-            Variable(t) => match self.locals.borrow().get(expr) {
-                Some(depth) => Ok(Rc::clone(&self.environment).borrow().get_at(*depth, t)?),
-                None => Ok(self.globals.borrow().get(t)?.unwrap()),
+        };
+
+        match superclass.find_method(method.name()) {
+            Some(m) => Ok(m.bind_value(object)),
+            None => Err(RloxError::UndefinedSymbol {
+                message: format!("undefined property '{}'", method.lexeme),
+                span: Some(method.span),
+            }),
+        }
+    }
+
+    #[inline(never)]
+    fn evaluate_unary(&mut self, t: &Token, e: &Expr) -> Result<RlValue> {
+        let right = self.evaluate_expr(e)?;
+        match t.token_type {
+            TokenType::Minus => match right.as_numeric() {
+                Some(d) => Ok(RlValue::Double(-d)),
+                None => Err(Self::type_error(t, &[&right])),
             },
+            TokenType::Bang => {
+                let b = !right.is_truthy();
+                Ok(RlValue::Boolean(b))
+            }
+            _ => Err(RloxError::Unreachable(format!(
+                "TokenType not accepted: {:?}",
+                t.token_type,
+            ))),
         }
     }
 
-    pub fn execute_block(&mut self, stmts: &[Stmt], env: Environment) -> Result<RlValue> {
-        let restore_env = Rc::clone(&self.environment);
-        self.environment = Rc::new(RefCell::new(env));
-        let mut ret = Ok(RlValue::Nil);
-        for stmt in stmts {
-            match self.execute(stmt) {
-                Ok(_) => (),
-                Err(RloxError::Return(val)) => {
-                    if let Some(val) = val {
-                        ret = Ok(val);
-                    }
-                    break;
+    fn numeric_operands(operator: &Token, left: &RlValue, right: &RlValue) -> Result<(f64, f64)> {
+        match (left.as_numeric(), right.as_numeric()) {
+            (Some(l), Some(r)) => Ok((l, r)),
+            _ => Err(Self::type_error(operator, &[left, right])),
+        }
+    }
+
+    fn type_error(operator: &Token, operands: &[&RlValue]) -> RloxError {
+        let operands: Vec<&str> = operands.iter().map(|v| v.type_name()).collect();
+        RloxError::TypeError {
+            operator: operator.lexeme.to_string(),
+            operands: operands.join(" and "),
+            span: operator.span,
+        }
+    }
+
+    fn call_value(&mut self, callee: Unpacked, args: &[RlValue]) -> Result<RlValue> {
+        match callee {
+            Unpacked::Callable(mut function) => {
+                if let Callable::BuiltIn(_) = function {
+                    self.budget.builtins()?;
                 }
-                Err(e) => {
-                    ret = Err(e);
-                    break;
+                // check for the correct number of arguments
+                if args.len() != function.arity() {
+                    return Err(RloxError::ArityError {
+                        expected: function.arity(),
+                        got: args.len(),
+                        span: None,
+                    });
                 }
+                Ok(function.call(self, args)?)
             }
+            Unpacked::Class(class) => self.instantiate(class, args),
+            // the caller knows where the call was
+            v => Err(RloxError::IncorrectType {
+                message: format!("can only call functions and classes, got {}", v.type_name()),
+                span: None,
+            }),
+        }
+    }
+
+    #[inline(never)]
+    fn instantiate(&mut self, class: Rc<Class>, args: &[RlValue]) -> Result<RlValue> {
+        if args.len() != class.arity() {
+            return Err(RloxError::ArityError {
+                expected: class.arity(),
+                got: args.len(),
+                span: None,
+            });
+        }
+
+        let instance = Rc::new(RefCell::new(Instance::new(Rc::clone(&class))));
+        gc::track(&instance);
+        if let Some(init) = class.find_method(*symbol::INIT) {
+            init.bind(Rc::clone(&instance)).call(self, args)?;
+        }
+        Ok(RlValue::Instance(instance))
+    }
+
+    ///////////////////
+    // embedding api
+    ///////////////////
+
+    /// Register a host function, callable from scripts as a global
+    /// named `name`.
+    pub fn register_fn<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut Interpreter, &[RlValue]) -> Result<RlValue> + 'static,
+    {
+        let native = NativeFunction::new(name, arity, function);
+        let value = RlValue::Callable(Callable::Native(Rc::new(native)));
+        self.define_global(name, value);
+    }
+
+    /// Share `interrupt` with the host, to stop scripts with.
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = interrupt;
+    }
+
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    /// Bound what scripts may do from now on, see `Limits`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.budget.limits = limits;
+    }
+
+    /// Limit how deeply calls may nest, see `DEFAULT_MAX_CALL_DEPTH`.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// Limit how much native stack scripts' calls may use, see
+    /// `DEFAULT_NATIVE_STACK_LIMIT`.
+    pub fn set_native_stack_limit(&mut self, bytes: usize) {
+        self.native_stack_limit = bytes;
+    }
+
+    /// Define (or redefine) a global variable. It's name is interned, like
+    /// those of the globals scripts define.
+    pub fn define_global(&mut self, name: &str, value: RlValue) {
+        self.globals
+            .borrow()
+            .define_name(Symbol::intern(name), Some(value));
+    }
+
+    /// Read a global variable. Returns `None` if it isn't defined;
+    /// a declared-but-uninitialized variable reads as `nil`.
+    pub fn get_global(&self, name: &str) -> Option<RlValue> {
+        let name = Symbol::lookup(name)?;
+        match self.globals.borrow().get_name(name) {
+            Ok(v) => Some(v.unwrap_or_default()),
+            Err(_) => None,
+        }
+    }
+
+    /// All the global variables, sorted by name.
+    pub fn globals(&self) -> Vec<(String, RlValue)> {
+        self.globals
+            .borrow()
+            .values()
+            .into_iter()
+            .map(|(name, value)| (name, value.unwrap_or_default()))
+            .collect()
+    }
+
+    /// Call a global function (or class) defined by a script.
+    pub fn call_function(&mut self, name: &str, args: &[RlValue]) -> Result<RlValue> {
+        self.start_run();
+        match self.get_global(name) {
+            Some(callee) => self.call_value(callee.unpack(), args),
+            None => Err(RloxError::UndefinedSymbol {
+                message: name.to_string(),
+                span: None,
+            }),
+        }
+    }
+
+    fn look_up_variable(&self, id: ExprId, name: &Token) -> Result<RlValue> {
+        match self.locals.borrow().get(&id) {
+            Some(slot) => self.environment.borrow().get_at(*slot),
+            // globals are those of the file the code was written in, which
+            // for a module's functions isn't the file calling them.
+            None => Ok(self
+                .environment
+                .borrow()
+                .get_global(name)?
+                .unwrap_or_default()),
+        }
+    }
+
+    ///////////////////
+    // tracebacks
+    ///////////////////
+
+    /// The name a call to `callee` gets in a traceback. Host and builtin
+    /// functions don't get a line of their own.
+    fn frame_name(callee: &Unpacked) -> Option<String> {
+        match callee {
+            Unpacked::Callable(Callable::Dynamic { name, .. }) => Some(name.to_string()),
+            Unpacked::Class(class) => class
+                .find_method(*symbol::INIT)
+                .map(|_| String::from("init")),
+            _ => None,
+        }
+    }
+
+    /// Note the function `error` unwound out of, and that it's now at the
+    /// call to it, for the traceback.
+    fn unwind_call(&mut self, error: &RloxError, function: Option<String>, paren: &Token) {
+        if error.is_control_flow() {
+            return;
+        }
+        // no line means the call itself failed, like an arity error,
+        // and the function never ran.
+        if let (Some(function), Some(line)) = (function, self.error_line) {
+            self.trace.push(TraceFrame { function, line });
+        }
+        self.error_line = Some(paren.span.line);
+    }
+
+    /// The traceback of the last error to unwind out to the top level,
+    /// innermost call first.
+    pub(crate) fn take_trace(&mut self) -> Vec<TraceFrame> {
+        let mut trace = std::mem::take(&mut self.trace);
+        if let Some(line) = self.error_line.take() {
+            trace.push(TraceFrame {
+                function: String::from("<script>"),
+                line,
+            });
         }
+        trace
+    }
+
+    fn clear_trace(&mut self) {
+        self.trace.clear();
+        self.error_line = None;
+    }
+
+    ///////////////////
+    // modules
+    ///////////////////
+
+    /// Note that `path` is being run, so imports are found next to it.
+    pub(crate) fn enter_file(&mut self, path: &Path) -> Result<()> {
+        self.modules.enter_file(path)
+    }
+
+    pub(crate) fn leave_file(&mut self) {
+        self.modules.leave_file();
+    }
+
+    /// Load the module at `path`, running it the first time it's
+    /// imported. Later imports of the same file share the module.
+    fn import(&mut self, keyword: &Token, path: &str) -> Result<Rc<Module>> {
+        let import_error = |message: String| RloxError::ImportError {
+            path: path.to_string(),
+            message,
+            span: keyword.span,
+        };
+
+        let (file, source) = match self.modules.find(path).map_err(import_error)? {
+            Import::Loaded(module) => return Ok(module),
+            Import::Load { file, source } => (file, source),
+        };
+        let module = self
+            .run_module(source)
+            .map(|globals| Rc::new(Module::new(module::file_name(&file), globals)));
+        self.modules.loaded(module.as_ref().ok());
+        module.map_err(|_| import_error(String::from("the module failed to load")))
+    }
+
+    /// Scan, parse, resolve and run a module's source in a fresh top-level
+    /// environment. Errors are reported here, while the reporter can
+    /// still show the module's source.
+    fn run_module(&mut self, source: String) -> Result<Rc<RefCell<Environment>>> {
+        let restore_source = self.error_reporter.source();
+        let res = self.execute_module(source);
+        self.error_reporter.set_source(&restore_source);
+        res
+    }
+
+    fn execute_module(&mut self, source: String) -> Result<Rc<RefCell<Environment>>> {
+        // the scanner and parser report their own errors
+        let mut scanner = Scanner::new(source, self.error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), self.error_reporter.clone()).parse()?;
+
+        let mut resolver = Resolver::new(self);
+        if let Err(e) = resolver.resolve_stmts(&stmts) {
+            self.error_reporter.static_error(&e);
+            return Err(e);
+        }
+
+        self.env_id.replace_with(|&mut prev| prev + 1);
+        let globals = Rc::new(RefCell::new(Environment::new(None, *self.env_id.borrow())));
+        gc::track_closure(&globals);
+        let restore_env = std::mem::replace(&mut self.environment, Rc::clone(&globals));
+        let res = match stmts.iter().try_for_each(|stmt| self.execute(stmt)) {
+            Err(RloxError::Return(_)) => Ok(()),
+            res => res,
+        };
+        self.environment = restore_env;
+
+        if let Err(e) = res {
+            self.error_reporter.runtime_error(&e);
+            let trace = self.take_trace();
+            self.error_reporter.traceback(&trace);
+            return Err(e);
+        }
+        Ok(globals)
+    }
+
+    /// Run `stmts` in `env`. Anything which unwinds, a `return`, `throw`
+    /// or runtime error, is passed on up once the parent is restored.
+    pub fn execute_block(&mut self, stmts: &[Stmt], env: Environment) -> Result<()> {
+        let restore_env = Rc::clone(&self.environment);
+        self.environment = Rc::new(RefCell::new(env));
+        let ret = stmts.iter().try_for_each(|stmt| self.execute(stmt));
 
         // restore the parent
         self.environment = restore_env;
         ret
     }
 
-    pub fn resolve(&self, expr: &Expr, depth: u32) -> Result<()> {
-        self.locals.borrow_mut().insert(expr.clone(), depth);
+    /// The value a `catch` sees for `error`: a thrown value as is, and
+    /// a runtime error as a map describing it, like
+    /// `{"line": 2, "message": "...", "type": "type error"}`.
+    /// `None` if the error can't be caught.
+    fn exception(error: &RloxError) -> Option<RlValue> {
+        match error {
+            RloxError::Throw { value, .. } => Some(value.clone()),
+            error => error.exception(),
+        }
+    }
+
+    pub fn resolve(&self, id: ExprId, slot: Slot) -> Result<()> {
+        self.locals.borrow_mut().insert(id, slot);
+        Ok(())
+    }
+}
+
+/// Roughly how far the native stack has grown, as an address on it.
+fn stack_position() -> usize {
+    let here = 0u8;
+    std::hint::black_box(&here) as *const u8 as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::scanner::Scanner;
+
+    fn run(src: &str) -> Result<Interpreter> {
+        run_in(Interpreter::new(ErrorReporter::default()), src)
+    }
+
+    fn run_in(mut interpreter: Interpreter, src: &str) -> Result<Interpreter> {
+        let error_reporter = interpreter.error_reporter.clone();
+        let mut scanner = Scanner::new(src.to_string(), error_reporter.clone());
+        scanner.scan_tokens()?;
+        let tokens = scanner.tokens();
+        let stmts = Parser::new(tokens, error_reporter).parse()?;
+
+        let mut resolver = Resolver::new(&interpreter);
+        resolver.resolve_stmts(&stmts)?;
+        interpreter.interpret(stmts)?;
+        Ok(interpreter)
+    }
+
+    fn global(interpreter: &Interpreter, name: &str) -> RlValue {
+        let token = Token::simple_token(TokenType::Identifier, name, 0);
+        interpreter.globals.borrow().get(&token).unwrap().unwrap()
+    }
+
+    #[test]
+    fn class_fields_and_methods() -> Result<()> {
+        let src = r#"
+            class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                }
+                sum() {
+                    return this.x + this.y;
+                }
+            }
+            var p = Point(1, 2);
+            p.x = 10;
+            var s = p.sum();
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(global(&interpreter, "s").as_numeric(), Some(12.0));
+        Ok(())
+    }
+
+    #[test]
+    fn initializer_returns_this() -> Result<()> {
+        let src = r#"
+            class Foo {
+                init() {
+                    this.v = "set";
+                    return;
+                }
+            }
+            var f = Foo();
+            var g = f.init();
+            var v = g.v;
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(global(&interpreter, "v").as_string(), Some(Rc::from("set")));
+        Ok(())
+    }
+
+    #[test]
+    fn this_outside_class() {
+        assert!(matches!(
+            run("print this;"),
+            Err(RloxError::ResolveError { .. })
+        ));
+    }
+
+    #[test]
+    fn inherited_and_super_methods() -> Result<()> {
+        let src = r#"
+            class A {
+                name() { return "A"; }
+                greet() { return "hi " + this.name(); }
+            }
+            class B < A {
+                name() { return "B"; }
+                greet() { return super.greet() + "!"; }
+            }
+            class C < B {}
+            var g = C().greet();
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(
+            global(&interpreter, "g").as_string(),
+            Some(Rc::from("hi B!"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_super_usage() {
+        assert!(matches!(
+            run("class A < A {}"),
+            Err(RloxError::ResolveError { .. })
+        ));
+        assert!(matches!(
+            run("class A { f() { super.f(); } }"),
+            Err(RloxError::ResolveError { .. })
+        ));
+        assert!(matches!(
+            run("fun f() { super.f(); }"),
+            Err(RloxError::ResolveError { .. })
+        ));
+        assert_eq!(
+            run("var A = 1;\nclass B < A {}")
+                .err()
+                .map(|e| e.to_string()),
+            Some(String::from(
+                "[line 2:11] superclass must be a class, got number"
+            ))
+        );
+    }
+
+    #[test]
+    fn break_and_continue() -> Result<()> {
+        let src = r#"
+            var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                if (i == 3) continue;
+                if (i == 6) break;
+                sum = sum + i;
+            }
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(global(&interpreter, "sum").as_numeric(), Some(12.0));
+        Ok(())
+    }
+
+    #[test]
+    fn local_slots() -> Result<()> {
+        let src = r#"
+            fun f() {
+                var a = 1;
+                var unset;
+                var b = a + 1;
+                // a second 'a' gets it's own slot
+                var a = b * 10;
+                class Point { init(x) { this.x = x; } }
+                var counter = 0;
+                fun bump() { counter = counter + b; }
+                bump();
+                bump();
+                return [a, b, unset, counter, Point(7).x];
+            }
+            var got = f();
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(
+            global(&interpreter, "got").to_string(),
+            "[20, 2, nil, 4, 7]"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lambdas() -> Result<()> {
+        let src = r#"
+            fun apply(f, x) { return f(x); }
+            fun adder(n) { return fun (x) { return x + n; }; }
+            var doubled = apply(fun (x) { return x * 2; }, 21);
+            var added = adder(1)(2);
+            var total = 0;
+            var bump = fun () { total = total + 1; };
+            bump();
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(global(&interpreter, "doubled").as_numeric(), Some(42.0));
+        assert_eq!(global(&interpreter, "added").as_numeric(), Some(3.0));
+        assert_eq!(global(&interpreter, "total").as_numeric(), Some(1.0));
+
+        assert!(matches!(
+            run("var f = fun () { return this; };"),
+            Err(RloxError::ResolveError { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn return_from_nested_block() -> Result<()> {
+        let src = r#"
+            fun first_over(xs, n) {
+                for (var i = 0; i < len(xs); i = i + 1) {
+                    if (xs[i] > n) { return xs[i]; }
+                }
+                return nil;
+            }
+            var found = first_over([1, 5, 10], 4);
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(global(&interpreter, "found").as_numeric(), Some(5.0));
+        Ok(())
+    }
+
+    #[test]
+    fn try_catch_finally() -> Result<()> {
+        let src = r#"
+            var log = [];
+            try {
+                push(log, "try");
+                throw "oops";
+                push(log, "unreachable");
+            } catch (e) {
+                push(log, e);
+            } finally {
+                push(log, "finally");
+            }
+
+            var caught;
+            try { nil - 1; } catch (e) { caught = e; }
+
+            fun cleanup() {
+                try { return 1; } finally { push(log, "cleanup"); }
+            }
+            var returned = cleanup();
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(
+            global(&interpreter, "log").to_string(),
+            "[\"try\", \"oops\", \"finally\", \"cleanup\"]"
+        );
+        assert_eq!(
+            global(&interpreter, "caught").to_string(),
+            "{\"line\": 14, \"message\": \"invalid operand type for '-': nil and number\", \"type\": \"type error\"}"
+        );
+        assert_eq!(global(&interpreter, "returned").as_numeric(), Some(1.0));
+
+        // without a catch, the error carries on after the finally
+        assert!(matches!(
+            run("try { throw 1; } finally {}"),
+            Err(RloxError::Throw { value, .. }) if value.is_numeric()
+        ));
+        assert!(matches!(
+            run("try { throw 1; } catch (e) { throw e + 1; }"),
+            Err(RloxError::Throw { value, .. }) if value.as_numeric() == Some(2.0)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn traceback() -> Result<()> {
+        let src = "fun inner() {\n  return nil + 1;\n}\nfun outer() {\n  inner();\n}\nouter();";
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new(src.to_string(), error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), error_reporter.clone()).parse()?;
+        let mut interpreter = Interpreter::new(error_reporter);
+        let mut resolver = Resolver::new(&interpreter);
+        resolver.resolve_stmts(&stmts)?;
+
+        assert!(interpreter.interpret(stmts).is_err());
+        let trace: Vec<String> = interpreter
+            .take_trace()
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        assert_eq!(
+            trace,
+            vec![
+                "at inner (line 2)",
+                "at outer (line 5)",
+                "at <script> (line 7)"
+            ]
+        );
+
+        // the next run starts afresh
+        interpreter.interpret(vec![])?;
+        assert!(interpreter.take_trace().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn stack_overflow() -> Result<()> {
+        // the test threads' stacks are small, so keep well inside them
+        let mut interpreter = Interpreter::new(ErrorReporter::default());
+        interpreter.set_max_call_depth(10);
+
+        let src = r#"
+            fun down(n) { if (n > 0) down(n - 1); }
+            down(9);
+            var caught;
+            try { down(10); } catch (e) { caught = e["type"]; }
+        "#;
+        let interpreter = run_in(interpreter, src)?;
+        assert_eq!(
+            global(&interpreter, "caught").as_string(),
+            Some(Rc::from("stack overflow"))
+        );
+        assert_eq!(interpreter.call_depth, 0);
+
+        let mut interpreter = Interpreter::new(ErrorReporter::default());
+        interpreter.set_max_call_depth(10);
+        assert!(matches!(
+            run_in(interpreter, "fun f() { f(); } f();"),
+            Err(RloxError::StackOverflow { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn stack_overflow_on_a_small_stack() -> Result<()> {
+        // with the default limits, a test thread's own 2MB stack is no
+        // obstacle to reaching the full call depth, in a debug build too
+        let src = r#"
+            var depth = 0;
+            fun down() { depth = depth + 1; return down() + 1; }
+            var caught;
+            try { down(); } catch (e) { caught = e["type"]; }
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(
+            global(&interpreter, "caught").as_string(),
+            Some(Rc::from("stack overflow"))
+        );
+        let depth = global(&interpreter, "depth").as_numeric();
+        assert_eq!(depth, Some(DEFAULT_MAX_CALL_DEPTH as f64));
+        assert_eq!(interpreter.call_depth, 0);
+
+        // running out of native stack first fails the call the same way
+        let mut interpreter = Interpreter::new(ErrorReporter::default());
+        interpreter.set_native_stack_limit(64 * 1024);
+        let interpreter = run_in(interpreter, src)?;
+        assert_eq!(
+            global(&interpreter, "caught").as_string(),
+            Some(Rc::from("stack overflow"))
+        );
+        let depth = global(&interpreter, "depth")
+            .as_numeric()
+            .unwrap_or_default();
+        assert!(depth >= 2.0 && depth < DEFAULT_MAX_CALL_DEPTH as f64);
+        Ok(())
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        assert!(matches!(run("break;"), Err(RloxError::ResolveError { .. })));
+        assert!(matches!(
+            run("while (true) { fun f() { continue; } }"),
+            Err(RloxError::ResolveError { .. })
+        ));
+    }
+
+    #[test]
+    fn lists() -> Result<()> {
+        let src = r#"
+            var xs = [1, 2, 3];
+            xs[0] = 10;
+            push(xs, 4);
+            var popped = pop(xs);
+            insert(xs, 1, "a");
+            var removed = remove(xs, 2);
+            var joined = slice(xs, 0, 2) + [nil];
+            var alias = xs;
+            push(alias, true);
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(
+            global(&interpreter, "xs").to_string(),
+            "[10, \"a\", 3, true]"
+        );
+        assert_eq!(global(&interpreter, "popped").as_numeric(), Some(4.0));
+        assert_eq!(global(&interpreter, "removed").as_numeric(), Some(2.0));
+        assert_eq!(
+            global(&interpreter, "joined").to_string(),
+            "[10, \"a\", nil]"
+        );
+
+        assert!(matches!(
+            run("var xs = [1]; xs[1];"),
+            Err(RloxError::IndexError { len: 1, .. })
+        ));
+        assert!(matches!(
+            run("var xs = [1]; xs[0.5] = 2;"),
+            Err(RloxError::IncorrectType { .. })
+        ));
+        assert!(matches!(run("1[0];"), Err(RloxError::TypeError { .. })));
+        assert!(matches!(
+            run("pop([]);"),
+            Err(RloxError::RuntimeError { .. })
+        ));
+
+        // the builtins' errors are placed at the call
+        let message = |src| run(src).err().map(|e| e.to_string());
+        assert_eq!(
+            message("var xs = [1];\nremove(xs, 3);").as_deref(),
+            Some("[line 2:13] index 3 out of bounds for list of length 1")
+        );
+        assert_eq!(
+            message("slice([1, 2], 0.5, 1);").as_deref(),
+            Some("[line 1:21] list index must be a whole number, got 0.5")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn maps() -> Result<()> {
+        let src = r#"
+            var m = {"a": 1, 2: "two"};
+            m["b"] = [true];
+            m[-0] = nil;
+            var found = has(m, 0);
+            var removed = remove(m, "a");
+            var ks = keys(m);
+            var size = len(m);
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(
+            global(&interpreter, "m").to_string(),
+            "{0: nil, 2: \"two\", \"b\": [true]}"
+        );
+        assert_eq!(global(&interpreter, "found"), RlValue::Boolean(true));
+        assert_eq!(global(&interpreter, "removed").as_numeric(), Some(1.0));
+        assert_eq!(global(&interpreter, "ks").to_string(), "[0, 2, \"b\"]");
+        assert_eq!(global(&interpreter, "size").as_numeric(), Some(3.0));
+
+        assert!(matches!(
+            run("({})[\"nope\"];"),
+            Err(RloxError::KeyError { .. })
+        ));
+        assert!(matches!(
+            run("var m = {}; m[[]] = 1;"),
+            Err(RloxError::UnhashableKey { .. })
+        ));
+        assert!(matches!(
+            run("var m = {0/0: 1};"),
+            Err(RloxError::UnhashableKey { .. })
+        ));
+        assert!(matches!(
+            run("has({}, {});"),
+            Err(RloxError::UnhashableKey { span: Some(_), .. })
+        ));
+        assert!(matches!(
+            run("remove({}, 1);"),
+            Err(RloxError::KeyError { span: Some(_), .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn operator_type_errors() {
+        match run("var a = 1;\nvar b = \"a\" - a;") {
+            Err(RloxError::TypeError {
+                operator,
+                operands,
+                span,
+            }) => {
+                assert_eq!(operator, "-");
+                assert_eq!(operands, "string and number");
+                assert_eq!((span.line, span.column), (2, 13));
+            }
+            other => panic!("expected a type error, got {:?}", other.err()),
+        }
+
+        assert!(matches!(run("1 < nil;"), Err(RloxError::TypeError { .. })));
+        assert!(matches!(run("-\"a\";"), Err(RloxError::TypeError { .. })));
+        assert!(matches!(
+            run("1 + \"a\";"),
+            Err(RloxError::TypeError { .. })
+        ));
+    }
+
+    #[test]
+    fn runtime_errors_are_located() {
+        let line_and_column = |src: &str| {
+            let span = run(src).err().and_then(|e| e.span());
+            span.map(|s| (s.line, s.column))
+        };
+        let at = |line, column| Some((line, column));
+
+        assert_eq!(line_and_column("print nope;"), at(1, 7));
+        assert_eq!(line_and_column("var a = 1;\na = nope = 2;"), at(2, 5));
+        assert_eq!(line_and_column("var a = 1;\na.b;"), at(2, 3));
+        // calls are placed at their closing paren, errors from inside
+        // builtin functions included
+        assert_eq!(line_and_column("fun f(a) { return a; }\nf();"), at(2, 3));
+        assert_eq!(line_and_column("len(1);"), at(1, 6));
+        assert_eq!(line_and_column("var a = 1;\n  a();"), at(2, 5));
+
+        let message = |src: &str| run(src).err().map(|e| e.to_string());
+        assert_eq!(
+            message("var a = 1;\na.b;").as_deref(),
+            Some("[line 2:3] only instances have properties, got a number")
+        );
+        assert_eq!(
+            message("var a = \"s\";\na.b = 1;").as_deref(),
+            Some("[line 2:3] only instances have fields, got a string")
+        );
+        assert_eq!(
+            message("var a = 1;\n  a();").as_deref(),
+            Some("[line 2:5] can only call functions and classes, got number")
+        );
+    }
+
+    #[test]
+    fn equality_operators() -> Result<()> {
+        let src = r#"
+            fun f() {}
+            fun g() {}
+            var h = f;
+            class A {}
+            var a = A();
+            var results = nil == nil and "a" == "a" and true != false
+                and nil != false and 1 != "1" and f == h and f != g
+                and a == a and a != A();
+        "#;
+        let interpreter = run(src)?;
+        assert_eq!(global(&interpreter, "results"), RlValue::Boolean(true));
         Ok(())
     }
 }
@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::{Result, RloxError};
+
+/// A handle for stopping a running script from outside of it, like from
+/// another thread or a Ctrl-C handler. Clones share the same flag.
+///
+/// Scripts check it at the back-edge of every loop and at every call, and
+/// stop with `RloxError::Interrupted` when it's been set. Whatever globals
+/// the script defined so far are kept.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupt {
+    flag: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    /// Ask the running script to stop.
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    /// Forget a request which came in while no script was running.
+    pub fn clear(&self) {
+        self.flag.store(false, Ordering::Relaxed);
+    }
+
+    /// Stop if we've been asked to. The request is used up, so the
+    /// next script runs normally.
+    pub(crate) fn check(&self) -> Result<()> {
+        // cheap enough to do all the time, the swap only happens when set
+        if self.is_interrupted() && self.flag.swap(false, Ordering::Relaxed) {
+            return Err(RloxError::Interrupted);
+        }
+        Ok(())
+    }
+}
@@ -1,24 +1,65 @@
+pub(crate) mod builtins;
 pub(crate) mod callable;
+pub(crate) mod class;
+pub(crate) mod compiler;
 pub(crate) mod environment;
 pub(crate) mod error;
 pub(crate) mod expr;
+pub(crate) mod gc;
 pub(crate) mod interpreter;
+pub(crate) mod interrupt;
+pub(crate) mod limits;
+pub(crate) mod map;
+pub(crate) mod module;
+#[cfg(feature = "nan-boxing")]
+pub(crate) mod nanbox;
 pub(crate) mod parser;
+pub(crate) mod repl;
 pub(crate) mod resolver;
 pub(crate) mod rlvalue;
 pub(crate) mod scanner;
 pub(crate) mod stmt;
+pub(crate) mod symbol;
 pub(crate) mod token;
+pub(crate) mod vm;
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::fs;
-use std::io::stdin;
+use std::path::Path;
+use std::rc::Rc;
 
-use crate::error::Result;
-use crate::interpreter::Interpreter;
+use crate::compiler::Compiler;
+use crate::error::TraceFrame;
 use crate::parser::Parser;
 use crate::resolver::Resolver;
 use crate::scanner::Scanner;
+use crate::vm::Vm;
+
+pub use crate::callable::NativeFunction;
+pub use crate::error::{Result, RloxError};
+pub use crate::expr::{Expr, ExprKind, LiteralValue};
+pub use crate::gc::GcStats;
+pub use crate::interpreter::Interpreter;
+pub use crate::interrupt::Interrupt;
+pub use crate::limits::{Limit, Limits};
+#[cfg(feature = "nan-boxing")]
+pub use crate::nanbox::NanBox;
+pub use crate::rlvalue::{RlValue, Unpacked};
+pub use crate::stmt::Stmt;
+pub use crate::token::{Span, Token};
+
+/// How deeply calls may nest, on either backend, before the script fails
+/// with a "stack overflow" error.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// How much native stack the tree-walking `Interpreter`, which recurses
+/// on it for every call, may use before calls fail with a "stack overflow"
+/// error too. Calls start out on the stack of whatever thread runs the
+/// script, and go on to segments of stack of their own once that runs low,
+/// so a script can't crash the host however small it's stack is. It's
+/// enough for `DEFAULT_MAX_CALL_DEPTH` calls in a debug build, which use a
+/// lot more stack per call.
+pub const DEFAULT_NATIVE_STACK_LIMIT: usize = 64 * 1024 * 1024;
 
 /// A centralized error reporting struct. Should be passed around to all
 /// the workers in this project.
@@ -26,7 +67,15 @@ use crate::scanner::Scanner;
 pub struct ErrorReporter {
     // TODO(jeb): Not sure if Cell is the best here, but it's
     // at least some form of interior mutability (yay!)
-    had_error: Cell<bool>,
+    // Shared via the Rc so every clone sees the same flag.
+    had_error: Rc<Cell<bool>>,
+    had_runtime_error: Rc<Cell<bool>>,
+
+    // the source currently being run, so errors can show the offending line.
+    source: Rc<RefCell<String>>,
+
+    // whether the resolver's warnings should stop the script like errors.
+    warnings_as_errors: Rc<Cell<bool>>,
 }
 
 impl ErrorReporter {
@@ -35,56 +84,363 @@ impl ErrorReporter {
     }
 
     pub fn report(&self, line: u32, place: &str, message: &str) {
-        println!("[line {:?}] Error {:?}: {:?}", line, place, message);
+        eprintln!("[line {}] Error{}: {}", line, place, message);
+        self.had_error.replace(true);
+    }
+
+    /// Report an error at `span`, underlining it in the source line.
+    pub fn report_span(&self, span: Span, place: &str, message: &str) {
+        eprintln!("[{}] Error{}: {}", span, place, message);
+        self.show_source(span);
+        self.had_error.replace(true);
+    }
+
+    /// Report something which is probably a mistake, but doesn't stop
+    /// the script from running.
+    pub fn warning(&self, span: Span, message: &str) {
+        eprintln!("[{}] Warning: {}", span, message);
+        self.show_source(span);
+    }
+
+    /// Make the resolver fail on it's first warning, instead of just
+    /// reporting them.
+    pub fn set_warnings_as_errors(&self, on: bool) {
+        self.warnings_as_errors.replace(on);
+    }
+
+    pub fn warnings_as_errors(&self) -> bool {
+        self.warnings_as_errors.get()
+    }
+
+    /// Report an error found before the script started running.
+    pub fn static_error(&self, error: &RloxError) {
+        self.show_error(error);
         self.had_error.replace(true);
     }
 
+    /// Report an error which stopped the script while it was running.
+    pub fn runtime_error(&self, error: &RloxError) {
+        self.show_error(error);
+        self.had_runtime_error.replace(true);
+    }
+
+    /// The source errors are currently shown against.
+    pub(crate) fn source(&self) -> String {
+        self.source.borrow().clone()
+    }
+
+    /// Show where a runtime error came from, innermost call first.
+    /// Runs of the same line, as from deep recursion, are collapsed.
+    pub(crate) fn traceback(&self, trace: &[TraceFrame]) {
+        for run in trace.chunk_by(|a, b| a == b) {
+            eprintln!("  {}", run[0]);
+            if run.len() > 1 {
+                eprintln!("  ... repeated {} more times", run.len() - 1);
+            }
+        }
+    }
+
+    pub fn set_source(&self, source: &str) {
+        *self.source.borrow_mut() = source.to_string();
+    }
+
     pub fn reset(&self) {
         self.had_error.replace(false);
+        self.had_runtime_error.replace(false);
     }
 
     pub fn had_error(&self) -> bool {
         self.had_error.get()
     }
+
+    pub fn had_runtime_error(&self) -> bool {
+        self.had_runtime_error.get()
+    }
+
+    fn show_error(&self, error: &RloxError) {
+        eprintln!("{}", error);
+        if let Some(span) = error.span() {
+            self.show_source(span);
+        }
+    }
+
+    fn show_source(&self, span: Span) {
+        if let Some(rendered) = render_span(&self.source.borrow(), span) {
+            eprint!("{}", rendered);
+        }
+    }
+}
+
+/// Render the source line `span` is on with a caret underline, e.g.
+///
+/// ```text
+///    3 | print a + "b";
+///      |         ^
+/// ```
+///
+/// Returns `None` when the span doesn't point into `source`.
+fn render_span(source: &str, span: Span) -> Option<String> {
+    if span.line == 0 || span.column == 0 {
+        return None;
+    }
+    let text = source.lines().nth(span.line as usize - 1)?;
+
+    // a span can cover multiple lines, only underline the first one.
+    let width = source
+        .get(span.offset..span.offset + span.len)
+        .map(|s| s.chars().take_while(|c| *c != '\n').count())
+        .unwrap_or(0)
+        .max(1);
+    // keep any tabs so the caret lines up with the text above it.
+    let padding: String = text
+        .chars()
+        .take(span.column as usize - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let gutter = span.line.to_string();
+    Some(format!(
+        "{:>4} | {}\n{:>4} | {}{}\n",
+        gutter,
+        text,
+        "",
+        padding,
+        "^".repeat(width)
+    ))
+}
+
+/// Which execution engine runs the parsed program.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Backend {
+    /// Walk the `Stmt`/`Expr` trees directly.
+    #[default]
+    TreeWalk,
+    /// Compile to bytecode and run it on the stack `Vm`.
+    Bytecode,
 }
 
 /// The main struct for doing all the things for this project.
 pub struct RLox {
     interpreter: Interpreter,
+    vm: Vm,
+    backend: Backend,
     error_reporter: ErrorReporter,
+    // shared by both backends, and kept across a `reset`.
+    interrupt: Interrupt,
 }
 
 impl RLox {
     pub fn new(error_reporter: ErrorReporter) -> Self {
-        RLox {
+        Self::with_backend(error_reporter, Backend::default())
+    }
+
+    pub fn with_backend(error_reporter: ErrorReporter, backend: Backend) -> Self {
+        let mut rlox = RLox {
             interpreter: Interpreter::new(error_reporter.clone()),
+            vm: Vm::new(error_reporter.clone()),
+            backend,
             error_reporter,
-        }
+            interrupt: Interrupt::default(),
+        };
+        rlox.interpreter.set_interrupt(rlox.interrupt.clone());
+        rlox.vm.set_interrupt(rlox.interrupt.clone());
+        rlox
+    }
+
+    /// A handle which stops whatever script is running when it's
+    /// interrupted, see `Interrupt`.
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.interrupt.clone()
     }
 
+    /// Run an interactive session, see `repl` for what it supports.
     pub fn run_prompt(&mut self) -> Result<()> {
-        println!("JEB::run_prompt HEAD");
-        loop {
-            println!("\nenter some program: ");
-            let mut input = String::new();
-            stdin().read_line(&mut input).expect("failed to read line");
-            let input = input.as_str().trim();
-
-            if input.is_empty() {
-                return Ok(());
-            }
+        repl::run(self)
+    }
 
-            if let Err(e) = self.run(input) {
-                println!("Erorr occurred: {:?}", e);
-                return Err(e);
-            }
+    /// Scan, parse, resolve and execute a chunk of source code.
+    pub fn run(&mut self, input: &str) -> Result<()> {
+        let stmts = self.parse(input)?;
+        self.execute(stmts)
+    }
+
+    /// Scan and parse a chunk of source code without running it, carrying
+    /// on past syntax errors. Returns every statement which parsed cleanly,
+    /// along with all the errors, for tools which want as much of the
+    /// syntax tree as they can get.
+    pub fn parse_recovering(&self, input: &str) -> (Vec<Stmt>, Vec<RloxError>) {
+        let mut scanner = Scanner::new(input.to_string(), self.error_reporter.clone());
+        if let Err(e) = scanner.scan_tokens() {
+            return (Vec::new(), vec![e.into()]);
+        }
+        let tokens = scanner.tokens();
+
+        let mut parser = Parser::new(tokens, self.error_reporter.clone());
+        parser.parse_recovering()
+    }
+
+    /// Like `run`, but prints the value of every top level expression
+    /// statement, and lets the last statement go without a ';'.
+    pub(crate) fn run_repl(&mut self, input: &str) -> Result<()> {
+        let stmts = self
+            .parse(&with_semicolon(input))?
+            .into_iter()
+            .map(|stmt| match stmt {
+                Stmt::Expression(e) => Stmt::Print(e),
+                stmt => stmt,
+            })
+            .collect();
+        self.execute(stmts)
+    }
+
+    /// Parse a single expression, and print it's syntax tree.
+    pub(crate) fn ast(&mut self, input: &str) -> Result<String> {
+        let mut stmts = self.parse(&with_semicolon(input))?;
+        match (stmts.pop(), stmts.is_empty()) {
+            (Some(Stmt::Expression(e)), true) => Ok(Expr::sorta_pretty_print(&e)),
+            _ => Err(RloxError::ParseError(String::from(
+                "expected a single expression",
+            ))),
+        }
+    }
+
+    /// All the global variables of the current backend, sorted by name.
+    pub(crate) fn globals(&self) -> Vec<(String, String)> {
+        match self.backend {
+            Backend::TreeWalk => self
+                .interpreter
+                .globals()
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect(),
+            Backend::Bytecode => self
+                .vm
+                .globals()
+                .into_iter()
+                .map(|(name, value)| (name, value.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Throw away all global state. Note this drops any functions the
+    /// host registered with `register_fn`, too.
+    pub(crate) fn reset(&mut self) {
+        self.interpreter = Interpreter::new(self.error_reporter.clone());
+        self.interpreter.set_interrupt(self.interrupt.clone());
+        self.vm = Vm::new(self.error_reporter.clone());
+        self.vm.set_interrupt(self.interrupt.clone());
+        self.error_reporter.reset();
+    }
+
+    pub(crate) fn error_reporter(&self) -> &ErrorReporter {
+        &self.error_reporter
+    }
+
+    pub fn run_file(&mut self, filename: &str) -> Result<()> {
+        let s = fs::read_to_string(filename)?;
+        // so imports are found next to the script
+        self.interpreter.enter_file(Path::new(filename))?;
+        self.vm.enter_file(Path::new(filename))?;
+        let res = self.run(s.as_str());
+        self.interpreter.leave_file();
+        self.vm.leave_file();
+        res
+    }
+
+    // The embedding functions below all work against the tree-walking
+    // `Interpreter`, the bytecode `Vm` keeps it's own globals. So those
+    // which touch globals fail for the bytecode backend, rather than
+    // quietly act on an interpreter which isn't running the scripts.
+
+    /// Register a Rust closure as a global function for scripts to call.
+    pub fn register_fn<F>(&mut self, name: &str, arity: usize, function: F) -> Result<()>
+    where
+        F: Fn(&mut Interpreter, &[RlValue]) -> Result<RlValue> + 'static,
+    {
+        self.tree_walk_only("host functions")?;
+        self.interpreter.register_fn(name, arity, function);
+        Ok(())
+    }
+
+    pub fn define_global(&mut self, name: &str, value: RlValue) -> Result<()> {
+        self.tree_walk_only("host globals")?;
+        self.interpreter.define_global(name, value);
+        Ok(())
+    }
+
+    /// Bound what scripts may do, see `Limits`. Only the tree-walking
+    /// backend enforces them, so this fails for the bytecode one rather
+    /// than leave scripts unbounded.
+    pub fn set_limits(&mut self, limits: Limits) -> Result<()> {
+        self.tree_walk_only("limits")?;
+        self.interpreter.set_limits(limits);
+        Ok(())
+    }
+
+    /// Limit how deeply calls may nest, on both backends. Raising it a lot
+    /// needs a bigger native stack limit for the tree-walker too.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.interpreter.set_max_call_depth(depth);
+        self.vm.set_max_call_depth(depth);
+    }
 
-            // reset the has_error on each run...
-            self.error_reporter.reset();
+    /// Limit how much of the native stack the tree-walker may use for
+    /// calls, see `DEFAULT_NATIVE_STACK_LIMIT`. It's not limited by the
+    /// host thread's stack, which scripts only start out on. The
+    /// bytecode backend keeps it's call frames on the heap, so this doesn't
+    /// apply to it.
+    pub fn set_native_stack_limit(&mut self, bytes: usize) {
+        self.interpreter.set_native_stack_limit(bytes);
+    }
+
+    /// What the garbage collector has been up to. It's shared by both
+    /// backends, and every `RLox` on the same thread.
+    pub fn gc_stats(&self) -> GcStats {
+        gc::stats()
+    }
+
+    /// Free any reference cycles nothing uses any more, without waiting
+    /// for enough allocations to trigger it. Returns how many objects
+    /// were freed.
+    pub fn collect_garbage(&mut self) -> usize {
+        gc::collect()
+    }
+
+    /// Collect garbage on every allocation. Very slow, but good for
+    /// flushing out anything freed too early.
+    pub fn set_gc_stress(&mut self, on: bool) {
+        gc::set_stress(on);
+    }
+
+    /// Read a global variable, `None` if it isn't defined.
+    pub fn get_global(&self, name: &str) -> Result<Option<RlValue>> {
+        self.tree_walk_only("host globals")?;
+        Ok(self.interpreter.get_global(name))
+    }
+
+    /// Call a function defined by a previously run script.
+    pub fn call_function(&mut self, name: &str, args: &[RlValue]) -> Result<RlValue> {
+        self.tree_walk_only("calls from the host")?;
+        self.interpreter.call_function(name, args)
+    }
+
+    ///////////////////
+    // helper functions
+    ///////////////////
+
+    /// Fail if this is running the bytecode backend, which doesn't
+    /// support `what` yet.
+    fn tree_walk_only(&self, what: &str) -> Result<()> {
+        match self.backend {
+            Backend::TreeWalk => Ok(()),
+            Backend::Bytecode => Err(RloxError::Unsupported(format!(
+                "{} aren't supported by the bytecode backend yet",
+                what
+            ))),
         }
     }
 
-    fn run(&mut self, input: &str) -> Result<()> {
+    fn parse(&mut self, input: &str) -> Result<Vec<Stmt>> {
         // 1. scan
         let mut scanner = Scanner::new(input.to_string(), self.error_reporter.clone());
         scanner.scan_tokens()?;
@@ -92,19 +448,451 @@ impl RLox {
 
         // 2. parse
         let mut parser = Parser::new(tokens, self.error_reporter.clone());
-        let stmts = parser.parse()?;
+        parser.parse()
+    }
 
+    fn execute(&mut self, stmts: Vec<Stmt>) -> Result<()> {
+        // 3. resolve. the bytecode compiler does it's own resolution,
+        // but this still catches the static errors for both backends.
         let mut resolver = Resolver::new(&self.interpreter);
-        for stmt in &stmts {
-            resolver.resolve(stmt)?;
+        if let Err(e) = resolver.resolve_stmts(&stmts) {
+            self.error_reporter.static_error(&e);
+            return Err(e);
         }
 
-        self.interpreter.interpret(stmts)?;
+        // 4. execute
+        let res = match self.backend {
+            Backend::TreeWalk => self.interpreter.interpret(stmts),
+            Backend::Bytecode => match Compiler::compile(&stmts) {
+                Ok(function) => self.vm.interpret(function),
+                Err(e) => {
+                    self.error_reporter.static_error(&e);
+                    return Err(e);
+                }
+            },
+        };
+        if let Err(e) = &res {
+            self.error_reporter.runtime_error(e);
+            let trace = match self.backend {
+                Backend::TreeWalk => self.interpreter.take_trace(),
+                Backend::Bytecode => self.vm.take_trace(),
+            };
+            self.error_reporter.traceback(&trace);
+        }
+        res
+    }
+}
+
+/// The REPL lets the last statement go without a ';', so add it back
+/// for the parser.
+fn with_semicolon(input: &str) -> String {
+    let trimmed = input.trim_end();
+    match trimmed.ends_with(';') || trimmed.ends_with('}') {
+        true => trimmed.to_string(),
+        false => format!("{};", trimmed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::symbol::Symbol;
+    use crate::token::Lexeme;
+
+    #[test]
+    fn host_functions_and_globals() -> Result<()> {
+        let mut rlox = RLox::new(ErrorReporter::default());
+        rlox.register_fn("add", 2, |_interpreter, args| {
+            match (args[0].as_numeric(), args[1].as_numeric()) {
+                (Some(a), Some(b)) => Ok(RlValue::Double(a + b)),
+                _ => Err(RloxError::IncorrectType {
+                    message: String::from("add takes numbers"),
+                    span: None,
+                }),
+            }
+        })?;
+        rlox.define_global("greeting", RlValue::String(Rc::from("hello")))?;
+
+        rlox.run("var sum = add(40, 2); var msg = greeting + \" world\";")?;
+        assert_eq!(
+            rlox.get_global("sum")?.and_then(|v| v.as_numeric()),
+            Some(42.0)
+        );
+        assert_eq!(
+            rlox.get_global("msg")?.and_then(|v| v.as_string()),
+            Some(Rc::from("hello world"))
+        );
+        assert!(rlox.get_global("nope")?.is_none());
         Ok(())
     }
 
-    pub fn run_file(&mut self, filename: &str) -> Result<()> {
-        let s = fs::read_to_string(filename)?;
-        self.run(s.as_str())
+    #[test]
+    fn runaway_recursion() -> Result<()> {
+        // from the test thread's own small stack, on either backend the
+        // documented depth is reachable, and going past it fails the script
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let mut rlox = RLox::with_backend(ErrorReporter::default(), backend);
+            assert!(matches!(
+                rlox.run("fun f() { f(); } f();"),
+                Err(RloxError::StackOverflow { .. })
+            ));
+
+            let depth = DEFAULT_MAX_CALL_DEPTH;
+            let src = "fun g(n) { if (n > 1) return g(n - 1) + 1; return 1; }";
+            rlox.run(&format!("{} var depth = g({});", src, depth))?;
+            let reached = rlox.globals().into_iter().find(|(name, _)| name == "depth");
+            assert_eq!(reached.map(|(_, v)| v), Some(depth.to_string()));
+            assert!(matches!(
+                rlox.run(&format!("g({});", depth + 1)),
+                Err(RloxError::StackOverflow { .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_carry_spans() -> Result<()> {
+        let mut scanner = Scanner::new(
+            String::from("var a = 1;\n  print \"hi\";"),
+            ErrorReporter::default(),
+        );
+        scanner.scan_tokens()?;
+        let spans: Vec<(String, Span)> = scanner
+            .tokens()
+            .iter()
+            .map(|t| (t.lexeme.to_string(), t.span))
+            .collect();
+
+        assert_eq!(spans[1], (String::from("a"), Span::new(4, 1, 1, 5)));
+        assert_eq!(spans[5], (String::from("print"), Span::new(13, 5, 2, 3)));
+        assert_eq!(spans[6], (String::from("\"hi\""), Span::new(19, 4, 2, 9)));
+
+        // only names are interned, literals keep their own text
+        let tokens = scanner.tokens();
+        assert!(matches!(tokens[1].lexeme, Lexeme::Name(_)));
+        assert!(matches!(tokens[3].lexeme, Lexeme::Literal(_)));
+        assert_eq!(Symbol::lookup("\"hi\""), None);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_past_syntax_errors() {
+        let rlox = RLox::new(ErrorReporter::default());
+        let (stmts, errors) = rlox.parse_recovering("var a = 1;\nprint (1;\nprint a;");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("[line 2:"));
+
+        // both good statements are still there, for tooling to look at
+        assert_eq!(stmts.len(), 2);
+        match &stmts[..] {
+            [Stmt::Var { name, .. }, Stmt::Print(expr)] => {
+                assert_eq!(name.lexeme(), "a");
+                assert_eq!(name.span(), Span::new(4, 1, 1, 5));
+                assert!(matches!(expr.kind(), ExprKind::Variable(t) if t.lexeme() == "a"));
+                assert_eq!(expr.span().line, 3);
+            }
+            stmts => panic!("unexpected statements {:?}", stmts),
+        }
+    }
+
+    #[test]
+    fn render_span_underlines_source() {
+        let source = "var a = 1;\nprint a + \"b\";\n";
+        let rendered = render_span(source, Span::new(19, 1, 2, 9));
+        assert_eq!(
+            rendered.as_deref(),
+            Some("   2 | print a + \"b\";\n     |         ^\n")
+        );
+
+        // a synthesized span has no column to point at
+        assert_eq!(render_span(source, Span::from_line(2)), None);
+    }
+
+    #[test]
+    fn imports() -> Result<()> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/scripts/modules/");
+        let mut rlox = RLox::new(ErrorReporter::default());
+        rlox.run_file(&format!("{}main.rlox", dir))?;
+        assert_eq!(
+            rlox.get_global("geometry")?.map(|v| v.to_string()),
+            Some(String::from("<module geometry>"))
+        );
+
+        // a module's globals don't leak into the importer, or back
+        rlox.run("var inner = geometry.scale;")?;
+        assert_eq!(
+            rlox.get_global("inner")?.and_then(|v| v.as_numeric()),
+            Some(10.0)
+        );
+        assert!(rlox.get_global("unit")?.is_none());
+        assert!(matches!(
+            rlox.run("geometry.nope;"),
+            Err(RloxError::UndefinedSymbol { .. })
+        ));
+
+        assert!(matches!(
+            rlox.run_file(&format!("{}cycle_a.rlox", dir)),
+            Err(RloxError::ImportError { .. })
+        ));
+        assert!(matches!(
+            rlox.run("import \"missing.rlox\" as m;"),
+            Err(RloxError::ImportError { .. })
+        ));
+
+        // the same again on the vm, where the host can only see globals
+        // as text
+        let mut rlox = RLox::with_backend(ErrorReporter::default(), Backend::Bytecode);
+        rlox.run_file(&format!("{}main.rlox", dir))?;
+        rlox.run("var inner = geometry.scale; var same = geometry == again;")?;
+        let global = |rlox: &RLox, name: &str| {
+            let globals = rlox.globals();
+            globals.into_iter().find(|g| g.0 == name).map(|g| g.1)
+        };
+        assert_eq!(
+            global(&rlox, "geometry").as_deref(),
+            Some("<module geometry>")
+        );
+        assert_eq!(global(&rlox, "inner").as_deref(), Some("10"));
+        assert_eq!(global(&rlox, "same").as_deref(), Some("true"));
+        assert_eq!(global(&rlox, "unit"), None);
+        assert!(matches!(
+            rlox.run("geometry.nope;"),
+            Err(RloxError::UndefinedSymbol { .. })
+        ));
+
+        assert!(matches!(
+            rlox.run_file(&format!("{}cycle_a.rlox", dir)),
+            Err(RloxError::ImportError { .. })
+        ));
+        assert!(matches!(
+            rlox.run("try { import \"broken.rlox\" as b; } catch (e) { throw e[\"type\"]; }"),
+            Err(RloxError::Throw { value, .. })
+                if value.as_string().as_deref() == Some("import error")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn limits() -> Result<()> {
+        let limited = |limits: Limits, src: &str| {
+            let mut rlox = RLox::new(ErrorReporter::default());
+            rlox.set_limits(limits)?;
+            rlox.run(src)
+        };
+        let exceeded = |res: Result<()>| match res {
+            Err(RloxError::LimitExceeded(limit)) => Some(limit),
+            _ => None,
+        };
+
+        let steps = Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        };
+        assert_eq!(
+            exceeded(limited(steps.clone(), "while (true) {}")),
+            Some(Limit::Steps)
+        );
+        // scripts can't get around a limit by catching it
+        assert_eq!(
+            exceeded(limited(steps, "try { while (true) {} } catch (e) {}")),
+            Some(Limit::Steps)
+        );
+
+        let timeout = Limits {
+            timeout: Some(std::time::Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        assert_eq!(
+            exceeded(limited(timeout, "while (true) {}")),
+            Some(Limit::Timeout)
+        );
+
+        let allocation = Limits {
+            max_allocation: Some(1024),
+            ..Limits::default()
+        };
+        let src = "var s = \"x\"; while (true) { s = s + s; }";
+        assert_eq!(
+            exceeded(limited(allocation.clone(), src)),
+            Some(Limit::Allocation)
+        );
+        let src = "var xs = []; while (true) { push(xs, nil); }";
+        assert_eq!(exceeded(limited(allocation, src)), Some(Limit::Allocation));
+
+        let sandboxed = Limits {
+            allow_print: false,
+            allow_builtins: false,
+            ..Limits::default()
+        };
+        assert_eq!(
+            exceeded(limited(sandboxed.clone(), "print 1;")),
+            Some(Limit::Print)
+        );
+        assert_eq!(
+            exceeded(limited(sandboxed.clone(), "clock();")),
+            Some(Limit::Builtins)
+        );
+
+        // each run gets a fresh budget, and host functions are still fine
+        let mut rlox = RLox::new(ErrorReporter::default());
+        rlox.register_fn("answer", 0, |_interpreter, _args| Ok(RlValue::Double(42.0)))?;
+        rlox.set_limits(Limits {
+            max_steps: Some(5),
+            ..sandboxed
+        })?;
+        assert!(rlox
+            .run("var a = 1; var b = 2; var c = 3; var d = 4; var e = 5; var f = 6;")
+            .is_err());
+        rlox.run("var a = answer();")?;
+        assert_eq!(
+            rlox.get_global("a")?.and_then(|v| v.as_numeric()),
+            Some(42.0)
+        );
+
+        // nor does calling back into the script from a host function
+        // start the budget over
+        let mut rlox = RLox::new(ErrorReporter::default());
+        rlox.register_fn("host", 0, |interpreter, _args| {
+            interpreter.call_function("cb", &[])
+        })?;
+        rlox.set_limits(Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        })?;
+        let src = "fun cb() { return 1; } var n = 0; while (n < 100000) { n = n + host(); }";
+        assert_eq!(exceeded(rlox.run(src)), Some(Limit::Steps));
+
+        let mut rlox = RLox::with_backend(ErrorReporter::default(), Backend::Bytecode);
+        assert!(rlox.set_limits(Limits::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn interrupt() -> Result<()> {
+        let mut rlox = RLox::new(ErrorReporter::default());
+        let interrupt = rlox.interrupt_handle();
+        rlox.register_fn("stop", 0, move |_interpreter, _args| {
+            interrupt.interrupt();
+            Ok(RlValue::Nil)
+        })?;
+
+        let src =
+            "var n = 0; try { while (true) { n = n + 1; if (n == 10) stop(); } } catch (e) {}";
+        assert!(matches!(rlox.run(src), Err(RloxError::Interrupted)));
+        assert!(!rlox.interrupt_handle().is_interrupted());
+
+        // the session carries on from where it was stopped
+        rlox.run("n = n + 1;")?;
+        assert_eq!(
+            rlox.get_global("n")?.and_then(|v| v.as_numeric()),
+            Some(11.0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn identical_expressions_resolve_separately() -> Result<()> {
+        let mut rlox = RLox::new(ErrorReporter::default());
+        rlox.run("var a = \"global\";")?;
+        // both `a`s in the bodies are at the same place in their source,
+        // but only the first is a local
+        rlox.run("fun f(a)  { return a; }")?;
+        rlox.run("fun g(_b) { return a; }")?;
+        assert_eq!(
+            rlox.call_function("g", &[RlValue::Double(1.0)])?
+                .as_string(),
+            Some(Rc::from("global"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn garbage_collection() -> Result<()> {
+        // closures, methods bound into fields and self-referencing
+        // lists all make cycles, which have to survive while in use
+        let src = r#"
+            class Node {
+                init(n) { this.n = n; this.get = this.value; }
+                value() { return this.n; }
+            }
+            var total = 0;
+            for (var i = 0; i < 50; i = i + 1) {
+                fun add(x) { return x + i; }
+                var node = Node(add(1));
+                var l = [node];
+                push(l, l);
+                total = total + l[1][0].get();
+            }
+        "#;
+
+        // both backends share the one collector
+        for backend in [Backend::TreeWalk, Backend::Bytecode] {
+            let mut rlox = RLox::with_backend(ErrorReporter::default(), backend);
+            let start = rlox.gc_stats();
+            rlox.set_gc_stress(true);
+            rlox.run(src)?;
+            rlox.set_gc_stress(false);
+            let total = rlox.globals().into_iter().find(|(name, _)| name == "total");
+            assert_eq!(total.map(|(_, v)| v), Some(String::from("1275")));
+
+            // every iteration left it's cycles behind, and they've all gone
+            let before = rlox.gc_stats();
+            rlox.collect_garbage();
+            let after = rlox.gc_stats();
+            let freed = after.freed - start.freed;
+            assert!(freed >= 50 * 3, "{:?} freed {}", backend, freed);
+            assert!(after.collections > before.collections);
+            assert!(after.objects < 10, "{:?} left {}", backend, after.objects);
+            assert!(after.heap_bytes > 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn call_script_function_from_host() -> Result<()> {
+        let mut rlox = RLox::new(ErrorReporter::default());
+        rlox.run("fun double(x) { return x * 2; }")?;
+
+        let res = rlox.call_function("double", &[RlValue::Double(21.0)])?;
+        assert_eq!(res.as_numeric(), Some(42.0));
+
+        assert!(matches!(
+            rlox.call_function("double", &[]),
+            Err(RloxError::ArityError {
+                expected: 1,
+                got: 0,
+                ..
+            })
+        ));
+        assert!(matches!(
+            rlox.call_function("missing", &[]),
+            Err(RloxError::UndefinedSymbol { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn embedding_with_the_bytecode_backend() -> Result<()> {
+        // the vm's globals aren't reachable from the host yet, so rather
+        // than act on the idle tree-walker, these all fail
+        let mut rlox = RLox::with_backend(ErrorReporter::default(), Backend::Bytecode);
+        let unsupported = |res: Result<()>| matches!(res, Err(RloxError::Unsupported(_)));
+        assert!(unsupported(rlox.register_fn(
+            "answer",
+            0,
+            |_interpreter, _args| { Ok(RlValue::Double(42.0)) }
+        )));
+        assert!(unsupported(rlox.define_global("x", RlValue::Nil)));
+
+        rlox.run("var x = 5; fun f() { return x; }")?;
+        assert!(matches!(
+            rlox.get_global("x"),
+            Err(RloxError::Unsupported(_))
+        ));
+        assert!(matches!(
+            rlox.call_function("f", &[]),
+            Err(RloxError::Unsupported(_))
+        ));
+        Ok(())
     }
 }
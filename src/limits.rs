@@ -0,0 +1,137 @@
+use std::fmt;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use crate::error::{Result, RloxError};
+use crate::map::MapKey;
+use crate::rlvalue::RlValue;
+
+/// Bounds on what a script may do, for running ones you don't trust.
+/// Each run of a script (`RLox::run`, or a host `call_function`) gets a
+/// fresh budget. Going over any of them stops the script with a
+/// `RloxError::LimitExceeded`, which the script itself can't catch.
+///
+/// Start from `Limits::default()`, which has none, and set the ones you
+/// want, like `Limits { max_steps: Some(100_000), ..Limits::default() }`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// How many statements may be executed.
+    pub max_steps: Option<u64>,
+    /// How long a run may take, by the wall clock.
+    pub timeout: Option<Duration>,
+    /// Roughly how many bytes the script may allocate for strings, lists
+    /// and maps, in total. Memory freed along the way isn't given back.
+    pub max_allocation: Option<usize>,
+    /// Whether `print` statements may run.
+    pub allow_print: bool,
+    /// Whether the builtin functions, like `clock()` and `len()`, may be
+    /// called. Functions the host registers are always allowed.
+    pub allow_builtins: bool,
+}
+
+/// No limits at all, the way scripts run unless the host says otherwise.
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_steps: None,
+            timeout: None,
+            max_allocation: None,
+            allow_print: true,
+            allow_builtins: true,
+        }
+    }
+}
+
+/// Which of the `Limits` a script ran into.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    Steps,
+    Timeout,
+    Allocation,
+    Print,
+    Builtins,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Steps => write!(f, "ran out of steps"),
+            Limit::Timeout => write!(f, "ran out of time"),
+            Limit::Allocation => write!(f, "allocated too much memory"),
+            Limit::Print => write!(f, "print is disabled"),
+            Limit::Builtins => write!(f, "builtin functions are disabled"),
+        }
+    }
+}
+
+// looking at the clock on every step would be slow, and it's
+// fine to run a little over the deadline.
+const STEPS_PER_CLOCK_CHECK: u64 = 256;
+
+/// Tracks a single run of a script against it's `Limits`.
+#[derive(Debug, Default)]
+pub(crate) struct Budget {
+    pub(crate) limits: Limits,
+    steps: u64,
+    allocated: usize,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    /// Start over with the full budget, for a new run.
+    pub(crate) fn start(&mut self) {
+        self.steps = 0;
+        self.allocated = 0;
+        self.deadline = self.limits.timeout.map(|t| Instant::now() + t);
+    }
+
+    /// Account for executing one statement.
+    pub(crate) fn step(&mut self) -> Result<()> {
+        self.steps += 1;
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                return Err(RloxError::LimitExceeded(Limit::Steps));
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) && Instant::now() > deadline {
+                return Err(RloxError::LimitExceeded(Limit::Timeout));
+            }
+        }
+        Ok(())
+    }
+
+    /// Account for allocating `bytes` more memory.
+    pub(crate) fn allocate(&mut self, bytes: usize) -> Result<()> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        match self.limits.max_allocation {
+            Some(max) if self.allocated > max => Err(RloxError::LimitExceeded(Limit::Allocation)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Account for `n` more list elements.
+    pub(crate) fn allocate_list(&mut self, n: usize) -> Result<()> {
+        self.allocate(n.saturating_mul(mem::size_of::<RlValue>()))
+    }
+
+    /// Account for `n` more map entries.
+    pub(crate) fn allocate_map(&mut self, n: usize) -> Result<()> {
+        let entry = mem::size_of::<MapKey>() + mem::size_of::<RlValue>();
+        self.allocate(n.saturating_mul(entry))
+    }
+
+    pub(crate) fn print(&self) -> Result<()> {
+        match self.limits.allow_print {
+            true => Ok(()),
+            false => Err(RloxError::LimitExceeded(Limit::Print)),
+        }
+    }
+
+    pub(crate) fn builtins(&self) -> Result<()> {
+        match self.limits.allow_builtins {
+            true => Ok(()),
+            false => Err(RloxError::LimitExceeded(Limit::Builtins)),
+        }
+    }
+}
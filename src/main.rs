@@ -1,30 +1,44 @@
 use std::env;
 use std::process;
 
-pub(crate) mod callable;
-pub(crate) mod environment;
-pub(crate) mod error;
-pub(crate) mod expr;
-pub(crate) mod interpreter;
-pub(crate) mod rlvalue;
-pub(crate) mod stmt;
-pub(crate) mod token;
-
-use error::Result;
-use rlox::{ErrorReporter, RLox};
-
-fn main() -> Result<()> {
+use rlox::{Backend, ErrorReporter, RLox, RloxError};
+
+fn main() {
     let env_args: Vec<String> = env::args().collect();
 
+    // `--vm` selects the bytecode backend, `--warnings-as-errors` stops
+    // scripts the resolver warns about, and `--gc-stress` collects garbage
+    // on every allocation. they can go anywhere in the args.
+    let flag = |name: &str| env_args.iter().any(|a| a == name);
+    let backend = match flag("--vm") {
+        true => Backend::Bytecode,
+        false => Backend::TreeWalk,
+    };
+    let warnings_as_errors = flag("--warnings-as-errors");
+    let gc_stress = flag("--gc-stress");
+    let env_args: Vec<String> = env_args
+        .into_iter()
+        .filter(|a| !matches!(a.as_str(), "--vm" | "--warnings-as-errors" | "--gc-stress"))
+        .collect();
+
     let error_reporter = ErrorReporter::default();
-    let mut rlox = RLox::new(error_reporter.clone());
+    error_reporter.set_warnings_as_errors(warnings_as_errors);
+    let mut rlox = RLox::with_backend(error_reporter.clone(), backend);
+    rlox.set_gc_stress(gc_stress);
+
+    // ctrl-c stops the running script rather than the whole process. at
+    // the repl's prompt, the line editor deals with it itself.
+    let interrupt = rlox.interrupt_handle();
+    if let Err(e) = ctrlc::set_handler(move || interrupt.interrupt()) {
+        eprintln!("can't handle ctrl-c: {}", e);
+    }
 
-    match env_args.len() {
+    let res = match env_args.len() {
         // ignore the first arg (it's the standard unix name of the process)
-        1 => rlox.run_prompt().unwrap(),
-        2 => rlox.run_file(&env_args[1]).unwrap(),
+        1 => rlox.run_prompt(),
+        2 => rlox.run_file(&env_args[1]),
         _ => {
-            println!("Usage: rlox [script]");
+            println!("Usage: rlox [--vm] [--warnings-as-errors] [--gc-stress] [script]");
             process::exit(64);
         }
     };
@@ -32,10 +46,22 @@ fn main() -> Result<()> {
     // why the '?' operator requires type magic, I'm not sure,
     // just doing this shitty alternative ... :(
 
+    // the conventional exit code for being killed by SIGINT
+    if let Err(RloxError::Interrupted) = res {
+        process::exit(130);
+    }
+
     // Not sure if this is cool with the clone, but :shrug: for now
     if error_reporter.had_error() {
         process::exit(65);
     }
+    if error_reporter.had_runtime_error() {
+        process::exit(70);
+    }
 
-    Ok(())
+    // anything left over wasn't from the script itself, like a missing file.
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        process::exit(74);
+    }
 }
@@ -0,0 +1,108 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+/// A value which can be used as a key in a map. Only the immutable,
+/// compared-by-value types are hashable: lists, maps, functions and
+/// instances are rejected, as are NaNs, which are never equal to themselves.
+///
+/// Both backends convert their own values into these, so maps behave
+/// the same in either.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum MapKey {
+    Nil,
+    Boolean(bool),
+    // the bits of the f64, with -0.0 folded into 0.0 so they hash the same
+    Number(u64),
+    String(Rc<str>),
+}
+
+impl MapKey {
+    /// `None` for a NaN, as it couldn't be looked up again.
+    pub(crate) fn number(n: f64) -> Option<MapKey> {
+        if n.is_nan() {
+            None
+        } else if n == 0.0 {
+            // matches -0.0 too
+            Some(MapKey::Number(0.0_f64.to_bits()))
+        } else {
+            Some(MapKey::Number(n.to_bits()))
+        }
+    }
+
+    pub(crate) fn as_number(&self) -> Option<f64> {
+        match self {
+            MapKey::Number(bits) => Some(f64::from_bits(*bits)),
+            _ => None,
+        }
+    }
+
+    /// Keys are iterated (and printed) in this order: nil, then
+    /// booleans, numbers and strings, each sorted within their type.
+    fn rank(&self) -> u8 {
+        match self {
+            MapKey::Nil => 0,
+            MapKey::Boolean(_) => 1,
+            MapKey::Number(_) => 2,
+            MapKey::String(_) => 3,
+        }
+    }
+}
+
+impl Ord for MapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MapKey::Boolean(a), MapKey::Boolean(b)) => a.cmp(b),
+            (MapKey::Number(_), MapKey::Number(_)) => {
+                let (a, b) = (self.as_number(), other.as_number());
+                a.unwrap_or_default().total_cmp(&b.unwrap_or_default())
+            }
+            (MapKey::String(a), MapKey::String(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Keys print the way they'd be written in a map literal.
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapKey::Nil => write!(f, "nil"),
+            MapKey::Boolean(b) => write!(f, "{}", b),
+            MapKey::Number(_) => write!(f, "{}", self.as_number().unwrap_or_default()),
+            MapKey::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn number_keys() {
+        assert_eq!(MapKey::number(0.0), MapKey::number(-0.0));
+        assert_ne!(MapKey::number(1.0), MapKey::number(1.5));
+        assert_eq!(MapKey::number(f64::NAN), None);
+    }
+
+    #[test]
+    fn key_order() {
+        let mut keys = [
+            MapKey::String(Rc::from("a")),
+            MapKey::number(2.0).unwrap(),
+            MapKey::Nil,
+            MapKey::number(-1.0).unwrap(),
+            MapKey::Boolean(true),
+        ];
+        keys.sort();
+        let printed: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        assert_eq!(printed, vec!["nil", "true", "-1", "2", "\"a\""]);
+    }
+}
@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::environment::Environment;
+use crate::error::{Result, RloxError};
+use crate::rlvalue::RlValue;
+use crate::token::Token;
+
+/// A file brought in with `import "path" as name;`. It runs once, in it's
+/// own top-level `Environment`, and the globals it leaves behind are
+/// what `name.thing` reads.
+#[derive(Debug)]
+pub struct Module {
+    // the file name, without the directory or extension
+    pub(crate) name: String,
+    globals: Rc<RefCell<Environment>>,
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
+
+impl Module {
+    pub fn new(name: String, globals: Rc<RefCell<Environment>>) -> Self {
+        Self { name, globals }
+    }
+
+    /// Read one of the module's globals. Unlike a plain variable lookup,
+    /// this doesn't fall back to the builtin functions.
+    pub fn get(&self, name: &Token) -> Result<RlValue> {
+        match self.globals.borrow().get_local(name) {
+            Ok(value) => Ok(value.unwrap_or_default()),
+            Err(_) => Err(RloxError::UndefinedSymbol {
+                message: format!("module '{}' has no '{}'", self.name, name.lexeme),
+                span: Some(name.span),
+            }),
+        }
+    }
+}
+
+/// The files a backend is running, and the modules (of it's own kind, `M`)
+/// it has imported, so each file only runs once.
+#[derive(Debug)]
+pub(crate) struct Modules<M> {
+    // the files being run right now, innermost last. imports are found
+    // relative to the last one, and importing any of them is a cycle.
+    loading: Vec<PathBuf>,
+    // by canonical path
+    loaded: HashMap<PathBuf, Rc<M>>,
+}
+
+/// What an import needs to do, see `Modules::find`.
+pub(crate) enum Import<M> {
+    Loaded(Rc<M>),
+    // the file's been entered, tell `Modules::loaded` how running it went
+    Load { file: PathBuf, source: String },
+}
+
+impl<M> Default for Modules<M> {
+    fn default() -> Self {
+        Self {
+            loading: Vec::new(),
+            loaded: HashMap::new(),
+        }
+    }
+}
+
+impl<M> Modules<M> {
+    /// Note that `path` is being run, so imports are found next to it.
+    pub(crate) fn enter_file(&mut self, path: &Path) -> Result<()> {
+        self.loading.push(fs::canonicalize(path)?);
+        Ok(())
+    }
+
+    pub(crate) fn leave_file(&mut self) {
+        self.loading.pop();
+    }
+
+    /// Find the module `path` refers to, from the file being run. If it
+    /// hasn't been loaded before, it's file is entered and the source read,
+    /// ready to run. The error is why it can't be imported.
+    pub(crate) fn find(&mut self, path: &str) -> std::result::Result<Import<M>, String> {
+        let dir = match self.loading.last().and_then(|p| p.parent()) {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::new(),
+        };
+        let file = fs::canonicalize(dir.join(path)).map_err(|e| e.to_string())?;
+        if let Some(module) = self.loaded.get(&file) {
+            return Ok(Import::Loaded(Rc::clone(module)));
+        }
+
+        if let Some(start) = self.loading.iter().position(|p| *p == file) {
+            let cycle: Vec<String> = self.loading[start..]
+                .iter()
+                .chain([&file])
+                .map(|p| file_name(p))
+                .collect();
+            return Err(format!("import cycle {}", cycle.join(" -> ")));
+        }
+
+        let source = fs::read_to_string(&file).map_err(|e| e.to_string())?;
+        self.loading.push(file.clone());
+        Ok(Import::Load { file, source })
+    }
+
+    /// Every module loaded so far.
+    pub(crate) fn loaded_modules(&self) -> impl Iterator<Item = &Rc<M>> {
+        self.loaded.values()
+    }
+
+    /// Leave the file `find` entered, keeping the module it made if it
+    /// loaded.
+    pub(crate) fn loaded(&mut self, module: Option<&Rc<M>>) {
+        if let (Some(file), Some(module)) = (self.loading.pop(), module) {
+            self.loaded.insert(file, Rc::clone(module));
+        }
+    }
+}
+
+/// A module's name, the file name without it's extension.
+pub(crate) fn file_name(path: &Path) -> String {
+    match path.file_stem() {
+        Some(stem) => stem.to_string_lossy().to_string(),
+        None => path.display().to_string(),
+    }
+}
@@ -0,0 +1,512 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::rc::Rc;
+
+use crate::builtins::ScriptValue;
+use crate::callable::Callable;
+use crate::class::{Class, Instance};
+use crate::expr::LiteralValue;
+use crate::gc::{self, Trace};
+use crate::map::MapKey;
+use crate::module::Module;
+use crate::rlvalue::{List, Map, RlValue, Unpacked};
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("the nan-boxing feature needs a 64-bit target");
+
+/// The `nan-boxing` feature's `RlValue`, packed into 8 bytes rather than
+/// the 64 `Unpacked` takes.
+///
+/// Any double which isn't a NaN is stored as it is. The rest of the
+/// values hide in the bits of a quiet NaN [0], which no arithmetic produces
+/// (NaNs the scripts make are all stored as the one the hardware uses):
+///
+/// ```text
+///   nil, false, true:  0 11111111111 11 000...0 + 1, 2 or 3
+///   heap objects:      1 11111111111 11 00 + a 48 bit pointer
+/// ```
+///
+/// Objects are `Rc`s, whose pointers are always 8-byte aligned, so their
+/// low 3 bits are free to say which kind of object it is. Strings and
+/// functions aren't a single pointer in `Unpacked`, so they get boxed in
+/// another `Rc` here.
+///
+/// A `NanBox` owns a strong reference to it's object, like the `Rc` it
+/// was made from, and clones and drops it the same way.
+///
+/// [0] https://piotrduperas.com/posts/nan-boxing
+pub struct NanBox(u64);
+
+const SIGN: u64 = 1 << 63;
+// the exponent all ones, the quiet bit, and one more so it can't be
+// mistaken for `CANONICAL_NAN`
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+const NIL: u64 = QNAN | 1;
+const FALSE: u64 = QNAN | 2;
+const TRUE: u64 = QNAN | 3;
+
+const OBJECT: u64 = SIGN | QNAN;
+const POINTER: u64 = 0x0000_ffff_ffff_fff8;
+const TAG: u64 = 0b111;
+
+// which kind of object the pointer is to
+const STRING: u64 = 0;
+const CALLABLE: u64 = 1;
+const CLASS: u64 = 2;
+const INSTANCE: u64 = 3;
+const LIST: u64 = 4;
+const MAP: u64 = 5;
+const MODULE: u64 = 6;
+
+// the tags need the low bits of every pointer to be free
+const _: () = assert!(
+    align_of::<Rc<str>>() >= 8
+        && align_of::<Callable>() >= 8
+        && align_of::<Class>() >= 8
+        && align_of::<RefCell<Instance>>() >= 8
+        && align_of::<RefCell<Vec<NanBox>>>() >= 8
+        && align_of::<RefCell<HashMap<MapKey, NanBox>>>() >= 8
+        && align_of::<Module>() >= 8
+);
+
+impl NanBox {
+    pub fn number(d: f64) -> Self {
+        match d.is_nan() {
+            true => NanBox(CANONICAL_NAN),
+            false => NanBox(d.to_bits()),
+        }
+    }
+
+    fn object<T>(rc: Rc<T>, tag: u64) -> Self {
+        let ptr = Rc::into_raw(rc) as u64;
+        debug_assert_eq!(ptr & !POINTER, 0, "pointer doesn't fit in a NanBox");
+        NanBox(OBJECT | ptr | tag)
+    }
+
+    fn is_object(&self) -> bool {
+        self.0 & OBJECT == OBJECT
+    }
+
+    fn tag(&self) -> Option<u64> {
+        self.is_object().then_some(self.0 & TAG)
+    }
+
+    /// The object, as the `Rc` it was made from. It mustn't be dropped,
+    /// as the reference still belongs to the box.
+    ///
+    /// # Safety
+    ///
+    /// `T` has to be the type the tag says the object is.
+    unsafe fn rc<T>(&self) -> ManuallyDrop<Rc<T>> {
+        ManuallyDrop::new(Rc::from_raw((self.0 & POINTER) as *const T))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.0 == TRUE || self.0 == FALSE
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.tag() == Some(STRING)
+    }
+
+    /// The name of the value's type, as shown to script authors in errors.
+    pub fn type_name(&self) -> &'static str {
+        match self.tag() {
+            None if self.is_numeric() => "number",
+            None if self.is_nil() => "nil",
+            None => "boolean",
+            Some(STRING) => "string",
+            Some(CALLABLE) => "function",
+            Some(CLASS) => "class",
+            Some(INSTANCE) => "instance",
+            Some(LIST) => "list",
+            Some(MAP) => "map",
+            Some(_) => "module",
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        self.0 != NIL && self.0 != FALSE
+    }
+
+    pub fn as_numeric(&self) -> Option<f64> {
+        self.is_numeric().then(|| f64::from_bits(self.0))
+    }
+
+    pub fn as_string(&self) -> Option<Rc<str>> {
+        match self.tag() {
+            Some(STRING) => Some(Rc::clone(unsafe { &self.rc::<Rc<str>>() })),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<List> {
+        match self.tag() {
+            Some(LIST) => Some(Rc::clone(unsafe { &self.rc() })),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<Map> {
+        match self.tag() {
+            Some(MAP) => Some(Rc::clone(unsafe { &self.rc() })),
+            _ => None,
+        }
+    }
+
+    /// The value as a map key, or `None` if it's of an unhashable type.
+    pub fn to_key(&self) -> Option<MapKey> {
+        match self.tag() {
+            None if self.is_numeric() => MapKey::number(f64::from_bits(self.0)),
+            None if self.is_nil() => Some(MapKey::Nil),
+            None => Some(MapKey::Boolean(self.0 == TRUE)),
+            Some(STRING) => self.as_string().map(MapKey::String),
+            Some(_) => None,
+        }
+    }
+
+    /// What the value is, to match on.
+    pub fn unpack(self) -> Unpacked {
+        let ptr = self.0 & POINTER;
+        let tag = self.tag();
+        // the reference the box owned now belongs to what it unpacks to
+        let this = ManuallyDrop::new(self);
+        unsafe {
+            match tag {
+                None if this.is_numeric() => Unpacked::Double(f64::from_bits(this.0)),
+                None if this.is_nil() => Unpacked::Nil,
+                None => Unpacked::Boolean(this.0 == TRUE),
+                Some(STRING) => Unpacked::String(unbox(ptr)),
+                Some(CALLABLE) => Unpacked::Callable(unbox(ptr)),
+                Some(CLASS) => Unpacked::Class(Rc::from_raw(ptr as *const _)),
+                Some(INSTANCE) => Unpacked::Instance(Rc::from_raw(ptr as *const _)),
+                Some(LIST) => Unpacked::List(Rc::from_raw(ptr as *const _)),
+                Some(MAP) => Unpacked::Map(Rc::from_raw(ptr as *const _)),
+                Some(_) => Unpacked::Module(Rc::from_raw(ptr as *const _)),
+            }
+        }
+    }
+
+    pub fn new_list(values: Vec<RlValue>) -> Self {
+        let list = Rc::new(RefCell::new(values));
+        gc::track(&list);
+        NanBox::List(list)
+    }
+
+    pub fn new_map(entries: HashMap<MapKey, RlValue>) -> Self {
+        let map = Rc::new(RefCell::new(entries));
+        gc::track(&map);
+        NanBox::Map(map)
+    }
+}
+
+/// Take a string or function out of the extra `Rc` it's boxed in.
+///
+/// # Safety
+///
+/// `ptr` has to be one of those `Rc`s, which is given up.
+unsafe fn unbox<T: Clone>(ptr: u64) -> T {
+    Rc::unwrap_or_clone(Rc::from_raw(ptr as *const T))
+}
+
+// constructors named after `Unpacked`'s variants, so code making values
+// is the same whichever `RlValue` it's making.
+#[allow(non_snake_case, non_upper_case_globals)]
+impl NanBox {
+    pub const Nil: NanBox = NanBox(NIL);
+
+    pub fn Boolean(b: bool) -> Self {
+        match b {
+            true => NanBox(TRUE),
+            false => NanBox(FALSE),
+        }
+    }
+
+    pub fn Double(d: f64) -> Self {
+        NanBox::number(d)
+    }
+
+    pub fn String(s: Rc<str>) -> Self {
+        NanBox::object(Rc::new(s), STRING)
+    }
+
+    pub fn Callable(c: Callable) -> Self {
+        NanBox::object(Rc::new(c), CALLABLE)
+    }
+
+    pub fn Class(c: Rc<Class>) -> Self {
+        NanBox::object(c, CLASS)
+    }
+
+    pub fn Instance(i: Rc<RefCell<Instance>>) -> Self {
+        NanBox::object(i, INSTANCE)
+    }
+
+    pub fn List(l: List) -> Self {
+        NanBox::object(l, LIST)
+    }
+
+    pub fn Map(m: Map) -> Self {
+        NanBox::object(m, MAP)
+    }
+
+    pub fn Module(m: Rc<Module>) -> Self {
+        NanBox::object(m, MODULE)
+    }
+}
+
+impl Default for NanBox {
+    fn default() -> Self {
+        NanBox(NIL)
+    }
+}
+
+impl From<Unpacked> for NanBox {
+    fn from(value: Unpacked) -> Self {
+        match value {
+            Unpacked::Nil => NanBox::Nil,
+            Unpacked::Boolean(b) => NanBox::Boolean(b),
+            Unpacked::Double(d) => NanBox::Double(d),
+            Unpacked::String(s) => NanBox::String(s),
+            Unpacked::Callable(c) => NanBox::Callable(c),
+            Unpacked::Class(c) => NanBox::Class(c),
+            Unpacked::Instance(i) => NanBox::Instance(i),
+            Unpacked::List(l) => NanBox::List(l),
+            Unpacked::Map(m) => NanBox::Map(m),
+            Unpacked::Module(m) => NanBox::Module(m),
+        }
+    }
+}
+
+impl From<&MapKey> for NanBox {
+    fn from(key: &MapKey) -> Self {
+        NanBox::from(Unpacked::from(key))
+    }
+}
+
+impl From<LiteralValue> for NanBox {
+    fn from(l: LiteralValue) -> Self {
+        NanBox::from(Unpacked::from(l))
+    }
+}
+
+impl From<&LiteralValue> for NanBox {
+    fn from(l: &LiteralValue) -> Self {
+        NanBox::from(Unpacked::from(l))
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> Self {
+        let ptr = (self.0 & POINTER) as *const ();
+        unsafe {
+            match self.tag() {
+                None => (),
+                Some(STRING) => Rc::increment_strong_count(ptr as *const Rc<str>),
+                Some(CALLABLE) => Rc::increment_strong_count(ptr as *const Callable),
+                Some(CLASS) => Rc::increment_strong_count(ptr as *const Class),
+                Some(INSTANCE) => Rc::increment_strong_count(ptr as *const RefCell<Instance>),
+                Some(LIST) => Rc::increment_strong_count(ptr as *const RefCell<Vec<NanBox>>),
+                Some(MAP) => {
+                    Rc::increment_strong_count(ptr as *const RefCell<HashMap<MapKey, NanBox>>)
+                }
+                Some(_) => Rc::increment_strong_count(ptr as *const Module),
+            }
+        }
+        NanBox(self.0)
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        let ptr = (self.0 & POINTER) as *const ();
+        unsafe {
+            match self.tag() {
+                None => (),
+                Some(STRING) => Rc::decrement_strong_count(ptr as *const Rc<str>),
+                Some(CALLABLE) => Rc::decrement_strong_count(ptr as *const Callable),
+                Some(CLASS) => Rc::decrement_strong_count(ptr as *const Class),
+                Some(INSTANCE) => Rc::decrement_strong_count(ptr as *const RefCell<Instance>),
+                Some(LIST) => Rc::decrement_strong_count(ptr as *const RefCell<Vec<NanBox>>),
+                Some(MAP) => {
+                    Rc::decrement_strong_count(ptr as *const RefCell<HashMap<MapKey, NanBox>>)
+                }
+                Some(_) => Rc::decrement_strong_count(ptr as *const Module),
+            }
+        }
+    }
+}
+
+/// The same as `Unpacked`'s: by value for nil, booleans, numbers and
+/// strings, by identity for everything else.
+impl PartialEq for NanBox {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.tag(), other.tag()) {
+            (None, None) if self.is_numeric() && other.is_numeric() => {
+                f64::from_bits(self.0) == f64::from_bits(other.0)
+            }
+            (Some(STRING), Some(STRING)) => self.as_string() == other.as_string(),
+            (Some(CALLABLE), Some(CALLABLE)) => unsafe {
+                **self.rc::<Callable>() == **other.rc::<Callable>()
+            },
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl fmt::Display for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.clone().unpack())
+    }
+}
+
+impl fmt::Debug for NanBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NanBox({:?})", self.clone().unpack())
+    }
+}
+
+impl ScriptValue for NanBox {
+    fn type_name(&self) -> &'static str {
+        NanBox::type_name(self)
+    }
+
+    fn number(n: f64) -> Self {
+        NanBox::number(n)
+    }
+
+    fn boolean(b: bool) -> Self {
+        NanBox::Boolean(b)
+    }
+
+    fn string(s: &str) -> Self {
+        NanBox::String(Rc::from(s))
+    }
+
+    fn as_numeric(&self) -> Option<f64> {
+        NanBox::as_numeric(self)
+    }
+
+    fn as_string(&self) -> Option<Rc<str>> {
+        NanBox::as_string(self)
+    }
+
+    fn as_list(&self) -> Option<List> {
+        NanBox::as_list(self)
+    }
+
+    fn as_map(&self) -> Option<Map> {
+        NanBox::as_map(self)
+    }
+
+    fn to_key(&self) -> Option<MapKey> {
+        NanBox::to_key(self)
+    }
+
+    fn new_list(values: Vec<NanBox>) -> Self {
+        NanBox::new_list(values)
+    }
+
+    fn new_map(entries: HashMap<MapKey, NanBox>) -> Self {
+        NanBox::new_map(entries)
+    }
+}
+
+impl Trace for NanBox {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match self.tag() {
+            Some(CALLABLE) => unsafe { self.rc::<Callable>().trace(visit) },
+            // the same pointers `Rc::as_ptr` gives
+            Some(CLASS | INSTANCE | LIST | MAP) => visit((self.0 & POINTER) as *const ()),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builtins::BuiltInFunction;
+
+    #[test]
+    fn eight_bytes() {
+        assert_eq!(std::mem::size_of::<NanBox>(), 8);
+    }
+
+    #[test]
+    fn plain_values() {
+        for value in [
+            Unpacked::Nil,
+            Unpacked::Boolean(true),
+            Unpacked::Boolean(false),
+            Unpacked::Double(0.0),
+            Unpacked::Double(-1.5),
+            Unpacked::Double(f64::INFINITY),
+            Unpacked::Double(f64::MAX),
+        ] {
+            let boxed = NanBox::from(value.clone());
+            assert_eq!(boxed.clone().unpack(), value);
+            assert_eq!(boxed.type_name(), value.type_name());
+            assert_eq!(boxed.is_truthy(), value.is_truthy());
+            assert_eq!(boxed.as_numeric(), value.as_numeric());
+        }
+
+        let nan = NanBox::number(-f64::NAN);
+        assert!(nan.is_numeric());
+        assert!(nan.as_numeric().is_some_and(f64::is_nan));
+        assert_ne!(nan, nan.clone());
+        assert!(NanBox::default().is_nil());
+    }
+
+    #[test]
+    fn objects_are_reference_counted() {
+        let list = Rc::new(RefCell::new(vec![NanBox::Double(1.0)]));
+        let boxed = NanBox::List(Rc::clone(&list));
+        assert_eq!(Rc::strong_count(&list), 2);
+
+        let copy = boxed.clone();
+        assert_eq!(Rc::strong_count(&list), 3);
+        assert!(Rc::ptr_eq(&copy.as_list().unwrap(), &list));
+        assert_eq!(Rc::strong_count(&list), 3);
+        assert_eq!(copy.type_name(), "list");
+
+        // unpacking hands the box's reference over
+        assert!(matches!(copy.unpack(), Unpacked::List(l) if Rc::ptr_eq(&l, &list)));
+        assert_eq!(Rc::strong_count(&list), 2);
+        drop(boxed);
+        assert_eq!(Rc::strong_count(&list), 1);
+    }
+
+    #[test]
+    fn strings_and_functions() {
+        let s: Rc<str> = Rc::from("hello");
+        let boxed = NanBox::String(Rc::clone(&s));
+        assert!(boxed.is_string());
+        assert_eq!(boxed, NanBox::String(Rc::from("hello")));
+        assert_eq!(boxed.to_key(), Some(MapKey::String(Rc::clone(&s))));
+        assert!(matches!(boxed.clone().unpack(), Unpacked::String(u) if Rc::ptr_eq(&u, &s)));
+        drop(boxed);
+        assert_eq!(Rc::strong_count(&s), 1);
+
+        let clock = NanBox::Callable(Callable::BuiltIn(BuiltInFunction::Clock));
+        assert_eq!(clock.type_name(), "function");
+        assert_eq!(clock, clock.clone());
+        assert_eq!(clock.to_key(), None);
+        assert!(matches!(
+            clock.unpack(),
+            Unpacked::Callable(Callable::BuiltIn(_))
+        ));
+    }
+}
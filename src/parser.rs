@@ -1,28 +1,44 @@
 use crate::error::{Result, RloxError};
-use crate::expr::{Expr, LiteralValue};
+use crate::expr::{Expr, ExprKind, LiteralValue};
 use crate::stmt::Stmt;
 use crate::token::{Literal, Token, TokenType};
 use crate::ErrorReporter;
 
+use std::fmt;
+use std::rc::Rc;
+
 pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
-    _error_reporter: ErrorReporter,
+    error_reporter: ErrorReporter,
     current: usize,
+
+    // every syntax error seen so far, already reported.
+    errors: Vec<RloxError>,
 }
 
-#[allow(dead_code)]
 enum FunctionKind {
     Function,
-    #[allow(dead_code)]
+    Lambda,
     Method,
 }
 
+impl fmt::Display for FunctionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FunctionKind::Function => write!(f, "function"),
+            FunctionKind::Lambda => write!(f, "lambda"),
+            FunctionKind::Method => write!(f, "method"),
+        }
+    }
+}
+
 impl<'a> Parser<'a> {
     pub fn new(tokens: &'a Vec<Token>, error_reporter: ErrorReporter) -> Self {
         Parser {
             tokens,
-            _error_reporter: error_reporter,
+            error_reporter,
             current: 0,
+            errors: Vec::new(),
         }
     }
 
@@ -40,61 +56,207 @@ impl<'a> Parser<'a> {
     /// Factor (Multiplication)
     /// Unary
     /// (Primary)
+    ///
+    /// Fails if there were any syntax errors, each of which has already
+    /// been reported through the `ErrorReporter`.
     pub fn parse(&mut self) -> Result<Vec<Stmt>> {
+        let (stmts, errors) = self.parse_recovering();
+        match errors.len() {
+            0 => Ok(stmts),
+            1 => Err(errors.into_iter().next().expect("just checked the len")),
+            _ => Err(RloxError::ParseError(
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            )),
+        }
+    }
+
+    /// Parse the whole token stream, recovering from syntax errors by
+    /// skipping ahead to the next statement boundary. Returns all
+    /// the statements which parsed cleanly, along with every error.
+    pub fn parse_recovering(&mut self) -> (Vec<Stmt>, Vec<RloxError>) {
         let mut stmts = Vec::new();
         while !self.at_end() {
-            stmts.push(self.declaration()?);
+            if let Some(stmt) = self.recovering_declaration() {
+                stmts.push(stmt);
+            }
+        }
+        (stmts, std::mem::take(&mut self.errors))
+    }
+
+    /// A declaration, or `None` if it had a syntax error, which is kept
+    /// for later and skipped past.
+    fn recovering_declaration(&mut self) -> Option<Stmt> {
+        match self.declaration() {
+            Ok(stmt) => Some(stmt),
+            Err(e) => {
+                self.errors.push(e);
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    /// Panic-mode recovery: discard tokens until we're (probably) at the
+    /// start of the next statement.
+    fn synchronize(&mut self) {
+        self.advance();
+
+        while !self.at_end() {
+            if self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            // the end of the block the error was in, which `block` closes
+            if self.check(TokenType::RightBrace) {
+                return;
+            }
+
+            match self.peek().token_type {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Import
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Throw
+                | TokenType::Try => return,
+                _ => (),
+            }
+
+            self.advance();
         }
-        Ok(stmts)
     }
 
     fn declaration(&mut self) -> Result<Stmt> {
-        if self.matching(vec![TokenType::Fun]) {
+        if self.matching(vec![TokenType::Class]) {
+            return self.class_declaration();
+        }
+        // a `fun` not followed by a name is a lambda, in an expression statement
+        if self.check(TokenType::Fun) && self.check_next(TokenType::Identifier) {
+            self.advance();
             return self.function(FunctionKind::Function);
         }
+        if self.matching(vec![TokenType::Import]) {
+            return self.import_declaration();
+        }
         if self.matching(vec![TokenType::Var]) {
             return self.var_declaration();
         }
         self.statement()
     }
 
-    fn function(&mut self, _kind: FunctionKind) -> Result<Stmt> {
-        let name = self.consume(TokenType::Identifier).clone();
+    fn import_declaration(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        let path = match &self
+            .consume(TokenType::String, "expected a file path after 'import'")?
+            .literal
+        {
+            Some(Literal::StringLiteral(s)) => s.to_string(),
+            _ => unreachable!("a string token always has a string literal"),
+        };
+
+        self.consume(TokenType::As, "expected 'as' after the import path")?;
+        let name = self
+            .consume(TokenType::Identifier, "expected module name after 'as'")?
+            .clone();
+        self.consume(TokenType::Semicolon, "expected ';' after import")?;
+        Ok(Stmt::Import {
+            keyword,
+            path,
+            name,
+        })
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt> {
+        let name = self
+            .consume(TokenType::Identifier, "expected class name")?
+            .clone();
 
-        self.consume(TokenType::LeftParen);
+        let superclass = if self.matching(vec![TokenType::Less]) {
+            let superclass_name = self
+                .consume(TokenType::Identifier, "expected superclass name")?
+                .clone();
+            Some(ExprKind::Variable(superclass_name).into())
+        } else {
+            None
+        };
+
+        self.consume(TokenType::LeftBrace, "expected '{' before class body")?;
+
+        let mut methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.at_end() {
+            methods.push(self.function(FunctionKind::Method)?);
+        }
+
+        self.consume(TokenType::RightBrace, "expected '}' after class body")?;
+        Ok(Stmt::Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
+    fn function(&mut self, kind: FunctionKind) -> Result<Stmt> {
+        let name = self
+            .consume(TokenType::Identifier, &format!("expected {} name", kind))?
+            .clone();
+
+        self.consume(
+            TokenType::LeftParen,
+            &format!("expected '(' after {} name", kind),
+        )?;
+        let (params, body) = self.function_body(kind)?;
+        Ok(Stmt::Function { name, params, body })
+    }
+
+    /// Parse a function's parameters and body, starting just after the '('.
+    fn function_body(&mut self, kind: FunctionKind) -> Result<(Vec<Token>, Vec<Stmt>)> {
         let mut params = Vec::new();
 
         // check for zero params
         if !self.check(TokenType::RightParen) {
             loop {
                 if params.len() >= 255 {
-                    return Err(RloxError::ArityError(255, 256));
+                    // report, but no need to synchronize
+                    let token = self.peek().clone();
+                    let e = self.error(&token, "can't have more than 255 parameters");
+                    self.errors.push(e);
                 }
 
-                params.push(self.consume(TokenType::Identifier).clone());
+                params.push(
+                    self.consume(TokenType::Identifier, "expected parameter name")?
+                        .clone(),
+                );
 
                 if !self.matching(vec![TokenType::Comma]) {
                     break;
                 }
             }
         }
-        self.consume(TokenType::RightParen);
+        self.consume(TokenType::RightParen, "expected ')' after parameters")?;
 
         // now, on to the body of the function
-        self.consume(TokenType::LeftBrace);
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("expected '{{' before {} body", kind),
+        )?;
         // there's a better a way to do this, i am sure ...
         match self.block()? {
-            Stmt::Block(v) => Ok(Stmt::Function {
-                name,
-                params,
-                body: v,
-            }),
+            Stmt::Block(v) => Ok((params, v)),
             _ => unreachable!("wtf?!?!"),
         }
     }
 
     fn var_declaration(&mut self) -> Result<Stmt> {
-        let name = self.consume(TokenType::Identifier).clone();
+        let name = self
+            .consume(TokenType::Identifier, "expected variable name")?
+            .clone();
         let has_initializer = &self.matching(vec![TokenType::Equal]);
 
         let initializer = if *has_initializer {
@@ -103,12 +265,23 @@ impl<'a> Parser<'a> {
             None
         };
 
-        self.consume(TokenType::Semicolon);
+        self.consume(
+            TokenType::Semicolon,
+            "expected ';' after variable declaration",
+        )?;
         Ok(Stmt::Var { name, initializer })
     }
 
     fn statement(&mut self) -> Result<Stmt> {
-        if self.matching(vec![TokenType::For]) {
+        if self.matching(vec![TokenType::Break]) {
+            let keyword = self.previous().clone();
+            self.consume(TokenType::Semicolon, "expected ';' after 'break'")?;
+            return Ok(Stmt::Break(keyword));
+        } else if self.matching(vec![TokenType::Continue]) {
+            let keyword = self.previous().clone();
+            self.consume(TokenType::Semicolon, "expected ';' after 'continue'")?;
+            return Ok(Stmt::Continue(keyword));
+        } else if self.matching(vec![TokenType::For]) {
             return self.for_statement();
         } else if self.matching(vec![TokenType::If]) {
             return self.if_statement();
@@ -116,6 +289,10 @@ impl<'a> Parser<'a> {
             return self.print_statement();
         } else if self.matching(vec![TokenType::Return]) {
             return self.return_statement();
+        } else if self.matching(vec![TokenType::Throw]) {
+            return self.throw_statement();
+        } else if self.matching(vec![TokenType::Try]) {
+            return self.try_statement();
         } else if self.matching(vec![TokenType::While]) {
             return self.while_statement();
         } else if self.matching(vec![TokenType::LeftBrace]) && !self.at_end() {
@@ -126,7 +303,7 @@ impl<'a> Parser<'a> {
     }
 
     fn for_statement(&mut self) -> Result<Stmt> {
-        self.consume(TokenType::LeftParen);
+        self.consume(TokenType::LeftParen, "expected '(' after 'for'")?;
 
         let initializer = if self.matching(vec![TokenType::Semicolon]) {
             None
@@ -138,31 +315,28 @@ impl<'a> Parser<'a> {
 
         // default the condition to true if None was provided
         let condition = if self.check(TokenType::Semicolon) {
-            Expr::Literal(LiteralValue::Boolean(true))
+            ExprKind::Literal(LiteralValue::Boolean(true), self.peek().span).into()
         } else {
             self.expression()?
         };
-        self.consume(TokenType::Semicolon);
+        self.consume(TokenType::Semicolon, "expected ';' after loop condition")?;
 
         let increment = if self.check(TokenType::RightParen) {
             None
         } else {
             Some(self.expression()?)
         };
-        self.consume(TokenType::RightParen);
+        self.consume(TokenType::RightParen, "expected ')' after for clauses")?;
 
-        let mut body = self.statement()?;
+        let body = self.statement()?;
 
         //desugar the syntax
-        // put the increment after the body
-        if let Some(incr) = increment {
-            body = Stmt::Block(vec![body, Stmt::Expression(incr)]);
-        };
-
-        // put the conditional up front, and make it a while loop
-        body = Stmt::While {
+        // put the conditional up front, and make it a while loop.
+        // the increment runs after the body.
+        let mut body = Stmt::While {
             condition,
             body: Box::new(body),
+            increment,
         };
 
         if let Some(init) = initializer {
@@ -173,9 +347,9 @@ impl<'a> Parser<'a> {
     }
 
     fn if_statement(&mut self) -> Result<Stmt> {
-        self.consume(TokenType::LeftParen);
+        self.consume(TokenType::LeftParen, "expected '(' after 'if'")?;
         let condition = self.expression()?;
-        self.consume(TokenType::RightParen);
+        self.consume(TokenType::RightParen, "expected ')' after if condition")?;
 
         let then_branch = Box::new(self.statement()?);
         let else_branch = match self.matching(vec![TokenType::Else]) {
@@ -191,7 +365,7 @@ impl<'a> Parser<'a> {
 
     fn print_statement(&mut self) -> Result<Stmt> {
         let value = self.expression()?;
-        self.consume(TokenType::Semicolon);
+        self.consume(TokenType::Semicolon, "expected ';' after value")?;
         Ok(Stmt::Print(value))
     }
 
@@ -201,32 +375,93 @@ impl<'a> Parser<'a> {
             true => None,
             false => Some(self.expression()?),
         };
-        self.consume(TokenType::Semicolon);
+        self.consume(TokenType::Semicolon, "expected ';' after return value")?;
         Ok(Stmt::Return { keyword, expr })
     }
 
+    fn throw_statement(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        let value = self.expression()?;
+        self.consume(TokenType::Semicolon, "expected ';' after thrown value")?;
+        Ok(Stmt::Throw { keyword, value })
+    }
+
+    fn try_statement(&mut self) -> Result<Stmt> {
+        let keyword = self.previous().clone();
+        let body = self.block_body("try")?;
+
+        let catch = if self.matching(vec![TokenType::Catch]) {
+            self.consume(TokenType::LeftParen, "expected '(' after 'catch'")?;
+            let name = self
+                .consume(TokenType::Identifier, "expected name of the caught value")?
+                .clone();
+            self.consume(TokenType::RightParen, "expected ')' after caught name")?;
+            Some((name, self.block_body("catch")?))
+        } else {
+            None
+        };
+
+        let finally = if self.matching(vec![TokenType::Finally]) {
+            Some(self.block_body("finally")?)
+        } else {
+            None
+        };
+
+        if catch.is_none() && finally.is_none() {
+            let token = self.peek().clone();
+            return Err(self.error(&token, "expected 'catch' or 'finally' after try block"));
+        }
+
+        Ok(Stmt::Try {
+            keyword,
+            body,
+            catch,
+            finally,
+        })
+    }
+
+    /// The statements of a `{ ... }` block which must follow `keyword`.
+    fn block_body(&mut self, keyword: &str) -> Result<Vec<Stmt>> {
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("expected '{{' after '{}'", keyword),
+        )?;
+        match self.block()? {
+            Stmt::Block(stmts) => Ok(stmts),
+            _ => unreachable!("block() only makes blocks"),
+        }
+    }
+
     fn while_statement(&mut self) -> Result<Stmt> {
-        self.consume(TokenType::LeftParen);
+        self.consume(TokenType::LeftParen, "expected '(' after 'while'")?;
         let condition = self.expression()?;
-        self.consume(TokenType::RightParen);
+        self.consume(TokenType::RightParen, "expected ')' after condition")?;
         let body = Box::new(self.statement()?);
-        Ok(Stmt::While { condition, body })
+        Ok(Stmt::While {
+            condition,
+            body,
+            increment: None,
+        })
     }
 
     fn block(&mut self) -> Result<Stmt> {
         let mut stmts = Vec::new();
 
+        // recover inside the block, so the rest of it (and the '}' which
+        // ends it) still parse as they should
         while !self.check(TokenType::RightBrace) && !self.at_end() {
-            stmts.push(self.declaration()?);
+            if let Some(stmt) = self.recovering_declaration() {
+                stmts.push(stmt);
+            }
         }
 
-        self.consume(TokenType::RightBrace);
+        self.consume(TokenType::RightBrace, "expected '}' after block")?;
         Ok(Stmt::Block(stmts))
     }
 
     fn expression_statement(&mut self) -> Result<Stmt> {
         let expr = self.expression()?;
-        self.consume(TokenType::Semicolon);
+        self.consume(TokenType::Semicolon, "expected ';' after expression")?;
         Ok(Stmt::Expression(expr))
     }
 
@@ -238,17 +473,23 @@ impl<'a> Parser<'a> {
         let expr = self.or()?;
 
         if self.matching(vec![TokenType::Equal]) {
-            let _equals = self.previous();
+            let equals = self.previous().clone();
             let value = self.assignment()?;
 
-            match expr {
-                Expr::Variable(t) => {
-                    return Ok(Expr::Assign(t, Box::new(value)));
+            match expr.kind {
+                ExprKind::Variable(t) => {
+                    return Ok(ExprKind::Assign(t, Box::new(value)).into());
+                }
+                ExprKind::Get(object, name) => {
+                    return Ok(ExprKind::Set(object, name, Box::new(value)).into());
+                }
+                ExprKind::Index(object, bracket, index) => {
+                    return Ok(ExprKind::SetIndex(object, bracket, index, Box::new(value)).into());
                 }
                 _ => {
-                    return Err(RloxError::ParseError(String::from(
-                        "Invalid assignment target",
-                    )));
+                    // the parser isn't confused, so report without unwinding
+                    let e = self.error(&equals, "invalid assignment target");
+                    self.errors.push(e);
                 }
             }
         }
@@ -262,7 +503,7 @@ impl<'a> Parser<'a> {
         while self.matching(vec![TokenType::Or]) {
             let operator = self.previous().clone();
             let right = Box::new(self.and()?);
-            expr = Expr::Logical(Box::new(expr), operator, right)
+            expr = ExprKind::Logical(Box::new(expr), operator, right).into()
         }
 
         Ok(expr)
@@ -274,7 +515,7 @@ impl<'a> Parser<'a> {
         while self.matching(vec![TokenType::And]) {
             let operator = self.previous().clone();
             let right = Box::new(self.equality()?);
-            expr = Expr::Logical(Box::new(expr), operator, right);
+            expr = ExprKind::Logical(Box::new(expr), operator, right).into();
         }
 
         Ok(expr)
@@ -283,10 +524,10 @@ impl<'a> Parser<'a> {
     fn equality(&mut self) -> Result<Expr> {
         let mut expr = self.comparison()?;
 
-        if self.matching(vec![TokenType::BangEqual, TokenType::EqualEqual]) {
+        while self.matching(vec![TokenType::BangEqual, TokenType::EqualEqual]) {
             let operator = self.previous().clone();
             let right = self.comparison()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = ExprKind::Binary(Box::new(expr), operator, Box::new(right)).into();
         }
 
         Ok(expr)
@@ -295,7 +536,7 @@ impl<'a> Parser<'a> {
     fn comparison(&mut self) -> Result<Expr> {
         let mut expr = self.term()?;
 
-        if self.matching(vec![
+        while self.matching(vec![
             TokenType::Greater,
             TokenType::GreaterEqual,
             TokenType::Less,
@@ -303,7 +544,7 @@ impl<'a> Parser<'a> {
         ]) {
             let operator = self.previous().clone();
             let right = self.term()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = ExprKind::Binary(Box::new(expr), operator, Box::new(right)).into();
         }
 
         Ok(expr)
@@ -312,10 +553,10 @@ impl<'a> Parser<'a> {
     fn term(&mut self) -> Result<Expr> {
         let mut expr = self.factor()?;
 
-        if self.matching(vec![TokenType::Plus, TokenType::Minus]) {
+        while self.matching(vec![TokenType::Plus, TokenType::Minus]) {
             let operator = self.previous().clone();
             let right = self.factor()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = ExprKind::Binary(Box::new(expr), operator, Box::new(right)).into();
         }
 
         Ok(expr)
@@ -324,10 +565,10 @@ impl<'a> Parser<'a> {
     fn factor(&mut self) -> Result<Expr> {
         let mut expr = self.unary()?;
 
-        if self.matching(vec![TokenType::Slash, TokenType::Star]) {
+        while self.matching(vec![TokenType::Slash, TokenType::Star]) {
             let operator = self.previous().clone();
             let right = self.unary()?;
-            expr = Expr::Binary(Box::new(expr), operator, Box::new(right));
+            expr = ExprKind::Binary(Box::new(expr), operator, Box::new(right)).into();
         }
 
         Ok(expr)
@@ -337,7 +578,7 @@ impl<'a> Parser<'a> {
         if self.matching(vec![TokenType::Bang, TokenType::Minus]) {
             let operator = self.previous().clone();
             let right = self.unary()?;
-            return Ok(Expr::Unary(operator, Box::new(right)));
+            return Ok(ExprKind::Unary(operator, Box::new(right)).into());
         }
 
         self.call()
@@ -349,6 +590,17 @@ impl<'a> Parser<'a> {
         loop {
             if self.matching(vec![TokenType::LeftParen]) {
                 expr = self.finish_call(expr)?;
+            } else if self.matching(vec![TokenType::Dot]) {
+                let name = self
+                    .consume(TokenType::Identifier, "expected property name after '.'")?
+                    .clone();
+                expr = ExprKind::Get(Box::new(expr), name).into();
+            } else if self.matching(vec![TokenType::LeftBracket]) {
+                let index = self.expression()?;
+                let bracket = self
+                    .consume(TokenType::RightBracket, "expected ']' after index")?
+                    .clone();
+                expr = ExprKind::Index(Box::new(expr), bracket, Box::new(index)).into();
             } else {
                 break;
             }
@@ -357,32 +609,83 @@ impl<'a> Parser<'a> {
         Ok(expr)
     }
 
+    fn finish_list(&mut self, bracket: Token) -> Result<Expr> {
+        let mut elements = Vec::new();
+
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                elements.push(self.expression()?);
+                if !self.matching(vec![TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let closing = self.consume(TokenType::RightBracket, "expected ']' after list elements")?;
+        // stretch the bracket's span over the whole literal, for errors
+        let span = bracket.span.to(closing.span);
+
+        Ok(ExprKind::List(bracket.with_span(span), elements).into())
+    }
+
+    fn finish_map(&mut self, brace: Token) -> Result<Expr> {
+        let mut entries = Vec::new();
+
+        if !self.check(TokenType::RightBrace) {
+            loop {
+                let key = self.expression()?;
+                self.consume(TokenType::Colon, "expected ':' after map key")?;
+                let value = self.expression()?;
+                entries.push((key, value));
+                if !self.matching(vec![TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let closing = self.consume(TokenType::RightBrace, "expected '}' after map entries")?;
+        let span = brace.span.to(closing.span);
+
+        Ok(ExprKind::Map(brace.with_span(span), entries).into())
+    }
+
     fn finish_call(&mut self, callee: Expr) -> Result<Expr> {
         let mut args = Vec::new();
 
         if !self.check(TokenType::RightParen) {
             loop {
+                if args.len() >= 255 {
+                    let token = self.peek().clone();
+                    let e = self.error(&token, "can't have more than 255 arguments");
+                    self.errors.push(e);
+                }
                 args.push(self.expression()?);
                 if !self.matching(vec![TokenType::Comma]) {
                     break;
                 }
             }
         }
-        let paren = self.consume(TokenType::RightParen).clone();
+        let paren = self
+            .consume(TokenType::RightParen, "expected ')' after arguments")?
+            .clone();
 
-        Ok(Expr::Call(Box::new(callee), paren, args))
+        Ok(ExprKind::Call(Box::new(callee), paren, args).into())
     }
 
     fn primary(&mut self) -> Result<Expr> {
+        if self.at_end() {
+            let token = self.peek().clone();
+            return Err(self.error(&token, "expected expression"));
+        }
+
         let next = self.advance();
+        let span = next.span;
         let expr = match next.token_type {
-            TokenType::False => Expr::Literal(LiteralValue::Boolean(false)),
-            TokenType::True => Expr::Literal(LiteralValue::Boolean(true)),
-            TokenType::Nil => Expr::Literal(LiteralValue::Nil()),
+            TokenType::False => ExprKind::Literal(LiteralValue::Boolean(false), span).into(),
+            TokenType::True => ExprKind::Literal(LiteralValue::Boolean(true), span).into(),
+            TokenType::Nil => ExprKind::Literal(LiteralValue::Nil(), span).into(),
 
             TokenType::Number => {
                 if let Some(Literal::NumberLiteral(n)) = next.literal {
-                    Expr::Literal(LiteralValue::Number(n))
+                    ExprKind::Literal(LiteralValue::Number(n), span).into()
                 } else {
                     return Err(RloxError::ParseError(format!(
                         "unsupported literal type with Number token type: {:?}",
@@ -393,7 +696,7 @@ impl<'a> Parser<'a> {
 
             TokenType::String => {
                 if let Some(Literal::StringLiteral(ref s)) = next.literal {
-                    Expr::Literal(LiteralValue::String(s.clone()))
+                    ExprKind::Literal(LiteralValue::String(s.clone()), span).into()
                 } else {
                     return Err(RloxError::ParseError(format!(
                         "unsupported literal type with String token type: {:?}",
@@ -402,20 +705,46 @@ impl<'a> Parser<'a> {
                 }
             }
 
-            TokenType::Var => Expr::Variable(self.previous().clone()),
-            TokenType::Identifier => Expr::Variable(self.previous().clone()),
+            TokenType::Super => {
+                let keyword = self.previous().clone();
+                self.consume(TokenType::Dot, "expected '.' after 'super'")?;
+                let method = self
+                    .consume(TokenType::Identifier, "expected superclass method name")?
+                    .clone();
+                ExprKind::Super(keyword, method).into()
+            }
+            TokenType::This => ExprKind::This(self.previous().clone()).into(),
+            TokenType::Fun => {
+                let keyword = self.previous().clone();
+                self.consume(TokenType::LeftParen, "expected '(' after 'fun'")?;
+                let (params, body) = self.function_body(FunctionKind::Lambda)?;
+                ExprKind::Lambda(keyword, params, Rc::new(body)).into()
+            }
+            TokenType::Var => ExprKind::Variable(self.previous().clone()).into(),
+            TokenType::Identifier => ExprKind::Variable(self.previous().clone()).into(),
 
             TokenType::LeftParen => {
                 let expr = self.expression()?;
-                self.consume(TokenType::RightParen);
-                Expr::Grouping(Box::new(expr))
+                self.consume(TokenType::RightParen, "expected ')' after expression")?;
+                ExprKind::Grouping(Box::new(expr)).into()
+            }
+            TokenType::LeftBracket => {
+                let bracket = self.previous().clone();
+                self.finish_list(bracket)?
+            }
+            // a '{' starting a statement is a block, so this only
+            // happens where an expression is expected.
+            TokenType::LeftBrace => {
+                let brace = self.previous().clone();
+                self.finish_map(brace)?
             }
 
             _ => {
-                return Err(RloxError::ParseError(format!(
-                    "unsupported token type in primary(): {:?}",
-                    next
-                )))
+                // step back so the offending token is reported, and
+                // synchronize() starts from it.
+                self.current -= 1;
+                let token = self.peek().clone();
+                return Err(self.error(&token, "expected expression"));
             }
         };
 
@@ -444,6 +773,13 @@ impl<'a> Parser<'a> {
         self.peek().token_type == token_type
     }
 
+    fn check_next(&self, token_type: TokenType) -> bool {
+        match self.tokens.get(self.current + 1) {
+            Some(token) => token.token_type == token_type,
+            None => false,
+        }
+    }
+
     fn advance(&mut self) -> &Token {
         if !self.at_end() {
             self.current += 1;
@@ -463,9 +799,86 @@ impl<'a> Parser<'a> {
         &self.tokens[self.current - 1]
     }
 
-    fn consume(&mut self, _token_type: TokenType) -> &Token {
-        //if self.check(token_type) {
-        return self.advance();
-        //}
+    fn consume(&mut self, token_type: TokenType, message: &str) -> Result<&Token> {
+        if self.check(token_type) {
+            return Ok(self.advance());
+        }
+
+        let token = self.peek().clone();
+        Err(self.error(&token, message))
+    }
+
+    /// Report a syntax error at the given token, and hand back the error
+    /// for the caller to either unwind with or just record.
+    fn error(&self, token: &Token, message: &str) -> RloxError {
+        let place = match token.token_type {
+            TokenType::Eof => String::from(" at end"),
+            _ => format!(" at '{}'", token.lexeme),
+        };
+        self.error_reporter.report_span(token.span, &place, message);
+        RloxError::ParseError(format!("[{}] Error{}: {}", token.span, place, message))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scanner::Scanner;
+
+    fn parse_recovering(src: &str) -> (Vec<Stmt>, Vec<RloxError>) {
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new(src.to_string(), error_reporter.clone());
+        scanner.scan_tokens().unwrap();
+        let tokens = scanner.tokens().clone();
+        let mut parser = Parser::new(&tokens, error_reporter);
+        parser.parse_recovering()
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let (stmts, errors) = parse_recovering("var a = 1\nprint a;\nprint (1;\nprint 2;");
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0]
+            .to_string()
+            .contains("expected ';' after variable declaration"));
+        assert!(errors[1]
+            .to_string()
+            .contains("expected ')' after expression"));
+        // `print 2;` still parsed after recovering
+        assert_eq!(stmts.len(), 1);
+    }
+
+    #[test]
+    fn recovers_inside_blocks() {
+        let (stmts, errors) = parse_recovering("fun f() {\n var a = ;\n print a;\n}\nprint 1;");
+        // and not another at the function's closing '}'
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("[line 2:10]"));
+        match &stmts[..] {
+            [Stmt::Function { body, .. }, Stmt::Print(_)] => {
+                assert!(matches!(body[..], [Stmt::Print(_)]))
+            }
+            stmts => panic!("unexpected statements {:?}", stmts),
+        }
+
+        let (_, errors) = parse_recovering("{ print (1; print 2; }\nwhile (true) { 1 +; }");
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn missing_semicolon_is_an_error() {
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new("print 1 print 2;".to_string(), error_reporter.clone());
+        scanner.scan_tokens().unwrap();
+        let mut parser = Parser::new(scanner.tokens(), error_reporter.clone());
+        assert!(matches!(parser.parse(), Err(RloxError::ParseError(_))));
+        assert!(error_reporter.had_error());
+    }
+
+    #[test]
+    fn invalid_assignment_target_does_not_unwind() {
+        let (stmts, errors) = parse_recovering("1 = 2; print 3;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(stmts.len(), 2);
     }
 }
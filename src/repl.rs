@@ -0,0 +1,206 @@
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use crate::error::{Result, RloxError};
+use crate::RLox;
+
+const PROMPT: &str = "> ";
+// shown while the input so far has unclosed brackets or strings
+const CONTINUATION_PROMPT: &str = ". ";
+
+// kept in the user's home dir, so it's shared by every session.
+const HISTORY_FILE: &str = ".rlox_history";
+
+const HELP: &str = "\
+:env           show the global variables
+:load <file>   run a script in this session
+:ast <expr>    show the syntax tree of an expression
+:reset         throw away all global variables
+:help          show this message
+:quit          leave the repl (so does ctrl-d)";
+
+/// Run an interactive session against `rlox` until the user quits.
+///
+/// Input keeps being read while brackets or strings are left open, the
+/// value of bare expression statements is printed, and errors are reported
+/// without ending the session. Lines starting with ':' are meta-commands,
+/// see `HELP`.
+pub(crate) fn run(rlox: &mut RLox) -> Result<()> {
+    let mut editor = DefaultEditor::new().map_err(readline_error)?;
+    let history = history_path();
+    if let Some(path) = &history {
+        // there won't be a file the first time around, that's fine
+        let _ = editor.load_history(path);
+    }
+
+    println!("rlox repl, :help for commands");
+    let mut buffer = String::new();
+    loop {
+        let prompt = match buffer.is_empty() {
+            true => PROMPT,
+            false => CONTINUATION_PROMPT,
+        };
+
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // ctrl-c throws away whatever has been typed so far
+            Err(ReadlineError::Interrupted) => {
+                buffer.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+
+        if buffer.is_empty() && line.trim_start().starts_with(':') {
+            let _ = editor.add_history_entry(line.trim());
+            match command(rlox, line.trim()) {
+                true => continue,
+                false => break,
+            }
+        }
+
+        buffer.push_str(&line);
+        buffer.push('\n');
+        if is_incomplete(&buffer) {
+            continue;
+        }
+
+        let input = std::mem::take(&mut buffer);
+        if input.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(input.trim_end());
+        rlox.interrupt_handle().clear();
+        let res = rlox.run_repl(&input);
+        finish(rlox, res);
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path).map_err(readline_error)?;
+    }
+    Ok(())
+}
+
+/// Run a meta-command. Returns false if the session should end.
+fn command(rlox: &mut RLox, line: &str) -> bool {
+    let (name, arg) = match line.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (line, ""),
+    };
+
+    match (name, arg) {
+        (":env", _) => {
+            for (name, value) in rlox.globals() {
+                println!("{} = {}", name, value);
+            }
+        }
+        (":load", "") => eprintln!("usage: :load <file>"),
+        (":load", file) => {
+            let res = rlox.run_file(file);
+            finish(rlox, res);
+        }
+        (":ast", "") => eprintln!("usage: :ast <expr>"),
+        (":ast", expr) => {
+            let res = rlox.ast(expr).map(|ast| println!("{}", ast));
+            finish(rlox, res);
+        }
+        (":reset", _) => rlox.reset(),
+        (":help", _) => println!("{}", HELP),
+        (":quit", _) | (":q", _) => return false,
+        _ => eprintln!("unknown command {}, try :help", name),
+    }
+    true
+}
+
+/// Print any error the `ErrorReporter` didn't already show, and get
+/// ready for the next input.
+fn finish<T>(rlox: &RLox, res: Result<T>) {
+    let reporter = rlox.error_reporter();
+    if let Err(e) = res {
+        if !reporter.had_error() && !reporter.had_runtime_error() {
+            eprintln!("{}", e);
+        }
+    }
+    reporter.reset();
+}
+
+/// Whether `src` has unclosed brackets or an unterminated string, meaning
+/// there's more to come. Too many closing brackets is left for the parser
+/// to complain about.
+fn is_incomplete(src: &str) -> bool {
+    let mut depth = 0;
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            // an unterminated string runs to the end of the input
+            '"' if !chars.by_ref().any(|c| c == '"') => return true,
+            '"' => (),
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '(' | '{' | '[' => depth += 1,
+            ')' | '}' | ']' => depth -= 1,
+            _ => (),
+        }
+    }
+    depth > 0
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+fn readline_error(e: ReadlineError) -> RloxError {
+    match e {
+        ReadlineError::Io(e) => RloxError::IoError(e),
+        e => RloxError::IoError(io::Error::other(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ErrorReporter;
+
+    #[test]
+    fn incomplete_input() {
+        assert!(is_incomplete("fun f() {\n"));
+        assert!(is_incomplete("print (1 +\n"));
+        assert!(is_incomplete("var xs = [1,\n"));
+        assert!(is_incomplete("var s = \"abc\n"));
+        assert!(!is_incomplete("fun f() { print 1; }\n"));
+        assert!(!is_incomplete("print \"{\";\n"));
+        assert!(!is_incomplete("print 1; // {\n"));
+        assert!(!is_incomplete("}\n"));
+    }
+
+    #[test]
+    fn meta_commands() -> Result<()> {
+        let mut rlox = RLox::new(ErrorReporter::default());
+        rlox.run_repl("var a = 1;\nvar b = \"two\"")?;
+        assert_eq!(
+            rlox.globals(),
+            vec![
+                (String::from("a"), String::from(" 1")),
+                (String::from("b"), String::from("two")),
+            ]
+        );
+
+        assert_eq!(rlox.ast("-a * (b + 2)")?, "(* (- a) (group (+ b 2)))");
+        assert!(rlox.ast("var c = 1;").is_err());
+
+        assert!(command(&mut rlox, ":reset"));
+        assert!(rlox.globals().is_empty());
+        assert!(!command(&mut rlox, ":quit"));
+        Ok(())
+    }
+}
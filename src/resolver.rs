@@ -1,36 +1,204 @@
 use std::collections::HashMap;
 
+use crate::environment::Slot;
 use crate::error::{Result, RloxError};
-use crate::expr::Expr;
+use crate::expr::{Expr, ExprId, ExprKind};
 use crate::interpreter::Interpreter;
 use crate::stmt::Stmt;
-use crate::token::Token;
+use crate::symbol::{self, Symbol};
+use crate::token::{Span, Token};
+use crate::ErrorReporter;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LocalKind {
+    Variable,
+    Parameter,
+    // local functions and classes, imports, caught exceptions, and the
+    // implicit `this` and `super`. these aren't warned about if unused.
+    Other,
+}
+
+/// What the resolver knows about a name declared in a local scope.
+#[derive(Clone, Copy, Debug)]
+struct Local {
+    // where it was declared
+    span: Span,
+    kind: LocalKind,
+    // false from declaring a variable until it's initializer is resolved
+    defined: bool,
+    // whether anything reads it. assigning to it doesn't count
+    used: bool,
+    // where it's kept in the scope's `Environment`
+    slot: usize,
+}
+
+impl Local {
+    fn implicit(span: Span) -> Self {
+        Self {
+            span,
+            kind: LocalKind::Other,
+            defined: true,
+            used: true,
+            slot: 0,
+        }
+    }
+}
+
+/// The names declared in a block or function body.
+#[derive(Debug, Default)]
+struct Scope {
+    locals: HashMap<Symbol, Local>,
+    // how many slots the scope's `Environment` will end up with. declaring
+    // a name twice gives it a second one, so this can be more than
+    // `locals.len()`.
+    slots: usize,
+}
+
+impl Scope {
+    /// Put `local` in the next free slot, returning whatever had it's name.
+    fn add(&mut self, name: Symbol, mut local: Local) -> Option<Local> {
+        local.slot = self.slots;
+        self.slots += 1;
+        self.locals.insert(name, local)
+    }
+
+    fn get(&self, name: Symbol) -> Option<&Local> {
+        self.locals.get(&name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
 
 pub struct Resolver<'a> {
     interpreter: &'a Interpreter,
 
+    // warnings go straight out through this, unless they're being
+    // treated as errors.
+    error_reporter: ErrorReporter,
+
     // CI uses a java.util.Stack<Map> to hold the scopes.
     // Rust has no stack data structure in the std lib,
     // but Vec is good enough as we only need push/pop.
-    scopes: Vec<HashMap<String, bool>>,
+    scopes: Vec<Scope>,
+
+    // what kind of function (if any) we're currently resolving the body of.
+    current_function: FunctionType,
+
+    // what kind of class (if any) we're currently resolving the body of.
+    current_class: ClassType,
+
+    // how many loops deep we are within the current function.
+    loop_depth: usize,
 }
 
 impl<'a> Resolver<'a> {
     pub fn new(interpreter: &'a Interpreter) -> Self {
         Self {
             interpreter,
+            error_reporter: interpreter.error_reporter.clone(),
             scopes: Vec::new(),
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+            loop_depth: 0,
         }
     }
 
+    /// Resolve a run of statements, like a whole script or the body of a
+    /// block, warning about any which can never be reached.
+    pub fn resolve_stmts(&mut self, stmts: &[Stmt]) -> Result<()> {
+        let mut reachable = true;
+        for (i, stmt) in stmts.iter().enumerate() {
+            self.resolve(stmt)?;
+
+            // only the first unreachable statement is worth pointing out
+            if let (true, Some(keyword), Some(next)) =
+                (reachable, exit_keyword(stmt), stmts.get(i + 1))
+            {
+                reachable = false;
+                self.warn(
+                    next.span().unwrap_or(keyword.span),
+                    format!("unreachable code after '{}'", keyword.lexeme),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn resolve(&mut self, stmt: &Stmt) -> Result<()> {
         match stmt {
-            Stmt::Block(stmts) => {
+            Stmt::Block(stmts) => self.resolve_block(stmts),
+            Stmt::Break(keyword) | Stmt::Continue(keyword) => {
+                if self.loop_depth == 0 {
+                    return Err(self.error(
+                        keyword,
+                        format!("Can't use '{}' outside of a loop", keyword.lexeme),
+                    ));
+                }
+                Ok(())
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Class;
+
+                self.declare(name, LocalKind::Other)?;
+                self.define(name);
+
+                if let Some(superclass) = superclass {
+                    if let ExprKind::Variable(superclass_name) = &superclass.kind {
+                        if superclass_name.lexeme == name.lexeme {
+                            return Err(self.error(
+                                superclass_name,
+                                String::from("A class can't inherit from itself"),
+                            ));
+                        }
+                    }
+
+                    self.current_class = ClassType::Subclass;
+                    self.resolve_expr(superclass)?;
+
+                    self.begin_scope();
+                    self.scopes
+                        .last_mut()
+                        .and_then(|s| s.add(*symbol::SUPER, Local::implicit(name.span)));
+                }
+
                 self.begin_scope();
-                for stmt in stmts {
-                    self.resolve(stmt)?;
+                self.scopes
+                    .last_mut()
+                    .and_then(|s| s.add(*symbol::THIS, Local::implicit(name.span)));
+
+                for method in methods {
+                    if let Stmt::Function { name, params, body } = method {
+                        let function_type = match name.lexeme.as_str() {
+                            "init" => FunctionType::Initializer,
+                            _ => FunctionType::Method,
+                        };
+                        self.resolve_function(params, body, function_type)?;
+                    }
+                }
+
+                self.end_scope()?;
+                if superclass.is_some() {
+                    self.end_scope()?;
                 }
-                self.end_scope();
+                self.current_class = enclosing_class;
                 Ok(())
             }
             Stmt::Expression(e) => {
@@ -38,9 +206,9 @@ impl<'a> Resolver<'a> {
                 Ok(())
             }
             Stmt::Function { name, params, body } => {
-                self.declare(name);
+                self.declare(name, LocalKind::Other)?;
                 self.define(name);
-                self.resolve_function(params, body)?;
+                self.resolve_function(params, body, FunctionType::Function)?;
                 Ok(())
             }
             Stmt::If {
@@ -59,71 +227,202 @@ impl<'a> Resolver<'a> {
                 self.resolve_expr(e)?;
                 Ok(())
             }
-            Stmt::Return { expr, .. } => {
+            Stmt::Return { keyword, expr } => {
+                if self.current_function == FunctionType::None {
+                    self.warn(
+                        keyword.span,
+                        String::from("'return' outside of a function ends the script"),
+                    )?;
+                }
                 if let Some(e) = expr {
+                    if self.current_function == FunctionType::Initializer {
+                        return Err(self.error(
+                            keyword,
+                            String::from("Can't return a value from an initializer"),
+                        ));
+                    }
                     self.resolve_expr(e)?;
                 }
                 Ok(())
             }
+            Stmt::Import { name, .. } => {
+                self.declare(name, LocalKind::Other)?;
+                self.define(name);
+                Ok(())
+            }
+            Stmt::Throw { value, .. } => self.resolve_expr(value),
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+                ..
+            } => {
+                self.resolve_block(body)?;
+                if let Some((name, handler)) = catch {
+                    // the caught value lives in the handler's scope
+                    self.begin_scope();
+                    self.declare(name, LocalKind::Other)?;
+                    self.define(name);
+                    self.resolve_stmts(handler)?;
+                    self.end_scope()?;
+                }
+                if let Some(finally) = finally {
+                    self.resolve_block(finally)?;
+                }
+                Ok(())
+            }
             Stmt::Var { name, initializer } => {
-                self.declare(name);
+                self.declare(name, LocalKind::Variable)?;
                 if let Some(init) = initializer {
                     self.resolve_expr(init)?;
                 }
                 self.define(name);
                 Ok(())
             }
-            Stmt::While { .. } => Ok(()),
-            _ => Err(RloxError::Unsupported(format!(
-                "unsupported stmt type: {:?}",
-                stmt
-            ))),
+            Stmt::While {
+                condition,
+                body,
+                increment,
+            } => {
+                self.resolve_expr(condition)?;
+                self.loop_depth += 1;
+                self.resolve(body)?;
+                self.loop_depth -= 1;
+                if let Some(incr) = increment {
+                    self.resolve_expr(incr)?;
+                }
+                Ok(())
+            }
         }
     }
 
     fn begin_scope(&mut self) {
-        self.scopes.push(HashMap::new());
+        self.scopes.push(Scope::default());
     }
 
-    fn declare(&mut self, name: &Token) {
+    /// Add `name` to the current scope, warning if it's already there or
+    /// hides a local from an enclosing one. Globals aren't tracked.
+    fn declare(&mut self, name: &Token, kind: LocalKind) -> Result<()> {
+        let Some(scope) = self.scopes.last() else {
+            return Ok(());
+        };
+
+        if let Some(previous) = scope.get(name.name()).copied() {
+            self.warn(
+                name.span,
+                format!(
+                    "'{}' is already declared in this scope, on line {}",
+                    name.lexeme, previous.span.line
+                ),
+            )?;
+            // the earlier one can't be used after this
+            self.check_used(name.name(), previous)?;
+        } else if let Some(outer) = self
+            .scopes
+            .iter()
+            .rev()
+            .skip(1)
+            .find_map(|s| s.get(name.name()))
+        {
+            let line = outer.span.line;
+            self.warn(
+                name.span,
+                format!(
+                    "'{}' shadows the local declared on line {}",
+                    name.lexeme, line
+                ),
+            )?;
+        }
+
+        let local = Local {
+            span: name.span,
+            kind,
+            defined: false,
+            used: false,
+            slot: 0,
+        };
         self.scopes
             .last_mut()
-            .and_then(|m| m.insert(name.lexeme.clone(), false));
+            .and_then(|s| s.add(name.name(), local));
+        Ok(())
     }
 
     fn define(&mut self, name: &Token) {
-        self.scopes
+        if let Some(local) = self
+            .scopes
             .last_mut()
-            .and_then(|m| m.insert(name.lexeme.clone(), true));
+            .and_then(|s| s.locals.get_mut(&name.name()))
+        {
+            local.defined = true;
+        }
     }
 
-    fn end_scope(&mut self) {
-        self.scopes.pop();
+    /// Leave the current scope, warning about anything in it which was
+    /// never read.
+    fn end_scope(&mut self) -> Result<()> {
+        let Some(scope) = self.scopes.pop() else {
+            return Ok(());
+        };
+
+        // in source order, rather than whatever order the map has
+        let mut locals: Vec<(Symbol, Local)> = scope.locals.into_iter().collect();
+        locals.sort_by_key(|(_, local)| local.span.offset);
+        for (name, local) in locals {
+            self.check_used(name, local)?;
+        }
+        Ok(())
+    }
+
+    fn check_used(&self, name: Symbol, local: Local) -> Result<()> {
+        // like rust, a leading '_' says it's unused on purpose
+        if local.used || name.as_str().starts_with('_') {
+            return Ok(());
+        }
+        match local.kind {
+            LocalKind::Variable => self.warn(local.span, format!("unused variable '{}'", name)),
+            LocalKind::Parameter => self.warn(local.span, format!("unused parameter '{}'", name)),
+            LocalKind::Other => Ok(()),
+        }
+    }
+
+    fn resolve_block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        self.begin_scope();
+        self.resolve_stmts(stmts)?;
+        self.end_scope()
     }
 
-    fn resolve_function(&mut self, params: &[Token], body: &[Stmt]) -> Result<()> {
+    fn resolve_function(
+        &mut self,
+        params: &[Token],
+        body: &[Stmt],
+        function_type: FunctionType,
+    ) -> Result<()> {
+        let enclosing_function = self.current_function;
+        self.current_function = function_type;
+        // a loop outside of the function can't be broken out of from inside it
+        let enclosing_loop_depth = self.loop_depth;
+        self.loop_depth = 0;
         self.begin_scope();
 
         for param in params {
-            self.declare(param);
+            self.declare(param, LocalKind::Parameter)?;
             self.define(param);
         }
 
-        for stmt in body {
-            self.resolve(stmt)?;
-        }
+        self.resolve_stmts(body)?;
 
-        self.end_scope();
+        self.end_scope()?;
+        self.current_function = enclosing_function;
+        self.loop_depth = enclosing_loop_depth;
         Ok(())
     }
 
     fn resolve_expr(&mut self, expr: &Expr) -> Result<()> {
-        use Expr::*;
-        match expr {
+        use ExprKind::*;
+        match &expr.kind {
             Assign(t, e) => {
                 self.resolve_expr(e)?;
-                self.resolve_local(expr, t)?;
-                Ok(())
+                self.resolve_local(expr.id, t)
             }
             Binary(l, _t, r) => {
                 self.resolve_expr(l)?;
@@ -139,49 +438,136 @@ impl<'a> Resolver<'a> {
                 }
                 Ok(())
             }
-            // Get(_e, _t) => Ok(RlValue::Nil),
+            Get(object, _name) => {
+                self.resolve_expr(object)?;
+                Ok(())
+            }
             Grouping(e) => {
                 self.resolve_expr(e)?;
                 Ok(())
             }
+            Index(object, _bracket, index) => {
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)?;
+                Ok(())
+            }
+            List(_bracket, elements) => {
+                for e in elements {
+                    self.resolve_expr(e)?;
+                }
+                Ok(())
+            }
+            Lambda(_keyword, params, body) => {
+                self.resolve_function(params, body, FunctionType::Function)
+            }
             Literal(..) => Ok(()),
             Logical(left, _operator, right) => {
                 self.resolve_expr(left)?;
                 self.resolve_expr(right)?;
                 Ok(())
             }
-            // Set(_l, _t, _r) => Ok(RlValue::Nil),
-            // Super(_t1, _t2) => Ok(RlValue::Nil),
-            // This(_t) => Ok(RlValue::Nil),
+            Set(object, _name, value) => {
+                self.resolve_expr(value)?;
+                self.resolve_expr(object)?;
+                Ok(())
+            }
+            SetIndex(object, _bracket, index, value) => {
+                self.resolve_expr(value)?;
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)?;
+                Ok(())
+            }
+            Map(_brace, entries) => {
+                for (key, value) in entries {
+                    self.resolve_expr(key)?;
+                    self.resolve_expr(value)?;
+                }
+                Ok(())
+            }
+            Super(keyword, _method) => {
+                match self.current_class {
+                    ClassType::Subclass => (),
+                    ClassType::None => {
+                        return Err(self.error(
+                            keyword,
+                            String::from("Can't use 'super' outside of a class"),
+                        ))
+                    }
+                    ClassType::Class => {
+                        return Err(self.error(
+                            keyword,
+                            String::from("Can't use 'super' in a class with no superclass"),
+                        ))
+                    }
+                }
+                self.resolve_local(expr.id, keyword)
+            }
+            This(t) => {
+                if self.current_class == ClassType::None {
+                    return Err(self.error(t, String::from("Can't use 'this' outside of a class")));
+                }
+                self.resolve_local(expr.id, t)
+            }
             Unary(_t, e) => {
                 self.resolve_expr(e)?;
                 Ok(())
             }
             Variable(t) => {
-                match self.scopes.last_mut().and_then(|s| s.get(&t.lexeme)) {
-                    Some(true) | None => (),
-                    Some(false) => {
-                        return Err(RloxError::ResolveError(format!(
-                            "Can't read local var in its own initializer, token: {:?}",
-                            t
-                        )))
+                match self.scopes.last().and_then(|s| s.get(t.name())) {
+                    Some(Local { defined: true, .. }) | None => (),
+                    Some(Local { defined: false, .. }) => {
+                        return Err(self.error(
+                            t,
+                            String::from("Can't read local var in its own initializer"),
+                        ))
                     }
                 };
 
-                self.resolve_local(expr, t)
+                self.resolve_local(expr.id, t)?;
+                self.mark_used(t);
+                Ok(())
             }
-            _ => Err(RloxError::ResolveError(format!(
-                "not resolving this type of expr: {:?}",
-                expr
-            ))),
         }
     }
 
-    fn resolve_local(&self, expr: &Expr, name: &Token) -> Result<()> {
-        for (i, scope) in self.scopes.iter().rev().enumerate() {
-            if scope.contains_key(&name.lexeme) {
-                let depth = (self.scopes.len() - 1 - i) as u32;
-                self.interpreter.resolve(expr, depth)?;
+    fn error(&self, token: &Token, message: String) -> RloxError {
+        RloxError::ResolveError {
+            message,
+            span: token.span,
+        }
+    }
+
+    /// Report a warning, or fail with it as an error if the reporter is
+    /// treating warnings as errors.
+    fn warn(&self, span: Span, message: String) -> Result<()> {
+        if self.error_reporter.warnings_as_errors() {
+            return Err(RloxError::ResolveError { message, span });
+        }
+        self.error_reporter.warning(span, &message);
+        Ok(())
+    }
+
+    fn mark_used(&mut self, name: &Token) {
+        if let Some(local) = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|s| s.locals.get_mut(&name.name()))
+        {
+            local.used = true;
+        }
+    }
+
+    fn resolve_local(&self, id: ExprId, name: &Token) -> Result<()> {
+        // walking the scopes innermost-first, so the index is the distance
+        // from the current scope to where the name is defined.
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if let Some(local) = scope.get(name.name()) {
+                let slot = Slot {
+                    depth: depth as u32,
+                    index: local.slot,
+                };
+                self.interpreter.resolve(id, slot)?;
                 return Ok(());
             }
         }
@@ -190,3 +576,106 @@ impl<'a> Resolver<'a> {
         Ok(())
     }
 }
+
+/// The keyword of a statement which always leaves the block it's in,
+/// making anything after it in the block unreachable.
+fn exit_keyword(stmt: &Stmt) -> Option<&Token> {
+    match stmt {
+        Stmt::Return { keyword, .. } | Stmt::Throw { keyword, .. } => Some(keyword),
+        Stmt::Break(keyword) | Stmt::Continue(keyword) => Some(keyword),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    // resolve `src` with warnings as errors, so the first one comes back
+    fn first_warning(src: &str) -> Result<Option<String>> {
+        let error_reporter = ErrorReporter::default();
+        error_reporter.set_warnings_as_errors(true);
+        let mut scanner = Scanner::new(src.to_string(), error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), error_reporter.clone()).parse()?;
+
+        let interpreter = Interpreter::new(error_reporter);
+        match Resolver::new(&interpreter).resolve_stmts(&stmts) {
+            Ok(()) => Ok(None),
+            Err(RloxError::ResolveError { message, .. }) => Ok(Some(message)),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[test]
+    fn unused_locals() -> Result<()> {
+        assert_eq!(
+            first_warning("{ var a = 1; }")?.as_deref(),
+            Some("unused variable 'a'")
+        );
+        assert_eq!(
+            first_warning("fun f(a, b) { return a; }")?.as_deref(),
+            Some("unused parameter 'b'")
+        );
+        // only assigning to it isn't using it
+        assert_eq!(
+            first_warning("{ var a; a = 1; }")?.as_deref(),
+            Some("unused variable 'a'")
+        );
+        // reads from a closure count, and '_' opts out
+        assert_eq!(
+            first_warning("fun f(_unused) { var a = 1; return fun() { return a; }; }")?,
+            None
+        );
+        // globals aren't checked
+        assert_eq!(first_warning("var a = 1;")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn shadowing_and_redeclaration() -> Result<()> {
+        assert_eq!(
+            first_warning("{ var a = 1;\n { var a = 2; print a; } print a; }")?.as_deref(),
+            Some("'a' shadows the local declared on line 1")
+        );
+        assert_eq!(
+            first_warning("fun f(a) { var a = 1; print a; }")?.as_deref(),
+            Some("'a' is already declared in this scope, on line 1")
+        );
+        // shadowing a global is fine
+        assert_eq!(first_warning("var a = 1; { var a = 2; print a; }")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn unreachable_code() -> Result<()> {
+        assert_eq!(
+            first_warning("fun f() { return 1; print 2; }")?.as_deref(),
+            Some("unreachable code after 'return'")
+        );
+        assert_eq!(
+            first_warning("while (true) { break; print 1; }")?.as_deref(),
+            Some("unreachable code after 'break'")
+        );
+        assert_eq!(
+            first_warning("return;")?.as_deref(),
+            Some("'return' outside of a function ends the script")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn warnings_dont_stop_the_script() -> Result<()> {
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new(String::from("{ var a = 1; }"), error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), error_reporter.clone()).parse()?;
+
+        let interpreter = Interpreter::new(error_reporter.clone());
+        Resolver::new(&interpreter).resolve_stmts(&stmts)?;
+        assert!(!error_reporter.had_error());
+        Ok(())
+    }
+}
@@ -1,99 +1,372 @@
 #![allow(dead_code)]
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
 
+#[cfg(not(feature = "nan-boxing"))]
+use crate::builtins::ScriptValue;
 use crate::callable::Callable;
+use crate::class::{Class, Instance};
 use crate::expr::LiteralValue;
+use crate::gc::{self, Trace};
+use crate::map::MapKey;
+use crate::module::Module;
 
 /// The result value and type of evaluating an expression.
-/// This first attempt is a basic enum tag [0], but
-/// it'd be nifty to build a nan box for the next generation.
+///
+/// By default it's `Unpacked`, a basic enum tag [0]. With the `nan-boxing`
+/// feature it's a `NanBox` instead, which packs the same values into 8
+/// bytes. Both have the same accessors, and constructors named after the
+/// variants, so only code looking inside a value with `match` needs to
+/// `unpack()` it first.
 ///
 /// [0] https://piotrduperas.com/posts/nan-boxing
+#[cfg(not(feature = "nan-boxing"))]
+pub type RlValue = Unpacked;
+#[cfg(feature = "nan-boxing")]
+pub type RlValue = crate::nanbox::NanBox;
+
+/// What an `RlValue` is, to match on.
 #[derive(Clone, Debug, Default)]
-pub enum RlValue {
+pub enum Unpacked {
     #[default]
     Nil,
     Boolean(bool),
     Double(f64),
-    String(String),
+    // shared, as strings are immutable and copying one shouldn't
+    // reallocate
+    String(Rc<str>),
     Callable(Callable),
+    Class(Rc<Class>),
+    Instance(Rc<RefCell<Instance>>),
+    // shared, so every copy of the value sees the same mutations
+    List(Rc<RefCell<Vec<RlValue>>>),
+    // shared in the same way as lists
+    Map(Rc<RefCell<HashMap<MapKey, RlValue>>>),
+    Module(Rc<Module>),
 }
 
-impl fmt::Display for RlValue {
+/// A list shared between `RlValue`s.
+pub type List = Rc<RefCell<Vec<RlValue>>>;
+
+/// A map shared between `RlValue`s.
+pub type Map = Rc<RefCell<HashMap<MapKey, RlValue>>>;
+
+impl fmt::Display for Unpacked {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            RlValue::Nil => write!(f, "nil"),
-            RlValue::Boolean(b) => write!(f, "{}", b),
-            RlValue::Double(d) => write!(f, "{:2}", d),
-            RlValue::String(ref s) => write!(f, "{}", s),
+            Unpacked::Nil => write!(f, "nil"),
+            Unpacked::Boolean(b) => write!(f, "{}", b),
+            Unpacked::Double(d) => write!(f, "{:2}", d),
+            Unpacked::String(ref s) => write!(f, "{}", s),
             // TODO: something better than this!
-            RlValue::Callable(ref c) => write!(f, "{}", c),
+            Unpacked::Callable(ref c) => write!(f, "{}", c),
+            Unpacked::Class(ref c) => write!(f, "{}", c),
+            Unpacked::Instance(ref i) => write!(f, "{}", i.borrow()),
+            Unpacked::List(_) | Unpacked::Map(_) => fmt_nested(self, f, &mut Vec::new()),
+            Unpacked::Module(ref m) => write!(f, "{}", m),
         }
     }
 }
 
-impl RlValue {
+/// Lists print like `[1, "two", [3]]`, maps like `{"a": 1, 2: [3]}`
+/// with their keys sorted. `seen` holds the lists and maps we're already
+/// inside of, so one which contains itself doesn't recurse forever.
+fn fmt_nested(value: &Unpacked, f: &mut fmt::Formatter, seen: &mut Vec<*const ()>) -> fmt::Result {
+    let ptr = match value {
+        Unpacked::List(l) => Rc::as_ptr(l) as *const (),
+        Unpacked::Map(m) => Rc::as_ptr(m) as *const (),
+        Unpacked::Double(d) => return write!(f, "{}", d),
+        Unpacked::String(s) => return write!(f, "{:?}", s),
+        value => return write!(f, "{}", value),
+    };
+    if seen.contains(&ptr) {
+        return match value {
+            Unpacked::List(_) => write!(f, "[...]"),
+            _ => write!(f, "{{...}}"),
+        };
+    }
+    seen.push(ptr);
+
+    match value {
+        Unpacked::List(l) => {
+            write!(f, "[")?;
+            for (i, v) in l.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                fmt_nested(&v.clone().unpack(), f, seen)?;
+            }
+            write!(f, "]")?;
+        }
+        Unpacked::Map(m) => {
+            let m = m.borrow();
+            let mut keys: Vec<&MapKey> = m.keys().collect();
+            keys.sort();
+
+            write!(f, "{{")?;
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: ", key)?;
+                fmt_nested(&m[key].clone().unpack(), f, seen)?;
+            }
+            write!(f, "}}")?;
+        }
+        _ => (),
+    }
+    seen.pop();
+    Ok(())
+}
+
+/// Lox equality: `nil` only equals `nil`, booleans, numbers and strings
+/// compare by value (numbers per IEEE 754, so `NaN != NaN`), everything
+/// else by identity. Values of different types are never equal.
+impl PartialEq for Unpacked {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Unpacked::Nil, Unpacked::Nil) => true,
+            (Unpacked::Boolean(a), Unpacked::Boolean(b)) => a == b,
+            (Unpacked::Double(a), Unpacked::Double(b)) => a == b,
+            (Unpacked::String(a), Unpacked::String(b)) => a == b,
+            (Unpacked::Callable(a), Unpacked::Callable(b)) => a == b,
+            (Unpacked::Class(a), Unpacked::Class(b)) => Rc::ptr_eq(a, b),
+            (Unpacked::Instance(a), Unpacked::Instance(b)) => Rc::ptr_eq(a, b),
+            (Unpacked::List(a), Unpacked::List(b)) => Rc::ptr_eq(a, b),
+            (Unpacked::Map(a), Unpacked::Map(b)) => Rc::ptr_eq(a, b),
+            (Unpacked::Module(a), Unpacked::Module(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Unpacked {
     pub fn is_nil(&self) -> bool {
-        matches!(self, RlValue::Nil)
+        matches!(self, Unpacked::Nil)
     }
 
     pub fn is_bool(&self) -> bool {
-        matches!(self, RlValue::Boolean(_))
+        matches!(self, Unpacked::Boolean(_))
     }
 
     pub fn is_numeric(&self) -> bool {
-        matches!(self, RlValue::Double(_))
+        matches!(self, Unpacked::Double(_))
     }
 
     pub fn is_string(&self) -> bool {
-        matches!(self, RlValue::String(_))
+        matches!(self, Unpacked::String(_))
+    }
+
+    /// The name of the value's type, as shown to script authors in errors.
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Unpacked::Nil => "nil",
+            Unpacked::Boolean(_) => "boolean",
+            Unpacked::Double(_) => "number",
+            Unpacked::String(_) => "string",
+            Unpacked::Callable(_) => "function",
+            Unpacked::Class(_) => "class",
+            Unpacked::Instance(_) => "instance",
+            Unpacked::List(_) => "list",
+            Unpacked::Map(_) => "map",
+            Unpacked::Module(_) => "module",
+        }
     }
 
     pub fn is_truthy(&self) -> bool {
         match *self {
-            RlValue::Nil => false,
-            RlValue::Boolean(b) => b,
+            Unpacked::Nil => false,
+            Unpacked::Boolean(b) => b,
             _ => true,
         }
     }
 
     pub fn as_numeric(&self) -> Option<f64> {
         match *self {
-            RlValue::Double(d) => Some(d),
+            Unpacked::Double(d) => Some(d),
             _ => None,
         }
     }
 
-    pub fn as_string(&self) -> Option<String> {
+    pub fn as_string(&self) -> Option<Rc<str>> {
         match *self {
-            RlValue::String(ref r) => Some(r.to_string()),
+            Unpacked::String(ref r) => Some(Rc::clone(r)),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<List> {
+        match *self {
+            Unpacked::List(ref l) => Some(Rc::clone(l)),
+            _ => None,
+        }
+    }
+
+    pub fn new_list(values: Vec<RlValue>) -> Self {
+        let list = Rc::new(RefCell::new(values));
+        gc::track(&list);
+        Unpacked::List(list)
+    }
+
+    pub fn as_map(&self) -> Option<Map> {
+        match *self {
+            Unpacked::Map(ref m) => Some(Rc::clone(m)),
+            _ => None,
+        }
+    }
+
+    pub fn new_map(entries: HashMap<MapKey, RlValue>) -> Self {
+        let map = Rc::new(RefCell::new(entries));
+        gc::track(&map);
+        Unpacked::Map(map)
+    }
+
+    /// The value as a map key, or `None` if it's of an unhashable type.
+    pub fn to_key(&self) -> Option<MapKey> {
+        match *self {
+            Unpacked::Nil => Some(MapKey::Nil),
+            Unpacked::Boolean(b) => Some(MapKey::Boolean(b)),
+            Unpacked::Double(d) => MapKey::number(d),
+            Unpacked::String(ref s) => Some(MapKey::String(Rc::clone(s))),
             _ => None,
         }
     }
 }
 
-impl From<LiteralValue> for RlValue {
+#[cfg(not(feature = "nan-boxing"))]
+impl Unpacked {
+    /// What the value is, to match on. Values are never packed without
+    /// the `nan-boxing` feature, so it's the value itself.
+    pub fn unpack(self) -> Unpacked {
+        self
+    }
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+impl ScriptValue for RlValue {
+    fn type_name(&self) -> &'static str {
+        RlValue::type_name(self)
+    }
+
+    fn number(n: f64) -> Self {
+        RlValue::Double(n)
+    }
+
+    fn boolean(b: bool) -> Self {
+        RlValue::Boolean(b)
+    }
+
+    fn string(s: &str) -> Self {
+        RlValue::String(Rc::from(s))
+    }
+
+    fn as_numeric(&self) -> Option<f64> {
+        RlValue::as_numeric(self)
+    }
+
+    fn as_string(&self) -> Option<Rc<str>> {
+        RlValue::as_string(self)
+    }
+
+    fn as_list(&self) -> Option<List> {
+        RlValue::as_list(self)
+    }
+
+    fn as_map(&self) -> Option<Map> {
+        RlValue::as_map(self)
+    }
+
+    fn to_key(&self) -> Option<MapKey> {
+        RlValue::to_key(self)
+    }
+
+    fn new_list(values: Vec<RlValue>) -> Self {
+        RlValue::new_list(values)
+    }
+
+    fn new_map(entries: HashMap<MapKey, RlValue>) -> Self {
+        RlValue::new_map(entries)
+    }
+}
+
+impl From<&MapKey> for Unpacked {
+    fn from(key: &MapKey) -> Self {
+        match key {
+            MapKey::Nil => Unpacked::Nil,
+            MapKey::Boolean(b) => Unpacked::Boolean(*b),
+            MapKey::Number(_) => Unpacked::Double(key.as_number().unwrap_or_default()),
+            MapKey::String(s) => Unpacked::String(Rc::clone(s)),
+        }
+    }
+}
+
+impl From<LiteralValue> for Unpacked {
     fn from(l: LiteralValue) -> Self {
         match l {
-            LiteralValue::Nil() => RlValue::Nil,
-            LiteralValue::Boolean(b) => RlValue::Boolean(b),
-            LiteralValue::Number(d) => RlValue::Double(d),
-            // TODO: can probably do something better than clone(), but it
-            // works for this PL exercise.
-            LiteralValue::String(ref s) => RlValue::String(s.clone()),
+            LiteralValue::Nil() => Unpacked::Nil,
+            LiteralValue::Boolean(b) => Unpacked::Boolean(b),
+            LiteralValue::Number(d) => Unpacked::Double(d),
+            LiteralValue::String(ref s) => Unpacked::String(Rc::clone(s)),
         }
     }
 }
 
-impl From<&LiteralValue> for RlValue {
+impl From<&LiteralValue> for Unpacked {
     fn from(l: &LiteralValue) -> Self {
         match *l {
-            LiteralValue::Nil() => RlValue::Nil,
-            LiteralValue::Boolean(b) => RlValue::Boolean(b),
-            LiteralValue::Number(d) => RlValue::Double(d),
-            // TODO: can probably do something better than clone(), but it
-            // works for this PL exercise.
-            LiteralValue::String(ref s) => RlValue::String(s.clone()),
+            LiteralValue::Nil() => Unpacked::Nil,
+            LiteralValue::Boolean(b) => Unpacked::Boolean(b),
+            LiteralValue::Number(d) => Unpacked::Double(d),
+            LiteralValue::String(ref s) => Unpacked::String(Rc::clone(s)),
+        }
+    }
+}
+
+impl Trace for Unpacked {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match self {
+            Unpacked::Callable(c) => c.trace(visit),
+            Unpacked::Class(c) => visit(Rc::as_ptr(c) as *const ()),
+            Unpacked::Instance(i) => visit(Rc::as_ptr(i) as *const ()),
+            Unpacked::List(l) => visit(Rc::as_ptr(l) as *const ()),
+            Unpacked::Map(m) => visit(Rc::as_ptr(m) as *const ()),
+            // modules are cached by the interpreter for good, so aren't
+            // tracked, and nor are the plain values.
+            _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builtins::BuiltInFunction;
+
+    #[test]
+    fn equality_by_value() {
+        assert_eq!(RlValue::Nil, RlValue::Nil);
+        assert_eq!(RlValue::Boolean(true), RlValue::Boolean(true));
+        assert_ne!(RlValue::Boolean(true), RlValue::Boolean(false));
+        assert_eq!(RlValue::Double(1.5), RlValue::Double(1.5));
+        assert_ne!(RlValue::Double(f64::NAN), RlValue::Double(f64::NAN));
+        assert_eq!(
+            RlValue::String(Rc::from("a")),
+            RlValue::String(Rc::from("a"))
+        );
+    }
+
+    #[test]
+    fn mixed_types_never_equal() {
+        assert_ne!(RlValue::Nil, RlValue::Boolean(false));
+        assert_ne!(RlValue::Double(0.0), RlValue::Boolean(false));
+        assert_ne!(RlValue::String(Rc::from("1")), RlValue::Double(1.0));
+    }
+
+    #[test]
+    fn callables_by_identity() {
+        let clock = RlValue::Callable(Callable::BuiltIn(BuiltInFunction::Clock));
+        assert_eq!(clock, clock.clone());
+    }
+}
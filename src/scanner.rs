@@ -1,25 +1,34 @@
-use crate::token::{Literal, Token, TokenType};
+use crate::token::{Literal, Span, Token, TokenType};
 use crate::ErrorReporter;
 
 use std::io::Result;
+use std::rc::Rc;
 
 use phf::phf_map;
 
 static KEYWORDS: phf::Map<&'static str, TokenType> = phf_map! {
     "and" => TokenType::And,
+    "as" => TokenType::As,
+    "break" => TokenType::Break,
+    "catch" => TokenType::Catch,
     "class" => TokenType::Class,
+    "continue" => TokenType::Continue,
     "else" => TokenType::Else,
     "false" => TokenType::False,
+    "finally" => TokenType::Finally,
     "for" => TokenType::For,
     "fun" => TokenType::Fun,
     "if" => TokenType::If,
+    "import" => TokenType::Import,
     "nil" => TokenType::Nil,
     "or" => TokenType::Or,
     "print" => TokenType::Print,
     "return" => TokenType::Return,
     "super" => TokenType::Super,
     "this" => TokenType::This,
+    "throw" => TokenType::Throw,
     "true" => TokenType::True,
+    "try" => TokenType::Try,
     "var" => TokenType::Var,
     "while" => TokenType::While,
 };
@@ -32,10 +41,24 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: u32,
+
+    // `start` and `current` index chars, these track the same
+    // positions as byte offsets into the source.
+    start_byte: usize,
+    current_byte: usize,
+
+    // char index of the first char on the current line, for columns.
+    line_start: usize,
+
+    // where the current lexeme began, as it may span lines (strings).
+    start_line: u32,
+    start_column: u32,
 }
 
 impl Scanner {
     pub fn new(source: String, error_reporter: ErrorReporter) -> Self {
+        // hand the source to the reporter so it can show the offending lines
+        error_reporter.set_source(&source);
         Scanner {
             source,
             tokens: Vec::new(),
@@ -43,6 +66,11 @@ impl Scanner {
             start: 0_usize,
             current: 0_usize,
             line: 1_u32,
+            start_byte: 0_usize,
+            current_byte: 0_usize,
+            line_start: 0_usize,
+            start_line: 1_u32,
+            start_column: 1_u32,
         }
     }
 
@@ -58,12 +86,17 @@ impl Scanner {
         while !self.at_end(&src) {
             // we're at the start of the next lexeme
             self.start = self.current;
+            self.start_byte = self.current_byte;
+            self.start_line = self.line;
+            self.start_column = (self.start - self.line_start + 1) as u32;
             self.scan_token(&src);
         }
 
         // we're at the end, add the EOF
+        let column = (self.current - self.line_start + 1) as u32;
+        let span = Span::new(self.current_byte, 0, self.line, column);
         self.tokens
-            .push(Token::empty_token(TokenType::Eof, self.line));
+            .push(Token::empty_token(TokenType::Eof, self.line).with_span(span));
 
         // println!("**** tokens start ****");
         // for t in &self.tokens {
@@ -84,6 +117,9 @@ impl Scanner {
             ')' => self.add_token(RightParen, src),
             '{' => self.add_token(LeftBrace, src),
             '}' => self.add_token(RightBrace, src),
+            '[' => self.add_token(LeftBracket, src),
+            ']' => self.add_token(RightBracket, src),
+            ':' => self.add_token(Colon, src),
             ',' => self.add_token(Comma, src),
             '.' => self.add_token(Dot, src),
             '-' => self.add_token(Minus, src),
@@ -140,36 +176,40 @@ impl Scanner {
             ' ' => {}
             '\r' => {}
             '\t' => {}
-            '\n' => self.line += 1,
+            '\n' => self.newline(),
 
             // now we're onto handling literals
             '"' => self.string_literal(src),
             _ if c.is_ascii_digit() => self.number_literal(src),
             _ if Self::is_identifier_char(c) => self.identifier(src),
 
-            _ => self
-                .error_reporter
-                .error(self.line, format!("unexpected character: {:?}", c).as_str()),
+            _ => self.error_reporter.report_span(
+                self.lexeme_span(),
+                "",
+                format!("unexpected character: {:?}", c).as_str(),
+            ),
         }
     }
 
     /// Helper function to push the current index pointer into source along.
     fn string_literal(&mut self, src: &[char]) {
         while self.peek(src) != '"' && !self.at_end(src) {
-            if self.peek(src) == '\n' {
-                self.line += 1;
-            }
             self.advance(src);
+            if src[self.current - 1] == '\n' {
+                self.newline();
+            }
         }
 
         if self.at_end(src) {
-            self.error_reporter.error(self.line, "unterminated string");
+            self.error_reporter
+                .report_span(self.lexeme_span(), "", "unterminated string");
+            return;
         }
 
         // account for the closing '"'
         self.advance(src);
         let s: String = src[self.start + 1..self.current - 1].iter().collect();
-        self.add_literal_token(TokenType::String, Literal::StringLiteral(s), src);
+        self.add_literal_token(TokenType::String, Literal::StringLiteral(Rc::from(s)), src);
     }
 
     fn number_literal(&mut self, src: &[char]) {
@@ -213,15 +253,32 @@ impl Scanner {
     }
 
     fn add_token(&mut self, token_type: TokenType, src: &[char]) {
-        let lexeme = src[self.start..self.current].iter().collect();
+        let lexeme: String = src[self.start..self.current].iter().collect();
+        let span = self.lexeme_span();
         self.tokens
-            .push(Token::simple_token(token_type, lexeme, self.line));
+            .push(Token::simple_token(token_type, &lexeme, self.line).with_span(span));
     }
 
     fn add_literal_token(&mut self, token_type: TokenType, literal: Literal, src: &[char]) {
-        let lexeme = src[self.start..self.current].iter().collect();
+        let lexeme: String = src[self.start..self.current].iter().collect();
+        let span = self.lexeme_span();
         self.tokens
-            .push(Token::literal_token(token_type, lexeme, literal, self.line));
+            .push(Token::literal_token(token_type, &lexeme, literal, self.line).with_span(span));
+    }
+
+    /// The location of the lexeme currently being scanned.
+    fn lexeme_span(&self) -> Span {
+        Span::new(
+            self.start_byte,
+            self.current_byte - self.start_byte,
+            self.start_line,
+            self.start_column,
+        )
+    }
+
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
     }
 
     fn is_identifier_char(c: char) -> bool {
@@ -232,6 +289,7 @@ impl Scanner {
     fn advance(&mut self, src: &[char]) -> char {
         let c = src[self.current];
         self.current += 1;
+        self.current_byte += c.len_utf8();
         c
     }
 
@@ -259,7 +317,7 @@ impl Scanner {
         if src[self.current] != expected {
             return false;
         }
-        self.current += 1;
+        self.advance(src);
         true
     }
 
@@ -1,12 +1,20 @@
 #![allow(dead_code)]
 
 use crate::expr::Expr;
-use crate::token::Token;
+use crate::token::{Span, Token};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Stmt {
     Block(Vec<Stmt>),
-    Class,
+    Break(Token),
+    Class {
+        name: Token,
+        // always an `Expr::Variable`, if present
+        superclass: Option<Expr>,
+        // each of these is a `Stmt::Function`
+        methods: Vec<Stmt>,
+    },
+    Continue(Token),
     Expression(Expr),
     Function {
         name: Token,
@@ -18,6 +26,12 @@ pub enum Stmt {
         then_branch: Box<Stmt>,
         else_branch: Option<Box<Stmt>>,
     },
+    Import {
+        keyword: Token,
+        // as written, relative to the importing file
+        path: String,
+        name: Token,
+    },
     Print(Expr),
     Return {
         keyword: Token,
@@ -26,6 +40,17 @@ pub enum Stmt {
         // public void doIt(String, int, ...) {}
         expr: Option<Expr>,
     },
+    Throw {
+        keyword: Token,
+        value: Expr,
+    },
+    Try {
+        keyword: Token,
+        body: Vec<Stmt>,
+        // the name the caught value is bound to, and the handler
+        catch: Option<(Token, Vec<Stmt>)>,
+        finally: Option<Vec<Stmt>>,
+    },
     Var {
         name: Token,
         initializer: Option<Expr>,
@@ -33,5 +58,80 @@ pub enum Stmt {
     While {
         condition: Expr,
         body: Box<Stmt>,
+        // only set when desugaring a `for` loop. kept separate from the
+        // body so that it still runs after a `continue`.
+        increment: Option<Expr>,
     },
 }
+
+impl Stmt {
+    /// The source the statement covers, as best we know from the
+    /// tokens and expressions it kept hold of. `None` for an empty block.
+    pub(crate) fn span(&self) -> Option<Span> {
+        match self {
+            Stmt::Block(stmts) => {
+                let mut spans = stmts.iter().filter_map(|s| s.span());
+                let first = spans.next()?;
+                Some(spans.fold(first, Span::to))
+            }
+            Stmt::Break(keyword) | Stmt::Continue(keyword) => Some(keyword.span),
+            Stmt::Class { name, methods, .. } => Some(
+                methods
+                    .iter()
+                    .filter_map(|m| m.span())
+                    .fold(name.span, Span::to),
+            ),
+            Stmt::Expression(e) | Stmt::Print(e) => Some(e.span()),
+            Stmt::Function { name, body, .. } => Some(
+                body.iter()
+                    .filter_map(|s| s.span())
+                    .fold(name.span, Span::to),
+            ),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let mut span = condition.span();
+                if let Some(s) = then_branch.span() {
+                    span = span.to(s);
+                }
+                if let Some(s) = else_branch.as_ref().and_then(|e| e.span()) {
+                    span = span.to(s);
+                }
+                Some(span)
+            }
+            Stmt::Import { keyword, name, .. } => Some(keyword.span.to(name.span)),
+            Stmt::Return { keyword, expr } => Some(match expr {
+                Some(e) => keyword.span.to(e.span()),
+                None => keyword.span,
+            }),
+            Stmt::Throw { keyword, value } => Some(keyword.span.to(value.span())),
+            Stmt::Try {
+                keyword,
+                body,
+                catch,
+                finally,
+            } => {
+                let handler = catch.iter().flat_map(|(_, stmts)| stmts);
+                Some(
+                    body.iter()
+                        .chain(handler)
+                        .chain(finally.iter().flatten())
+                        .filter_map(|s| s.span())
+                        .fold(keyword.span, Span::to),
+                )
+            }
+            Stmt::Var { name, initializer } => Some(match initializer {
+                Some(e) => name.span.to(e.span()),
+                None => name.span,
+            }),
+            Stmt::While {
+                condition, body, ..
+            } => Some(match body.span() {
+                Some(s) => condition.span().to(s),
+                None => condition.span(),
+            }),
+        }
+    }
+}
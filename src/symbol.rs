@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ptr;
+use std::sync::{LazyLock, Mutex};
+
+/// An interned string, for identifiers, keywords and operators. Each
+/// distinct string is stored once, for good, so a `Symbol` is just a pointer
+/// to it: copying one is free, and comparing or hashing them doesn't look at
+/// the text at all.
+///
+/// The interner is global, so symbols mean the same thing on every thread
+/// and in every interpreter. It never gives memory back, so it's only for
+/// the names in a program's source (and those a host defines), never for
+/// literals or any other text there's no end to.
+#[derive(Clone, Copy)]
+pub struct Symbol(&'static str);
+
+static INTERNER: LazyLock<Mutex<HashSet<&'static str>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+// the names the interpreter looks up itself, so it doesn't have to
+// intern them again every time.
+pub(crate) static INIT: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern("init"));
+pub(crate) static LAMBDA: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern("lambda"));
+pub(crate) static SUPER: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern("super"));
+pub(crate) static THIS: LazyLock<Symbol> = LazyLock::new(|| Symbol::intern("this"));
+
+impl Symbol {
+    pub fn intern(s: &str) -> Symbol {
+        let mut interner = INTERNER.lock().unwrap_or_else(|e| e.into_inner());
+        match interner.get(s) {
+            Some(&s) => Symbol(s),
+            None => {
+                let s: &'static str = Box::leak(Box::from(s));
+                interner.insert(s);
+                Symbol(s)
+            }
+        }
+    }
+
+    /// The symbol for `s`, only if it's been interned already. Looking a
+    /// name up this way never adds to the interner, and a name nothing has
+    /// interned can't be defined anywhere anyway.
+    pub fn lookup(s: &str) -> Option<Symbol> {
+        let interner = INTERNER.lock().unwrap_or_else(|e| e.into_inner());
+        interner.get(s).map(|&s| Symbol(s))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        self.0
+    }
+}
+
+// interned strings are only equal if they're the same string
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_ptr().hash(state)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(s: &str) -> Self {
+        Symbol::intern(s)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interned_once() {
+        let a = Symbol::intern("counter");
+        let b = Symbol::intern(&String::from("counter"));
+        assert_eq!(a, b);
+        assert!(ptr::eq(a.as_str(), b.as_str()));
+        assert_ne!(a, Symbol::intern("count"));
+        assert_eq!(a, "counter");
+        assert_eq!(a.to_string(), "counter");
+
+        assert_eq!(Symbol::lookup("counter"), Some(a));
+        // and looking a name up doesn't intern it
+        assert_eq!(Symbol::lookup("never interned anywhere"), None);
+        assert_eq!(Symbol::lookup("never interned anywhere"), None);
+    }
+}
@@ -2,8 +2,11 @@
 use std::{
     fmt::{Display, Formatter, Result},
     hash::{Hash, Hasher},
+    rc::Rc,
 };
 
+use crate::symbol::Symbol;
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum TokenType {
     // single character tokens
@@ -11,6 +14,9 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
@@ -36,19 +42,27 @@ pub enum TokenType {
 
     // keywords
     And,
+    As,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     Fun,
     For,
     If,
+    Import,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
@@ -57,7 +71,7 @@ pub enum TokenType {
 
 #[derive(Clone, Debug)]
 pub enum Literal {
-    StringLiteral(String),
+    StringLiteral(Rc<str>),
     NumberLiteral(f64),
 }
 
@@ -98,44 +112,158 @@ impl Display for Literal {
     }
 }
 
+/// Where a piece of syntax lives in the source: a byte range, plus
+/// the (1-based) line and column it starts at.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Span {
+    pub(crate) offset: usize,
+    pub(crate) len: usize,
+    pub(crate) line: u32,
+    pub(crate) column: u32,
+}
+
+impl Span {
+    pub(crate) fn new(offset: usize, len: usize, line: u32, column: u32) -> Self {
+        Span {
+            offset,
+            len,
+            line,
+            column,
+        }
+    }
+
+    /// A span which only knows it's line, for synthesized tokens.
+    pub(crate) fn from_line(line: u32) -> Self {
+        Span {
+            line,
+            ..Default::default()
+        }
+    }
+
+    /// The smallest span covering both `self` and `other`.
+    pub(crate) fn to(self, other: Span) -> Span {
+        // a synthesized span has nothing to cover.
+        if other.column == 0 {
+            return self;
+        }
+        if self.column == 0 {
+            return other;
+        }
+        let (first, last) = match self.offset <= other.offset {
+            true => (self, other),
+            false => (other, self),
+        };
+        let end = (first.offset + first.len).max(last.offset + last.len);
+        Span {
+            offset: first.offset,
+            len: end - first.offset,
+            line: first.line,
+            column: first.column,
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.column {
+            0 => write!(f, "line {}", self.line),
+            column => write!(f, "line {}:{}", self.line, column),
+        }
+    }
+}
+
+/// The text of a token. Identifiers, keywords and operators are interned,
+/// as the interpreter looks names up all the time and there are only so
+/// many of them in a program. String and number literals keep their own
+/// copy, so a host running scripts for a long time doesn't hang on to
+/// every distinct one it's ever seen.
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+pub(crate) enum Lexeme {
+    Name(Symbol),
+    Literal(Rc<str>),
+}
+
+impl Lexeme {
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Lexeme::Name(name) => name.as_str(),
+            Lexeme::Literal(text) => text,
+        }
+    }
+}
+
+impl Display for Lexeme {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub struct Token {
     pub(crate) token_type: TokenType,
-    pub(crate) lexeme: String,
+    pub(crate) lexeme: Lexeme,
     pub(crate) literal: Option<Literal>,
-    line: u32,
+    pub(crate) line: u32,
+    pub(crate) span: Span,
 }
 
 impl Token {
     pub(crate) fn empty_token(token_type: TokenType, line: u32) -> Self {
         Token {
             token_type,
-            lexeme: "".to_string(),
+            lexeme: Lexeme::Name(Symbol::intern("")),
             literal: None,
             line,
+            span: Span::from_line(line),
         }
     }
 
-    pub(crate) fn simple_token(token_type: TokenType, lexeme: String, line: u32) -> Self {
+    pub(crate) fn simple_token(token_type: TokenType, lexeme: &str, line: u32) -> Self {
         Token {
             token_type,
-            lexeme,
+            lexeme: Lexeme::Name(Symbol::intern(lexeme)),
             literal: None,
             line,
+            span: Span::from_line(line),
         }
     }
 
     pub(crate) fn literal_token(
         token_type: TokenType,
-        lexeme: String,
+        lexeme: &str,
         literal: Literal,
         line: u32,
     ) -> Self {
         Token {
             token_type,
-            lexeme,
+            lexeme: Lexeme::Literal(Rc::from(lexeme)),
             literal: Some(literal),
             line,
+            span: Span::from_line(line),
+        }
+    }
+
+    /// The source text of the token.
+    pub fn lexeme(&self) -> &str {
+        self.lexeme.as_str()
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The interned name of an identifier (or keyword, or operator).
+    pub(crate) fn name(&self) -> Symbol {
+        match &self.lexeme {
+            Lexeme::Name(name) => *name,
+            Lexeme::Literal(_) => unreachable!("a literal token doesn't name anything"),
         }
     }
+
+    /// Attach the full source location, as found by the `Scanner`.
+    pub(crate) fn with_span(mut self, span: Span) -> Self {
+        self.line = span.line;
+        self.span = span;
+        self
+    }
 }
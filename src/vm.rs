@@ -0,0 +1,1675 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::builtins::{self, BuiltInFunction, ScriptValue};
+use crate::compiler::{Compiler, Function, OpCode};
+use crate::error::{Result, RloxError, TraceFrame};
+use crate::gc::{self, Clear, Collect, Trace};
+use crate::interpreter::Interpreter;
+use crate::interrupt::Interrupt;
+use crate::map::MapKey;
+use crate::module::{self, Import, Modules};
+use crate::parser::Parser;
+use crate::resolver::Resolver;
+use crate::rlvalue::RlValue;
+use crate::scanner::Scanner;
+use crate::token::Span;
+use crate::{ErrorReporter, DEFAULT_MAX_CALL_DEPTH};
+
+/// How many call frames to make room for up front.
+const FRAMES_MAX: usize = 256;
+
+/// The global variables of a script, or of a module it imported. Each
+/// closure holds on to the globals of the file it was defined in.
+type Globals = Rc<RefCell<HashMap<Rc<str>, Value>>>;
+
+/// A value on the `Vm`'s stack. This mirrors `RlValue`, but with the
+/// bytecode backend's own runtime objects for functions and classes.
+#[derive(Clone, Debug, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Double(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+    Native(Rc<ObjNative>),
+    Closure(Rc<ObjClosure>),
+    Class(Rc<ObjClass>),
+    Instance(Rc<RefCell<ObjInstance>>),
+    BoundMethod(Rc<ObjBoundMethod>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<HashMap<MapKey, Value>>>),
+    Module(Rc<ObjModule>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            // keep the output identical to the tree-walker's `RlValue`
+            Value::Double(d) => write!(f, "{:2}", d),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(func) => write!(f, "{}", func),
+            Value::Native(n) => write!(f, "<native fn {}>", n.function.name()),
+            Value::Closure(c) => write!(f, "{}", c.function),
+            Value::Class(c) => write!(f, "{}", c.name),
+            Value::Instance(i) => write!(f, "{} instance", i.borrow().class.name),
+            Value::BoundMethod(b) => write!(f, "{}", b.method.function),
+            Value::List(_) | Value::Map(_) => fmt_nested(self, f, &mut Vec::new()),
+            Value::Module(m) => write!(f, "<module {}>", m.name),
+        }
+    }
+}
+
+/// What an uncaught `throw` of `value` shows. The error can only hold the
+/// tree-walker's values, so anything but nil, booleans, numbers and strings
+/// is shown as it's text.
+fn thrown_value(value: &Value) -> RlValue {
+    match value {
+        Value::Nil => RlValue::Nil,
+        Value::Boolean(b) => RlValue::Boolean(*b),
+        Value::Double(d) => RlValue::Double(*d),
+        Value::String(s) => RlValue::String(Rc::clone(s)),
+        v => RlValue::String(Rc::from(v.to_string())),
+    }
+}
+
+/// Same as the tree-walker's lists and maps: `[1, "two", {3: 4}]`,
+/// with `[...]` or `{...}` for one found inside itself.
+fn fmt_nested(value: &Value, f: &mut fmt::Formatter, seen: &mut Vec<*const ()>) -> fmt::Result {
+    let ptr = match value {
+        Value::List(l) => Rc::as_ptr(l) as *const (),
+        Value::Map(m) => Rc::as_ptr(m) as *const (),
+        Value::Double(d) => return write!(f, "{}", d),
+        Value::String(s) => return write!(f, "{:?}", s),
+        value => return write!(f, "{}", value),
+    };
+    if seen.contains(&ptr) {
+        return match value {
+            Value::List(_) => write!(f, "[...]"),
+            _ => write!(f, "{{...}}"),
+        };
+    }
+    seen.push(ptr);
+
+    match value {
+        Value::List(l) => {
+            write!(f, "[")?;
+            for (i, v) in l.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                fmt_nested(v, f, seen)?;
+            }
+            write!(f, "]")?;
+        }
+        Value::Map(m) => {
+            let m = m.borrow();
+            let mut keys: Vec<&MapKey> = m.keys().collect();
+            keys.sort();
+
+            write!(f, "{{")?;
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: ", key)?;
+                fmt_nested(&m[key], f, seen)?;
+            }
+            write!(f, "}}")?;
+        }
+        _ => (),
+    }
+    seen.pop();
+    Ok(())
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        match *self {
+            Value::Nil => false,
+            Value::Boolean(b) => b,
+            _ => true,
+        }
+    }
+
+    /// Lox equality: primitives compare by value, heap objects by identity.
+    fn equals(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Double(a), Value::Double(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (Value::Module(a), Value::Module(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl ScriptValue for Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Double(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Native(_) | Value::Closure(_) => "function",
+            Value::BoundMethod(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Module(_) => "module",
+        }
+    }
+
+    fn number(n: f64) -> Self {
+        Value::Double(n)
+    }
+
+    fn boolean(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+
+    fn string(s: &str) -> Self {
+        Value::String(Rc::from(s))
+    }
+
+    fn as_numeric(&self) -> Option<f64> {
+        match self {
+            Value::Double(d) => Some(*d),
+            _ => None,
+        }
+    }
+
+    fn as_string(&self) -> Option<Rc<str>> {
+        match self {
+            Value::String(s) => Some(Rc::clone(s)),
+            _ => None,
+        }
+    }
+
+    fn as_list(&self) -> Option<Rc<RefCell<Vec<Value>>>> {
+        match self {
+            Value::List(l) => Some(Rc::clone(l)),
+            _ => None,
+        }
+    }
+
+    fn as_map(&self) -> Option<Rc<RefCell<HashMap<MapKey, Value>>>> {
+        match self {
+            Value::Map(m) => Some(Rc::clone(m)),
+            _ => None,
+        }
+    }
+
+    fn new_list(values: Vec<Value>) -> Value {
+        let list = Rc::new(RefCell::new(values));
+        gc::track(&list);
+        Value::List(list)
+    }
+
+    fn new_map(entries: HashMap<MapKey, Value>) -> Value {
+        let map = Rc::new(RefCell::new(entries));
+        gc::track(&map);
+        Value::Map(map)
+    }
+
+    fn to_key(&self) -> Option<MapKey> {
+        match self {
+            Value::Nil => Some(MapKey::Nil),
+            Value::Boolean(b) => Some(MapKey::Boolean(*b)),
+            Value::Double(d) => MapKey::number(*d),
+            Value::String(s) => Some(MapKey::String(Rc::clone(s))),
+            _ => None,
+        }
+    }
+}
+
+impl From<&MapKey> for Value {
+    fn from(key: &MapKey) -> Self {
+        match key {
+            MapKey::Nil => Value::Nil,
+            MapKey::Boolean(b) => Value::Boolean(*b),
+            MapKey::Number(_) => Value::Double(key.as_number().unwrap_or_default()),
+            MapKey::String(s) => Value::String(Rc::clone(s)),
+        }
+    }
+}
+
+/// One of the builtins, the only native functions the `Vm` has so far.
+pub struct ObjNative {
+    function: BuiltInFunction,
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<native fn {}>", self.function.name())
+    }
+}
+
+/// A captured variable. While the variable is still live on the stack
+/// the upvalue points at it's slot; once the variable goes out of scope
+/// the value is moved in to the upvalue itself.
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+pub struct ObjClosure {
+    function: Rc<Function>,
+    upvalues: Vec<Rc<RefCell<ObjUpvalue>>>,
+    globals: Globals,
+}
+
+// the globals usually hold the closure itself, so leave them out
+impl fmt::Debug for ObjClosure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ObjClosure")
+            .field("function", &self.function)
+            .field("upvalues", &self.upvalues)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    name: Rc<str>,
+    methods: RefCell<HashMap<Rc<str>, Rc<ObjClosure>>>,
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    class: Rc<ObjClass>,
+    fields: HashMap<Rc<str>, Value>,
+}
+
+/// A file brought in with `import "path" as name;`, the bytecode
+/// backend's `Module`.
+pub struct ObjModule {
+    // the file name, without the directory or extension
+    name: String,
+    globals: Globals,
+}
+
+impl fmt::Debug for ObjModule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ObjModule")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    receiver: Value,
+    method: Rc<ObjClosure>,
+}
+
+impl Trace for Value {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        match self {
+            Value::Closure(c) => visit(Rc::as_ptr(c) as *const ()),
+            Value::Class(c) => visit(Rc::as_ptr(c) as *const ()),
+            Value::Instance(i) => visit(Rc::as_ptr(i) as *const ()),
+            Value::BoundMethod(b) => visit(Rc::as_ptr(b) as *const ()),
+            Value::List(l) => visit(Rc::as_ptr(l) as *const ()),
+            Value::Map(m) => visit(Rc::as_ptr(m) as *const ()),
+            // functions, natives and modules can't be part of a cycle
+            // except through the globals, which the `Vm` clears itself.
+            _ => (),
+        }
+    }
+}
+
+impl Trace for ObjUpvalue {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        if let ObjUpvalue::Closed(value) = self {
+            value.trace(visit);
+        }
+    }
+}
+
+impl Clear for ObjUpvalue {
+    type Contents = ObjUpvalue;
+
+    fn take_all(&mut self) -> ObjUpvalue {
+        std::mem::replace(self, ObjUpvalue::Closed(Value::Nil))
+    }
+}
+
+impl Trace for ObjInstance {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) {
+        visit(Rc::as_ptr(&self.class) as *const ());
+        self.fields.values().for_each(|v| v.trace(visit));
+    }
+
+    fn heap_size(&self) -> usize {
+        let field = std::mem::size_of::<Rc<str>>() + std::mem::size_of::<Value>();
+        std::mem::size_of::<Self>() + self.fields.capacity() * field
+    }
+}
+
+impl Clear for ObjInstance {
+    type Contents = HashMap<Rc<str>, Value>;
+
+    fn take_all(&mut self) -> Self::Contents {
+        std::mem::take(&mut self.fields)
+    }
+}
+
+// closures, classes and bound methods only point at things which can
+// be cleared, so they're never cleared themselves.
+impl Collect for ObjClosure {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        for upvalue in &self.upvalues {
+            visit(Rc::as_ptr(upvalue) as *const ());
+        }
+        true
+    }
+
+    fn heap_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.upvalues.capacity() * std::mem::size_of::<Rc<RefCell<ObjUpvalue>>>()
+    }
+}
+
+impl Collect for ObjClass {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        match self.methods.try_borrow() {
+            Ok(methods) => {
+                for method in methods.values() {
+                    visit(Rc::as_ptr(method) as *const ());
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        let method = std::mem::size_of::<Rc<str>>() + std::mem::size_of::<Rc<ObjClosure>>();
+        let methods = self.methods.try_borrow().map(|m| m.capacity());
+        std::mem::size_of::<Self>() + methods.unwrap_or_default() * method
+    }
+}
+
+impl Collect for ObjBoundMethod {
+    fn trace(&self, visit: &mut dyn FnMut(*const ())) -> bool {
+        self.receiver.trace(visit);
+        visit(Rc::as_ptr(&self.method) as *const ());
+        true
+    }
+
+    fn heap_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+struct CallFrame {
+    closure: Rc<ObjClosure>,
+    ip: usize,
+    // index of the frame's slot zero on the value stack
+    slots: usize,
+}
+
+/// Where an error unwinds to, set up by a `try`.
+struct Handler {
+    // how many frames there were when the try started, it carries on
+    // in the last of them.
+    frames: usize,
+    stack: usize,
+    target: usize,
+    finally: bool,
+}
+
+/// An error caught by a finally handler, to carry on unwinding once the
+/// finally block has run.
+struct Unwinding {
+    // the stack slot of the placeholder the finally block runs above
+    slot: usize,
+    error: RloxError,
+    thrown: Option<Value>,
+    trace: Vec<TraceFrame>,
+    // the line the try's own frame had got to
+    line: u32,
+}
+
+/// A stack-based virtual machine which executes the bytecode produced
+/// by the `Compiler`. Globals live on in the `Vm` between calls to
+/// `interpret`, so it may back a REPL session.
+pub struct Vm {
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    // the globals of the scripts the vm is given, rather than a module's
+    globals: Globals,
+    // the builtin functions, for any global which isn't defined. as with
+    // the tree-walker, a module's globals don't include them.
+    natives: HashMap<Rc<str>, Value>,
+    modules: Modules<ObjModule>,
+    // for errors in modules, which are reported as they're imported
+    error_reporter: ErrorReporter,
+
+    // sorted by stack slot, lowest first.
+    open_upvalues: Vec<Rc<RefCell<ObjUpvalue>>>,
+
+    // where the last runtime error happened, innermost call first.
+    trace: Vec<TraceFrame>,
+    // the line of the innermost frame for the error being unwound, when
+    // it isn't where that frame has got to, as for a rethrow.
+    error_line: Option<u32>,
+
+    handlers: Vec<Handler>,
+    unwinding: Vec<Unwinding>,
+    // the value of the `throw` being unwound. the error only carries
+    // a copy the tree-walker can show, see `thrown_value`.
+    thrown: Option<Value>,
+
+    // how deeply calls may nest before a "stack overflow" error.
+    max_call_depth: usize,
+
+    // set from outside to stop the running script.
+    interrupt: Interrupt,
+}
+
+// closures hold on to their file's globals, which usually hold the
+// closures in turn, so break the cycles or they'd never be freed.
+impl Drop for Vm {
+    fn drop(&mut self) {
+        self.globals.borrow_mut().clear();
+        for module in self.modules.loaded_modules() {
+            module.globals.borrow_mut().clear();
+        }
+    }
+}
+
+impl Vm {
+    pub fn new(error_reporter: ErrorReporter) -> Self {
+        let natives = BuiltInFunction::ALL
+            .into_iter()
+            .map(|function| {
+                let native = ObjNative { function };
+                (Rc::from(function.name()), Value::Native(Rc::new(native)))
+            })
+            .collect();
+        Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: Vec::with_capacity(FRAMES_MAX * 8),
+            globals: Globals::default(),
+            natives,
+            modules: Modules::default(),
+            error_reporter,
+            open_upvalues: Vec::new(),
+            trace: Vec::new(),
+            error_line: None,
+            handlers: Vec::new(),
+            unwinding: Vec::new(),
+            thrown: None,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            interrupt: Interrupt::default(),
+        }
+    }
+
+    /// All the global variables, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals: Vec<(String, Value)> = self
+            .globals
+            .borrow()
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+        globals
+    }
+
+    /// Note that `path` is being run, so imports are found next to it.
+    pub(crate) fn enter_file(&mut self, path: &Path) -> Result<()> {
+        self.modules.enter_file(path)
+    }
+
+    pub(crate) fn leave_file(&mut self) {
+        self.modules.leave_file();
+    }
+
+    pub fn interpret(&mut self, function: Rc<Function>) -> Result<()> {
+        let closure = Rc::new(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+            globals: Rc::clone(&self.globals),
+        });
+        self.stack.push(Value::Closure(Rc::clone(&closure)));
+
+        self.trace.clear();
+        let res = self.call(closure, 0).and_then(|_| self.run(0));
+        if res.is_err() {
+            self.unwind_frames(0);
+
+            // leave the vm in a usable state for the next script
+            self.stack.clear();
+            self.open_upvalues.clear();
+            self.handlers.clear();
+            self.unwinding.clear();
+            self.thrown = None;
+        }
+        res
+    }
+
+    /// Run until the frame above the first `base` returns.
+    fn run(&mut self, base: usize) -> Result<()> {
+        loop {
+            match self.execute(base) {
+                Err(e) => self.catch(e, base)?,
+                res => return res,
+            }
+        }
+    }
+
+    /// Unwind `error` to the innermost handler above the first `base`
+    /// frames which takes it, and carry on from there. Passes the error
+    /// back if there's none.
+    fn catch(&mut self, error: RloxError, base: usize) -> Result<()> {
+        while let Some(handler) = self.handlers.pop_if(|h| h.frames > base) {
+            if handler.finally {
+                self.unwind_frames(handler.frames);
+                let line = match self.error_line.take() {
+                    Some(line) => line,
+                    None => self.current_span().line,
+                };
+                // anything left from a finally block a jump left early
+                self.unwinding.retain(|u| u.slot < handler.stack);
+                self.unwinding.push(Unwinding {
+                    slot: handler.stack,
+                    error,
+                    thrown: self.thrown.take(),
+                    trace: std::mem::take(&mut self.trace),
+                    line,
+                });
+                self.resume(&handler, Value::Nil);
+                return Ok(());
+            }
+
+            let exception = match error {
+                RloxError::Throw { .. } => self.thrown.take(),
+                ref error => error.exception(),
+            };
+            if let Some(exception) = exception {
+                self.trace.clear();
+                self.error_line = None;
+                self.frames.truncate(handler.frames);
+                self.resume(&handler, exception);
+                return Ok(());
+            }
+        }
+        Err(error)
+    }
+
+    /// Carry on from `handler`, with `caught` on top of the stack.
+    fn resume(&mut self, handler: &Handler, caught: Value) {
+        self.close_upvalues(handler.stack);
+        self.stack.truncate(handler.stack);
+        self.stack.push(caught);
+        self.frame_mut().ip = handler.target;
+    }
+
+    /// Drop all the frames above the first `keep`, noting each in the
+    /// traceback.
+    fn unwind_frames(&mut self, keep: usize) {
+        while self.frames.len() > keep {
+            let frame = self.frames.pop().expect("should have a call frame");
+            let function = &frame.closure.function;
+            let line = match self.error_line.take() {
+                Some(line) => line,
+                None => function.chunk.spans[frame.ip.saturating_sub(1)].line,
+            };
+            self.trace.push(TraceFrame {
+                function: match function.name.as_str() {
+                    "" => String::from("<script>"),
+                    name => name.to_string(),
+                },
+                line,
+            });
+        }
+    }
+
+    /// Run until the frame above the first `base` returns, or there's
+    /// an error.
+    fn execute(&mut self, base: usize) -> Result<()> {
+        loop {
+            let op = {
+                let frame = self.frames.last_mut().expect("should have a call frame");
+                let op = frame.closure.function.chunk.code[frame.ip];
+                frame.ip += 1;
+                op
+            };
+
+            match op {
+                OpCode::Constant(index) => {
+                    let constant = self.read_constant(index);
+                    self.stack.push(constant);
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Boolean(true)),
+                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::GetLocal(slot) => {
+                    let base = self.frame().slots;
+                    let value = self.stack[base + slot].clone();
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal(slot) => {
+                    let base = self.frame().slots;
+                    self.stack[base + slot] = self.peek(0).clone();
+                }
+                OpCode::GetGlobal(index) => {
+                    let name = self.read_string(index);
+                    let value = self.frame().closure.globals.borrow().get(&name).cloned();
+                    match value.or_else(|| self.natives.get(&name).cloned()) {
+                        Some(value) => self.stack.push(value),
+                        None => {
+                            return Err(RloxError::UndefinedVariable {
+                                message: format!("undefined variable '{}'", name),
+                                span: Some(self.current_span()),
+                            })
+                        }
+                    }
+                }
+                OpCode::DefineGlobal(index) => {
+                    let name = self.read_string(index);
+                    let value = self.pop();
+                    self.frame()
+                        .closure
+                        .globals
+                        .borrow_mut()
+                        .insert(name, value);
+                }
+                OpCode::SetGlobal(index) => {
+                    let name = self.read_string(index);
+                    let mut globals = self.frame().closure.globals.borrow_mut();
+                    if !globals.contains_key(&name) {
+                        return Err(RloxError::UndefinedVariable {
+                            message: format!("undefined variable '{}'", name),
+                            span: Some(self.current_span()),
+                        });
+                    }
+                    globals.insert(name, self.peek(0).clone());
+                }
+                OpCode::GetUpvalue(index) => {
+                    let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                    let value = match *upvalue.borrow() {
+                        ObjUpvalue::Open(slot) => self.stack[slot].clone(),
+                        ObjUpvalue::Closed(ref v) => v.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let upvalue = Rc::clone(&self.frame().closure.upvalues[index]);
+                    let value = self.peek(0).clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match *upvalue {
+                        ObjUpvalue::Open(slot) => self.stack[slot] = value,
+                        ObjUpvalue::Closed(ref mut v) => *v = value,
+                    }
+                }
+                OpCode::GetProperty(index) => {
+                    let instance = match self.peek(0) {
+                        Value::Instance(instance) => Rc::clone(instance),
+                        Value::Module(module) => {
+                            let name = self.read_string(index);
+                            let value = module.globals.borrow().get(&name).cloned();
+                            let value = value.ok_or_else(|| RloxError::UndefinedSymbol {
+                                message: format!("module '{}' has no '{}'", module.name, name),
+                                span: Some(self.current_span()),
+                            })?;
+                            self.pop();
+                            self.stack.push(value);
+                            continue;
+                        }
+                        v => {
+                            return Err(RloxError::IncorrectType {
+                                message: format!(
+                                    "only instances have properties, got a {}",
+                                    v.type_name()
+                                ),
+                                span: Some(self.current_span()),
+                            })
+                        }
+                    };
+
+                    let name = self.read_string(index);
+                    let field = instance.borrow().fields.get(&name).cloned();
+                    match field {
+                        Some(value) => {
+                            self.pop();
+                            self.stack.push(value);
+                        }
+                        None => {
+                            let class = Rc::clone(&instance.borrow().class);
+                            self.bind_method(&class, &name)?;
+                        }
+                    }
+                }
+                OpCode::SetProperty(index) => {
+                    let instance = match self.peek(1) {
+                        Value::Instance(instance) => Rc::clone(instance),
+                        v => {
+                            return Err(RloxError::IncorrectType {
+                                message: format!(
+                                    "only instances have fields, got a {}",
+                                    v.type_name()
+                                ),
+                                span: Some(self.current_span()),
+                            })
+                        }
+                    };
+
+                    let name = self.read_string(index);
+                    let value = self.pop();
+                    instance.borrow_mut().fields.insert(name, value.clone());
+                    self.pop();
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper(index) => {
+                    let name = self.read_string(index);
+                    let superclass = match self.pop() {
+                        Value::Class(class) => class,
+                        _ => {
+                            return Err(RloxError::Unreachable(String::from(
+                                "super must be a class",
+                            )))
+                        }
+                    };
+                    self.bind_method(&superclass, &name)?;
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = builtins::get_index(&object, &index, self.current_span())?;
+                    self.stack.push(value);
+                }
+                OpCode::SetIndex => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    if let Value::Map(map) = &object {
+                        let key = builtins::map_key(&index, Some(self.current_span()))?;
+                        map.borrow_mut().insert(key, value.clone());
+                        self.stack.push(value);
+                        continue;
+                    }
+
+                    let (list, i) = builtins::list_position(&object, &index, self.current_span())?;
+                    list.borrow_mut()[i] = value.clone();
+                    self.stack.push(value);
+                }
+                OpCode::Equal => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.stack.push(Value::Boolean(a.equals(&b)));
+                }
+                OpCode::NotEqual => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.stack.push(Value::Boolean(!a.equals(&b)));
+                }
+                OpCode::Greater => self.comparison_op(">", |a, b| a > b)?,
+                OpCode::GreaterEqual => self.comparison_op(">=", |a, b| a >= b)?,
+                OpCode::Less => self.comparison_op("<", |a, b| a < b)?,
+                OpCode::LessEqual => self.comparison_op("<=", |a, b| a <= b)?,
+                OpCode::Add => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let value = match (&a, &b) {
+                        (Value::Double(a), Value::Double(b)) => Value::Double(a + b),
+                        (Value::String(a), Value::String(b)) => {
+                            Value::String(Rc::from(format!("{}{}", a, b)))
+                        }
+                        (Value::List(a), Value::List(b)) => {
+                            let mut values = a.borrow().clone();
+                            values.extend(b.borrow().iter().cloned());
+                            Value::new_list(values)
+                        }
+                        _ => return Err(self.type_error("+", &[&a, &b])),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::Subtract => self.arithmetic_op("-", |a, b| a - b)?,
+                OpCode::Multiply => self.arithmetic_op("*", |a, b| a * b)?,
+                OpCode::Divide => self.arithmetic_op("/", |a, b| a / b)?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.stack.push(Value::Boolean(!value.is_truthy()));
+                }
+                OpCode::Negate => match self.pop() {
+                    Value::Double(d) => self.stack.push(Value::Double(-d)),
+                    v => return Err(self.type_error("-", &[&v])),
+                },
+                OpCode::Print => {
+                    let value = self.pop();
+                    println!("{}", value);
+                }
+                OpCode::Jump(target) => self.frame_mut().ip = target,
+                OpCode::JumpIfFalse(target) => {
+                    if !self.peek(0).is_truthy() {
+                        self.frame_mut().ip = target;
+                    }
+                }
+                OpCode::Loop(target) => {
+                    self.interrupt.check()?;
+                    self.frame_mut().ip = target;
+                }
+                OpCode::Call(arg_count) => {
+                    self.interrupt.check()?;
+                    let callee = self.peek(arg_count).clone();
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Closure(index) => {
+                    let function = match self.read_constant(index) {
+                        Value::Function(function) => function,
+                        _ => {
+                            return Err(RloxError::Unreachable(String::from(
+                                "closure of non-function",
+                            )))
+                        }
+                    };
+
+                    let mut upvalues = Vec::with_capacity(function.upvalues.len());
+                    for desc in &function.upvalues {
+                        let upvalue = if desc.is_local {
+                            let slot = self.frame().slots + desc.index;
+                            self.capture_upvalue(slot)
+                        } else {
+                            Rc::clone(&self.frame().closure.upvalues[desc.index])
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    let closure = Rc::new(ObjClosure {
+                        function,
+                        upvalues,
+                        globals: Rc::clone(&self.frame().closure.globals),
+                    });
+                    // without upvalues, it can't be part of a cycle
+                    if !closure.upvalues.is_empty() {
+                        gc::track(&closure);
+                    }
+                    self.stack.push(Value::Closure(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("should have a call frame");
+                    self.close_upvalues(frame.slots);
+
+                    self.stack.truncate(frame.slots);
+                    if self.frames.len() == base {
+                        return Ok(());
+                    }
+                    self.stack.push(result);
+                }
+                OpCode::Class(index) => {
+                    let class = Rc::new(ObjClass {
+                        name: self.read_string(index),
+                        methods: RefCell::new(HashMap::new()),
+                    });
+                    gc::track(&class);
+                    self.stack.push(Value::Class(class));
+                }
+                OpCode::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Value::Class(class) => Rc::clone(class),
+                        v => {
+                            return Err(RloxError::IncorrectType {
+                                message: format!(
+                                    "superclass must be a class, got {}",
+                                    v.type_name()
+                                ),
+                                span: Some(self.current_span()),
+                            })
+                        }
+                    };
+                    if let Value::Class(subclass) = self.peek(0) {
+                        // copy-down inheritance: later method definitions
+                        // in the subclass overwrite these.
+                        let methods = superclass.methods.borrow().clone();
+                        subclass.methods.borrow_mut().extend(methods);
+                    }
+                    self.pop();
+                }
+                OpCode::BuildList(count) => {
+                    let values = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(Value::new_list(values));
+                }
+                OpCode::BuildMap(count) => {
+                    let values = self.stack.split_off(self.stack.len() - count * 2);
+                    let mut map = HashMap::with_capacity(count);
+                    let mut entries = values.into_iter();
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        map.insert(builtins::map_key(&key, Some(self.current_span()))?, value);
+                    }
+                    self.stack.push(Value::new_map(map));
+                }
+                OpCode::Method(index) => {
+                    let name = self.read_string(index);
+                    let method = match self.pop() {
+                        Value::Closure(closure) => closure,
+                        _ => {
+                            return Err(RloxError::Unreachable(String::from(
+                                "method must be a closure",
+                            )))
+                        }
+                    };
+                    if let Value::Class(class) = self.peek(0) {
+                        class.methods.borrow_mut().insert(name, method);
+                    }
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    let error = RloxError::Throw {
+                        value: thrown_value(&value),
+                        span: self.current_span(),
+                    };
+                    self.thrown = Some(value);
+                    return Err(error);
+                }
+                OpCode::Import(index) => {
+                    let path = self.read_string(index);
+                    let module = self.import(&path)?;
+                    self.stack.push(Value::Module(module));
+                }
+                OpCode::PushCatch(target) => self.push_handler(target, false),
+                OpCode::PushFinally(target) => self.push_handler(target, true),
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::Rethrow => {
+                    // the finally block has popped it's placeholder
+                    let slot = self.stack.len();
+                    while let Some(unwinding) = self.unwinding.pop() {
+                        if unwinding.slot == slot {
+                            self.thrown = unwinding.thrown;
+                            self.trace = unwinding.trace;
+                            self.error_line = Some(unwinding.line);
+                            return Err(unwinding.error);
+                        }
+                    }
+                    return Err(RloxError::Unreachable(String::from(
+                        "rethrow with nothing to rethrow",
+                    )));
+                }
+            }
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<()> {
+        match callee {
+            Value::Closure(closure) => self.call(closure, arg_count),
+            Value::Native(native) => {
+                let arity = native.function.arity();
+                if arg_count != arity {
+                    return Err(RloxError::ArityError {
+                        expected: arity,
+                        got: arg_count,
+                        span: Some(self.current_span()),
+                    });
+                }
+                let args_start = self.stack.len() - arg_count;
+                let span = self.current_span();
+                // nothing limits what the vm allocates (yet)
+                let result = native
+                    .function
+                    .call(&self.stack[args_start..], &mut |_| Ok(()))
+                    .map_err(|e| e.or_at(span))?;
+                // drop the args and the native fn itself
+                self.stack.truncate(args_start - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            Value::Class(class) => {
+                let slot = self.stack.len() - arg_count - 1;
+                let instance = ObjInstance {
+                    class: Rc::clone(&class),
+                    fields: HashMap::new(),
+                };
+                let instance = Rc::new(RefCell::new(instance));
+                gc::track(&instance);
+                self.stack[slot] = Value::Instance(instance);
+
+                let init = class.methods.borrow().get("init").cloned();
+                match init {
+                    Some(init) => self.call(init, arg_count),
+                    None if arg_count != 0 => Err(RloxError::ArityError {
+                        expected: 0,
+                        got: arg_count,
+                        span: Some(self.current_span()),
+                    }),
+                    None => Ok(()),
+                }
+            }
+            Value::BoundMethod(bound) => {
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = bound.receiver.clone();
+                self.call(Rc::clone(&bound.method), arg_count)
+            }
+            v => Err(RloxError::IncorrectType {
+                message: format!("can only call functions and classes, got {}", v.type_name()),
+                span: Some(self.current_span()),
+            }),
+        }
+    }
+
+    fn call(&mut self, closure: Rc<ObjClosure>, arg_count: usize) -> Result<()> {
+        if arg_count != closure.function.arity {
+            return Err(RloxError::ArityError {
+                expected: closure.function.arity,
+                got: arg_count,
+                span: Some(self.current_span()),
+            });
+        }
+        // the script's own frame doesn't count as a call
+        if self.frames.len() > self.max_call_depth {
+            return Err(RloxError::StackOverflow {
+                span: self.current_span(),
+            });
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Load the module at `path`, running it the first time it's
+    /// imported. Later imports of the same file share the module.
+    fn import(&mut self, path: &str) -> Result<Rc<ObjModule>> {
+        let span = self.current_span();
+        let import_error = |message: String| RloxError::ImportError {
+            path: path.to_string(),
+            message,
+            span,
+        };
+
+        let (file, source) = match self.modules.find(path).map_err(import_error)? {
+            Import::Loaded(module) => return Ok(module),
+            Import::Load { file, source } => (file, source),
+        };
+        let module = self.run_module(source).map(|globals| {
+            Rc::new(ObjModule {
+                name: module::file_name(&file),
+                globals,
+            })
+        });
+        self.modules.loaded(module.as_ref().ok());
+        module.map_err(|_| import_error(String::from("the module failed to load")))
+    }
+
+    /// Compile and run a module's source, with globals of it's own. Errors
+    /// are reported here, while the reporter can still show the module's
+    /// source.
+    fn run_module(&mut self, source: String) -> Result<Globals> {
+        let restore_source = self.error_reporter.source();
+        let res = self.execute_module(source);
+        self.error_reporter.set_source(&restore_source);
+        res
+    }
+
+    fn execute_module(&mut self, source: String) -> Result<Globals> {
+        // the scanner and parser report their own errors
+        let mut scanner = Scanner::new(source, self.error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), self.error_reporter.clone()).parse()?;
+
+        // as for the script itself, the resolver only finds static errors
+        let interpreter = Interpreter::new(self.error_reporter.clone());
+        let function = Resolver::new(&interpreter)
+            .resolve_stmts(&stmts)
+            .and_then(|_| Compiler::compile(&stmts));
+        let function = match function {
+            Ok(function) => function,
+            Err(e) => {
+                self.error_reporter.static_error(&e);
+                return Err(e);
+            }
+        };
+
+        let globals = Globals::default();
+        let closure = Rc::new(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+            globals: Rc::clone(&globals),
+        });
+        let base = self.frames.len();
+        let stack = self.stack.len();
+        self.stack.push(Value::Closure(Rc::clone(&closure)));
+
+        let res = self.call(closure, 0).and_then(|_| self.run(base));
+        if let Err(e) = res {
+            self.unwind_frames(base);
+            self.close_upvalues(stack);
+            self.stack.truncate(stack);
+            self.thrown = None;
+
+            self.error_reporter.runtime_error(&e);
+            let trace = self.take_trace();
+            self.error_reporter.traceback(&trace);
+            return Err(e);
+        }
+        Ok(globals)
+    }
+
+    fn push_handler(&mut self, target: usize, finally: bool) {
+        self.handlers.push(Handler {
+            frames: self.frames.len(),
+            stack: self.stack.len(),
+            target,
+            finally,
+        });
+    }
+
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = interrupt;
+    }
+
+    /// The traceback of the last runtime error, innermost call first.
+    pub(crate) fn take_trace(&mut self) -> Vec<TraceFrame> {
+        std::mem::take(&mut self.trace)
+    }
+
+    /// Replace the instance on top of the stack with a bound method.
+    fn bind_method(&mut self, class: &ObjClass, name: &str) -> Result<()> {
+        let method = match class.methods.borrow().get(name) {
+            Some(method) => Rc::clone(method),
+            None => {
+                return Err(RloxError::UndefinedSymbol {
+                    message: format!("undefined property '{}'", name),
+                    span: Some(self.current_span()),
+                })
+            }
+        };
+
+        let receiver = self.pop();
+        let bound = Rc::new(ObjBoundMethod { receiver, method });
+        gc::track(&bound);
+        self.stack.push(Value::BoundMethod(bound));
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<ObjUpvalue>> {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate() {
+            if let ObjUpvalue::Open(s) = *upvalue.borrow() {
+                if s == slot {
+                    return Rc::clone(upvalue);
+                }
+                if s > slot {
+                    insert_at = i;
+                    break;
+                }
+            }
+        }
+
+        let upvalue = Rc::new(RefCell::new(ObjUpvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    /// Close every open upvalue pointing at `last` or any slot above it.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                ObjUpvalue::Open(slot) => slot,
+                ObjUpvalue::Closed(_) => unreachable!("closed upvalue in the open list"),
+            };
+            if slot < last {
+                break;
+            }
+
+            let value = self.stack[slot].clone();
+            *upvalue.borrow_mut() = ObjUpvalue::Closed(value);
+            // it's only once it's closed that it can be part of a cycle
+            let upvalue = self.open_upvalues.pop().expect("should have an upvalue");
+            gc::track(&upvalue);
+        }
+    }
+
+    fn arithmetic_op(&mut self, operator: &str, op: fn(f64, f64) -> f64) -> Result<()> {
+        let (a, b) = self.numeric_operands(operator)?;
+        self.stack.push(Value::Double(op(a, b)));
+        Ok(())
+    }
+
+    fn comparison_op(&mut self, operator: &str, op: fn(f64, f64) -> bool) -> Result<()> {
+        let (a, b) = self.numeric_operands(operator)?;
+        self.stack.push(Value::Boolean(op(a, b)));
+        Ok(())
+    }
+
+    fn numeric_operands(&mut self, operator: &str) -> Result<(f64, f64)> {
+        let b = self.pop();
+        let a = self.pop();
+        match (&a, &b) {
+            (Value::Double(a), Value::Double(b)) => Ok((*a, *b)),
+            _ => Err(self.type_error(operator, &[&a, &b])),
+        }
+    }
+
+    fn type_error(&self, operator: &str, operands: &[&Value]) -> RloxError {
+        let operands: Vec<&str> = operands.iter().map(|v| v.type_name()).collect();
+        RloxError::TypeError {
+            operator: operator.to_string(),
+            operands: operands.join(" and "),
+            span: self.current_span(),
+        }
+    }
+
+    ///////////////////
+    // helper functions
+    ///////////////////
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("should have a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("should have a call frame")
+    }
+
+    fn read_constant(&self, index: usize) -> Value {
+        self.frame().closure.function.chunk.constants[index].clone()
+    }
+
+    fn read_string(&self, index: usize) -> Rc<str> {
+        match &self.frame().closure.function.chunk.constants[index] {
+            Value::String(s) => Rc::clone(s),
+            v => unreachable!("expected a string constant, got {:?}", v),
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// Source span of the instruction currently executing.
+    fn current_span(&self) -> Span {
+        let frame = self.frame();
+        frame.closure.function.chunk.spans[frame.ip - 1]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use crate::ErrorReporter;
+
+    fn run(src: &str) -> Result<Vm> {
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new(src.to_string(), error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), error_reporter).parse()?;
+
+        let mut vm = Vm::new(ErrorReporter::default());
+        vm.interpret(Compiler::compile(&stmts)?)?;
+        Ok(vm)
+    }
+
+    fn global(vm: &Vm, name: &str) -> Value {
+        vm.globals
+            .borrow()
+            .get(name)
+            .cloned()
+            .expect("global should be defined")
+    }
+
+    #[test]
+    fn arithmetic_and_globals() -> Result<()> {
+        let vm = run("var a = 1 + 2 * 3; var b = \"x\" + \"y\"; a = a - 1;")?;
+        assert!(matches!(global(&vm, "a"), Value::Double(d) if d == 6.0));
+        assert_eq!(global(&vm, "b").to_string(), "xy");
+        Ok(())
+    }
+
+    #[test]
+    fn recursion_and_loops() -> Result<()> {
+        let src = r#"
+            fun fib(n) {
+                if (n <= 1) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            var total = 0;
+            var i = 0;
+            while (i < 10) {
+                total = total + fib(i);
+                i = i + 1;
+            }
+        "#;
+        let vm = run(src)?;
+        assert!(matches!(global(&vm, "total"), Value::Double(d) if d == 88.0));
+        Ok(())
+    }
+
+    #[test]
+    fn return_from_script() -> Result<()> {
+        // like the tree-walker, it just stops the script, even from
+        // inside a block or loop
+        let src = r#"
+            var a = 1;
+            while (true) {
+                var b = a + 1;
+                if (b == 2) return b;
+            }
+            a = 3;
+        "#;
+        let vm = run(src)?;
+        assert!(matches!(global(&vm, "a"), Value::Double(d) if d == 1.0));
+        assert!(vm.stack.is_empty());
+
+        let vm = run("var a = 1; return; a = 2;")?;
+        assert!(matches!(global(&vm, "a"), Value::Double(d) if d == 1.0));
+        Ok(())
+    }
+
+    #[test]
+    fn closures_capture_variables() -> Result<()> {
+        let src = r#"
+            fun make_counter() {
+                var i = 0;
+                fun count() {
+                    i = i + 1;
+                    return i;
+                }
+                return count;
+            }
+            var counter = make_counter();
+            counter();
+            var c = counter();
+        "#;
+        let vm = run(src)?;
+        assert!(matches!(global(&vm, "c"), Value::Double(d) if d == 2.0));
+        Ok(())
+    }
+
+    #[test]
+    fn lambdas() -> Result<()> {
+        let src = r#"
+            fun apply(f, x) { return f(x); }
+            fun adder(n) { return fun (x) { return x + n; }; }
+            var doubled = apply(fun (x) { return x * 2; }, 21);
+            var added = adder(1)(2);
+            var name = fun () {};
+        "#;
+        let vm = run(src)?;
+        assert!(matches!(global(&vm, "doubled"), Value::Double(d) if d == 42.0));
+        assert!(matches!(global(&vm, "added"), Value::Double(d) if d == 3.0));
+        assert_eq!(global(&vm, "name").to_string(), "<fn lambda>");
+        Ok(())
+    }
+
+    #[test]
+    fn traceback() -> Result<()> {
+        let src = "fun inner() {\n  return nil + 1;\n}\nfun outer() {\n  inner();\n}\nouter();";
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new(src.to_string(), error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), error_reporter).parse()?;
+
+        let mut vm = Vm::new(ErrorReporter::default());
+        assert!(vm.interpret(Compiler::compile(&stmts)?).is_err());
+        let trace: Vec<String> = vm.take_trace().iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            trace,
+            vec![
+                "at inner (line 2)",
+                "at outer (line 5)",
+                "at <script> (line 7)"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stack_overflow() -> Result<()> {
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new(
+            String::from("fun f() { f(); } f();"),
+            error_reporter.clone(),
+        );
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), error_reporter).parse()?;
+
+        let mut vm = Vm::new(ErrorReporter::default());
+        vm.set_max_call_depth(10);
+        assert!(matches!(
+            vm.interpret(Compiler::compile(&stmts)?),
+            Err(RloxError::StackOverflow { .. })
+        ));
+        assert_eq!(vm.take_trace().len(), 11);
+        Ok(())
+    }
+
+    #[test]
+    fn interrupt() -> Result<()> {
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new(String::from("while (true) {}"), error_reporter.clone());
+        scanner.scan_tokens()?;
+        let stmts = Parser::new(scanner.tokens(), error_reporter).parse()?;
+
+        let mut vm = Vm::new(ErrorReporter::default());
+        let interrupt = Interrupt::default();
+        vm.set_interrupt(interrupt.clone());
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            interrupt.interrupt();
+        });
+        assert!(matches!(
+            vm.interpret(Compiler::compile(&stmts)?),
+            Err(RloxError::Interrupted)
+        ));
+        stopper.join().expect("the stopping thread shouldn't panic");
+        Ok(())
+    }
+
+    #[test]
+    fn classes_and_super() -> Result<()> {
+        let src = r#"
+            class A {
+                init(n) { this.n = n; }
+                get() { return this.n; }
+            }
+            class B < A {
+                init(n) { super.init(n * 2); }
+                get() { return super.get() + 1; }
+            }
+            var v = B(5).get();
+        "#;
+        let vm = run(src)?;
+        assert!(matches!(global(&vm, "v"), Value::Double(d) if d == 11.0));
+        Ok(())
+    }
+
+    #[test]
+    fn break_and_continue_discard_locals() -> Result<()> {
+        let src = r#"
+            var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                var skip = i == 3;
+                if (skip) continue;
+                {
+                    var stop = i == 6;
+                    if (stop) break;
+                }
+                sum = sum + i;
+            }
+            var after = "ok";
+        "#;
+        let vm = run(src)?;
+        assert!(matches!(global(&vm, "sum"), Value::Double(d) if d == 12.0));
+        assert_eq!(global(&vm, "after").to_string(), "ok");
+        Ok(())
+    }
+
+    #[test]
+    fn lists() -> Result<()> {
+        let src = r#"
+            var xs = [1, 2, 3];
+            xs[0] = 10;
+            push(xs, 4);
+            var popped = pop(xs);
+            insert(xs, 1, "a");
+            var removed = remove(xs, 2);
+            var joined = slice(xs, 0, 2) + [nil];
+        "#;
+        let vm = run(src)?;
+        assert_eq!(global(&vm, "xs").to_string(), "[10, \"a\", 3]");
+        assert!(matches!(global(&vm, "popped"), Value::Double(d) if d == 4.0));
+        assert!(matches!(global(&vm, "removed"), Value::Double(d) if d == 2.0));
+        assert_eq!(global(&vm, "joined").to_string(), "[10, \"a\", nil]");
+
+        assert!(matches!(
+            run("var xs = [1]; xs[1];"),
+            Err(RloxError::IndexError { len: 1, .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn maps() -> Result<()> {
+        let src = r#"
+            var m = {"a": 1, 2: "two"};
+            m["b"] = [true];
+            var found = has(m, 2);
+            var removed = remove(m, "a");
+            var ks = keys(m);
+        "#;
+        let vm = run(src)?;
+        assert_eq!(global(&vm, "m").to_string(), "{2: \"two\", \"b\": [true]}");
+        assert!(matches!(global(&vm, "found"), Value::Boolean(true)));
+        assert!(matches!(global(&vm, "removed"), Value::Double(d) if d == 1.0));
+        assert_eq!(global(&vm, "ks").to_string(), "[2, \"b\"]");
+
+        assert!(matches!(
+            run("({})[\"nope\"];"),
+            Err(RloxError::KeyError { .. })
+        ));
+        assert!(matches!(
+            run("var m = {[]: 1};"),
+            Err(RloxError::UnhashableKey { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn try_catch_finally() -> Result<()> {
+        let src = r#"
+            var log = [];
+            try {
+                push(log, "try");
+                throw "oops";
+            } catch (e) {
+                push(log, e);
+            } finally {
+                push(log, "finally");
+            }
+
+            var caught;
+            try { nil - 1; } catch (e) { caught = e; }
+
+            fun cleanup() {
+                var a = 1;
+                try { var b = 2; return a + b; } finally { push(log, a); }
+            }
+            var returned = cleanup();
+
+            // the finally blocks run on the way out of the loop, and
+            // the thrown closure still sees the try's locals
+            var sum = 0;
+            for (var i = 0; i < 5; i = i + 1) {
+                try {
+                    var x = i;
+                    if (i == 1) continue;
+                    if (i == 3) throw fun () { return x; };
+                    sum = sum + x;
+                } catch (f) {
+                    sum = sum + f() * 10;
+                    break;
+                } finally {
+                    push(log, i);
+                }
+            }
+        "#;
+        let vm = run(src)?;
+        assert_eq!(
+            global(&vm, "log").to_string(),
+            "[\"try\", \"oops\", \"finally\", 1, 0, 1, 2, 3]"
+        );
+        assert_eq!(
+            global(&vm, "caught").to_string(),
+            "{\"line\": 13, \"message\": \"invalid operand type for '-': nil and number\", \"type\": \"type error\"}"
+        );
+        assert!(matches!(global(&vm, "returned"), Value::Double(d) if d == 3.0));
+        assert!(matches!(global(&vm, "sum"), Value::Double(d) if d == 32.0));
+        assert!(vm.stack.is_empty());
+
+        // without a catch, the error carries on after the finally
+        assert!(matches!(
+            run("try { throw 1; } finally {}"),
+            Err(RloxError::Throw { value, .. }) if value.is_numeric()
+        ));
+        assert!(matches!(
+            run("try { throw 1; } catch (e) { throw e + 1; }"),
+            Err(RloxError::Throw { value, .. }) if value.as_numeric() == Some(2.0)
+        ));
+        assert!(matches!(
+            run("try { [][0]; } finally { var a = 1; }"),
+            Err(RloxError::IndexError { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn runtime_error_leaves_vm_usable() {
+        let mut vm = Vm::new(ErrorReporter::default());
+        let error_reporter = ErrorReporter::default();
+        let mut scanner = Scanner::new("var x = 1; x - \"a\";".to_string(), error_reporter.clone());
+        scanner.scan_tokens().unwrap();
+        let stmts = Parser::new(scanner.tokens(), error_reporter)
+            .parse()
+            .unwrap();
+        let res = vm.interpret(Compiler::compile(&stmts).unwrap());
+        assert!(
+            matches!(res, Err(RloxError::TypeError { span, .. }) if span.line == 1 && span.column == 14)
+        );
+        assert!(vm.stack.is_empty());
+        assert!(matches!(global(&vm, "x"), Value::Double(_)));
+    }
+}